@@ -1,4 +1,9 @@
-use std::process::Command;
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+    process::Command,
+};
 
 fn main() {
     // We want to let people install WinterJS from source, so we can't have
@@ -27,4 +32,63 @@ fn main() {
             .status
             .success());
     }
+
+    // Set by mozjs_sys, which builds SpiderMonkey
+    let mozjs_dir = PathBuf::from(std::env::var("DEP_MOZJS_OUTDIR").unwrap());
+    let confdefs = mozjs_dir.join("js/src/js-confdefs.h");
+    build_stencil_cache_glue(&mozjs_dir, &confdefs);
+    emit_build_id(&mozjs_dir, &confdefs);
+}
+
+/// Compiles `src/stencil_cache.cpp` the same way the mozjs crate compiles
+/// its own glue code.
+fn build_stencil_cache_glue(mozjs_dir: &Path, confdefs: &Path) {
+    let mut build = cc::Build::new();
+    build
+        .cpp(true)
+        .file("src/stencil_cache.cpp")
+        .include(mozjs_dir.join("dist/include"))
+        .define("STATIC_JS_API", None)
+        // Emitting rerun-if-env-changed would stop cargo from rerunning this
+        // script when the TS sources change
+        .emit_rerun_if_env_changed(false);
+
+    if std::env::var("CARGO_CFG_TARGET_ENV").as_deref() == Ok("msvc") {
+        build
+            .flag("-std:c++17")
+            .flag(&format!("-FI{}", confdefs.display()))
+            .define("WIN32", None)
+            .define("NOMINMAX", None);
+    } else {
+        build
+            .flag("-std=c++17")
+            .flag("-include")
+            .flag(&confdefs.to_string_lossy())
+            .flag_if_supported("-fno-rtti")
+            .flag_if_supported("-fno-sized-deallocation")
+            .flag_if_supported("-Wno-unused-parameter")
+            .flag_if_supported("-Wno-invalid-offsetof");
+    }
+
+    build.compile("winterjs_stencil_cache");
+}
+
+/// Works out an id for the SpiderMonkey build we link against. SpiderMonkey
+/// only decodes stencils encoded by a build with the same id, so it has to
+/// change whenever SpiderMonkey or the way it's configured does.
+fn emit_build_id(mozjs_dir: &Path, confdefs: &Path) {
+    let mut hasher = DefaultHasher::new();
+    // Cargo picks a different directory for each version, source, feature
+    // set and profile of mozjs_sys
+    mozjs_dir.hash(&mut hasher);
+    std::fs::read(confdefs).unwrap().hash(&mut hasher);
+    for var in ["CARGO_PKG_VERSION", "TARGET", "PROFILE"] {
+        std::env::var(var).unwrap().hash(&mut hasher);
+    }
+
+    println!(
+        "cargo:rustc-env=WINTERJS_BUILD_ID={}-{:016x}",
+        std::env::var("CARGO_PKG_VERSION").unwrap(),
+        hasher.finish()
+    );
 }
@@ -13,10 +13,9 @@
 use anyhow::{anyhow, Context as _};
 use clap::builder::OsStr;
 use include_dir::{include_dir, Dir, File};
-use ion::{
-    module::{Module, ModuleRequest},
-    Context,
-};
+use ion::{module::ModuleRequest, Context};
+
+use crate::stencil_cache;
 
 const MODULES_DIR: Dir = include_dir!("src/builtins/internal_js_modules");
 
@@ -62,7 +61,7 @@ fn compile_and_register(cx: &Context, script_file: &File) -> anyhow::Result<()>
         .contents_utf8()
         .context("Failed to convert file contents to UTF-8")?;
 
-    let module = Module::compile(cx, &module_name, None, contents)
+    let module = stencil_cache::compile_module(cx, &module_name, None, contents)
         .map_err(|e| anyhow::anyhow!("Module compilation failed: {e:?}"))?;
 
     match unsafe { &mut (*cx.get_inner_data().as_ptr()).module_loader } {
@@ -2,12 +2,15 @@
 //! to defining classes in Rust. The scripts need to do something
 //! meaningful, such as making assignments to `globalThis`.
 //!
-//! Note: Files that don't have a .js extension will be ignored.
+//! Note: Files that don't have a .js extension will be ignored. Files
+//! are evaluated in alphabetical order.
 
 use anyhow::{bail, Context as _};
 use clap::builder::OsStr;
 use include_dir::{include_dir, Dir, File};
-use ion::{script::Script, Context};
+use ion::Context;
+
+use crate::stencil_cache;
 
 const MODULES_DIR: Dir = include_dir!("src/builtins/js_globals");
 
@@ -25,13 +28,19 @@ pub(super) fn define(cx: &Context) -> bool {
 }
 
 fn scan_dir(cx: &Context, dir: &Dir) -> anyhow::Result<()> {
-    for file in dir.files() {
+    // Scripts may build on globals defined by earlier ones, so they're
+    // evaluated in a fixed order
+    let mut files = dir.files().collect::<Vec<_>>();
+    files.sort_by_key(|file| file.path());
+    for file in files {
         if file.path().extension() == Some(&OsStr::from("js")) {
             compile_and_evaluate(cx, file)?;
         }
     }
 
-    for dir in dir.dirs() {
+    let mut dirs = dir.dirs().collect::<Vec<_>>();
+    dirs.sort_by_key(|dir| dir.path());
+    for dir in dirs {
         scan_dir(cx, dir)?;
     }
 
@@ -45,7 +54,7 @@ fn compile_and_evaluate(cx: &Context, script_file: &File) -> anyhow::Result<()>
         .contents_utf8()
         .context("Failed to convert file contents to UTF-8")?;
 
-    let script = Script::compile(cx, script_file.path(), contents)
+    let script = stencil_cache::compile_script(cx, &script_file.path().to_string_lossy(), contents)
         .map_err(|e| anyhow::anyhow!("Script compilation failed: {e:?}"))?;
 
     match script.evaluate(cx) {
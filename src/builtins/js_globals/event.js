@@ -344,7 +344,7 @@
     // TODO: implement according to https://dom.spec.whatwg.org/#concept-event-dispatch
     function dispatch(eventTarget, event) {
         // Tentative implementation that just calls the callback
-        eventTarget.eventTargetData.listeners[event.type].forEach((listener) => {
+        eventTarget.eventTargetData.listeners[event.type]?.forEach((listener) => {
             listener.callback(event);
         });
         return true;
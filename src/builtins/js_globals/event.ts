@@ -424,7 +424,7 @@
   // TODO: implement according to https://dom.spec.whatwg.org/#concept-event-dispatch
   function dispatch(eventTarget: EventTarget, event: Event): boolean {
     // Tentative implementation that just calls the callback
-    eventTarget.eventTargetData.listeners[event.type].forEach(
+    eventTarget.eventTargetData.listeners[event.type]?.forEach(
       (listener: any) => {
         listener.callback(event);
       }
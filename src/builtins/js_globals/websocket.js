@@ -0,0 +1,414 @@
+// WebSockets, as in Cloudflare Workers: `new WebSocketPair()` creates two
+// connected WebSockets. The script accepts one of them, and returns the
+// other one in a response with status 101:
+//
+//     const { 0: client, 1: server } = new WebSocketPair();
+//     server.accept();
+//     server.addEventListener("message", (event) => server.send(event.data));
+//     return new Response(null, { status: 101, webSocket: client });
+//
+// `upgradeWebSocket(request)` does the same in one go, like in Deno.
+//
+// `new WebSocket(url, protocols)` connects to a WebSocket server, as in
+// browsers.
+//
+// Both ends of a pair share a native WebSocketHandle (see
+// src/builtins/websocket), which passes messages to and from the client.
+// Outbound WebSockets have a handle of their own. Handles are only known to
+// this script; responses with a webSocket are created by the native
+// Response constructor, which looks them up.
+
+(function () {
+  const WebSocketHandle = globalThis.WebSocketHandle;
+  delete globalThis.WebSocketHandle;
+
+  const CONNECTING = 0;
+  const OPEN = 1;
+  const CLOSING = 2;
+  const CLOSED = 3;
+
+  // Lets WebSocketPair create the ends of a pair
+  const internal = Symbol("internal");
+
+  // The state shared by both ends of a pair, for each WebSocket
+  const pairs = new WeakMap();
+
+  class MessageEvent extends Event {
+    constructor(type, eventInitDict) {
+      super(type, eventInitDict);
+      this.data = eventInitDict?.data ?? null;
+      this.origin = eventInitDict?.origin ?? "";
+      this.lastEventId = eventInitDict?.lastEventId ?? "";
+    }
+  }
+
+  class CloseEvent extends Event {
+    constructor(type, eventInitDict) {
+      super(type, eventInitDict);
+      this.code = eventInitDict?.code ?? 0;
+      this.reason = eventInitDict?.reason ?? "";
+      this.wasClean = eventInitDict?.wasClean ?? false;
+    }
+  }
+
+  class ErrorEvent extends Event {
+    constructor(type, eventInitDict) {
+      super(type, eventInitDict);
+      this.message = eventInitDict?.message ?? "";
+      this.error = eventInitDict?.error;
+    }
+  }
+
+  // Subprotocols must be HTTP tokens
+  const TOKEN = /^[!#$%&'*+\-.^_`|~0-9A-Za-z]+$/;
+
+  function parseURL(url) {
+    let parsed;
+    try {
+      parsed = new URL(url);
+    } catch {
+      throw new DOMException(`Invalid WebSocket URL: ${url}`, "SyntaxError");
+    }
+
+    if (parsed.protocol === "http:") {
+      parsed.protocol = "ws:";
+    } else if (parsed.protocol === "https:") {
+      parsed.protocol = "wss:";
+    }
+    if (parsed.protocol !== "ws:" && parsed.protocol !== "wss:") {
+      throw new DOMException(
+        `WebSocket URLs must use ws: or wss:, not ${parsed.protocol}`,
+        "SyntaxError"
+      );
+    }
+    if (parsed.hash !== "") {
+      throw new DOMException(
+        "WebSocket URLs can't have a fragment",
+        "SyntaxError"
+      );
+    }
+    return parsed;
+  }
+
+  function parseProtocols(protocols) {
+    protocols = typeof protocols === "string" ? [protocols] : [...protocols];
+    for (const [i, protocol] of protocols.entries()) {
+      if (!TOKEN.test(protocol)) {
+        throw new DOMException(
+          `Invalid WebSocket subprotocol: ${protocol}`,
+          "SyntaxError"
+        );
+      }
+      if (protocols.indexOf(protocol) !== i) {
+        throw new DOMException(
+          `Duplicate WebSocket subprotocol: ${protocol}`,
+          "SyntaxError"
+        );
+      }
+    }
+    return protocols;
+  }
+
+  class WebSocket extends EventTarget {
+    static CONNECTING = CONNECTING;
+    static OPEN = OPEN;
+    static CLOSING = CLOSING;
+    static CLOSED = CLOSED;
+
+    #readyState = CONNECTING;
+    #url = null;
+    #protocol = "";
+    #binaryType = "blob";
+
+    // Sends waiting for a Blob to be read, to keep messages in order
+    #queue = null;
+
+    onopen = null;
+    onmessage = null;
+    onerror = null;
+    onclose = null;
+
+    constructor(url, protocols = []) {
+      super();
+
+      let pair;
+      if (url === internal) {
+        // One end of a WebSocketPair
+        pair = protocols;
+        this.#binaryType = "arraybuffer";
+      } else {
+        const parsed = parseURL(String(url));
+        protocols = parseProtocols(protocols);
+
+        this.#url = parsed.href;
+        pair = {
+          handle: WebSocketHandle.connect(parsed.href, protocols),
+          accepted: this,
+          returned: null,
+          outbound: true,
+        };
+        this.#receive(pair.handle);
+      }
+
+      pairs.set(this, pair);
+    }
+
+    get readyState() {
+      return this.#readyState;
+    }
+
+    get url() {
+      return this.#url;
+    }
+
+    get protocol() {
+      return this.#protocol;
+    }
+
+    get extensions() {
+      return "";
+    }
+
+    // Messages are handed over to the connection as soon as they're sent
+    get bufferedAmount() {
+      return 0;
+    }
+
+    get binaryType() {
+      return this.#binaryType;
+    }
+
+    set binaryType(binaryType) {
+      if (binaryType === "blob" || binaryType === "arraybuffer") {
+        this.#binaryType = binaryType;
+      }
+    }
+
+    accept() {
+      const pair = pairs.get(this);
+      if (pair.outbound) {
+        throw new TypeError("Outbound WebSockets don't need to be accepted");
+      }
+      if (pair.accepted === this) {
+        throw new TypeError("The WebSocket was already accepted");
+      }
+      if (pair.accepted !== null) {
+        throw new TypeError(
+          "The other end of the WebSocketPair was already accepted"
+        );
+      }
+      if (pair.returned === this) {
+        throw new TypeError(
+          "Can't accept a WebSocket that was returned in a response"
+        );
+      }
+
+      pair.accepted = this;
+      this.#readyState = OPEN;
+      this.#receive(pair.handle);
+      // There's no handshake on this end, but scripts written against the
+      // standard API wait for the open event before sending anything
+      Promise.resolve().then(() => this.#dispatch(new Event("open")));
+    }
+
+    send(data) {
+      const pair = pairs.get(this);
+      if (pair.accepted !== this) {
+        throw new TypeError(
+          "The WebSocket must be accepted before sending messages"
+        );
+      }
+      if (this.#readyState === CONNECTING) {
+        throw new DOMException(
+          "The WebSocket is still connecting",
+          "InvalidStateError"
+        );
+      }
+      // Messages sent after closing are dropped
+      if (this.#readyState !== OPEN) {
+        return;
+      }
+
+      let message;
+      if (typeof data === "string") {
+        message = data;
+      } else if (data instanceof ArrayBuffer) {
+        message = new Uint8Array(data);
+      } else if (ArrayBuffer.isView(data)) {
+        message = data;
+      } else if (data instanceof Blob) {
+        message = data.arrayBuffer().then((buffer) => new Uint8Array(buffer));
+      } else {
+        message = String(data);
+      }
+
+      this.#enqueue(message, (message) => {
+        if (typeof message === "string") {
+          pair.handle.sendText(message);
+        } else {
+          pair.handle.sendBinary(message);
+        }
+      });
+    }
+
+    close(code, reason) {
+      const pair = pairs.get(this);
+      if (pair.accepted !== this) {
+        throw new TypeError("The WebSocket must be accepted before closing it");
+      }
+      if (code !== undefined) {
+        // Scripts talking to a server can only use codes reserved for
+        // applications, as in browsers. Accepted WebSockets can use any
+        // code that can be sent, as in Cloudflare Workers.
+        const valid = pair.outbound
+          ? code === 1000 || (code >= 3000 && code <= 4999)
+          : code >= 1000 && code <= 4999 && ![1005, 1006, 1015].includes(code);
+        if (!valid) {
+          throw new DOMException(
+            `Invalid WebSocket close code ${code}`,
+            "InvalidAccessError"
+          );
+        }
+      }
+      if (
+        reason !== undefined &&
+        new TextEncoder().encode(reason).length > 123
+      ) {
+        throw new DOMException(
+          "The WebSocket close reason must be at most 123 bytes long",
+          "SyntaxError"
+        );
+      }
+
+      if (this.#readyState === CLOSING || this.#readyState === CLOSED) {
+        return;
+      }
+      this.#readyState = CLOSING;
+      this.#enqueue(null, () => pair.handle.close(code, reason));
+    }
+
+    #enqueue(message, send) {
+      if (this.#queue === null && !(message instanceof Promise)) {
+        send(message);
+        return;
+      }
+
+      const queue = Promise.all([this.#queue, message])
+        .then(([, message]) => send(message))
+        .catch((e) => console.error(e))
+        .finally(() => {
+          if (this.#queue === queue) {
+            this.#queue = null;
+          }
+        });
+      this.#queue = queue;
+    }
+
+    async #receive(handle) {
+      const origin = this.#url === null ? "" : new URL(this.#url).origin;
+      for (;;) {
+        const message = await handle.receive();
+        switch (message.type) {
+          case "open":
+            // Closing before the connection is established cancels it
+            if (this.#readyState === CONNECTING) {
+              this.#readyState = OPEN;
+              this.#protocol = message.protocol;
+              this.#dispatch(new Event("open"));
+            }
+            break;
+          case "text":
+            this.#dispatch(
+              new MessageEvent("message", { data: message.data, origin })
+            );
+            break;
+          case "binary": {
+            const data =
+              this.#binaryType === "blob"
+                ? new Blob([message.data])
+                : message.data;
+            this.#dispatch(new MessageEvent("message", { data, origin }));
+            break;
+          }
+          case "error":
+            this.#dispatch(
+              new ErrorEvent("error", { message: message.message })
+            );
+            break;
+          case "close":
+            this.#readyState = CLOSED;
+            this.#dispatch(
+              new CloseEvent("close", {
+                code: message.code,
+                reason: message.reason,
+                wasClean: message.code !== 1006,
+              })
+            );
+            return;
+        }
+      }
+    }
+
+    #dispatch(event) {
+      // An error in a listener shouldn't stop the WebSocket from receiving
+      // further messages
+      const handler = this[`on${event.type}`];
+      if (typeof handler === "function") {
+        try {
+          handler.call(this, event);
+        } catch (e) {
+          console.error(e);
+        }
+      }
+      try {
+        this.dispatchEvent(event);
+      } catch (e) {
+        console.error(e);
+      }
+    }
+  }
+
+  class WebSocketPair {
+    constructor() {
+      const pair = {
+        handle: WebSocketHandle.create(),
+        accepted: null,
+        returned: null,
+      };
+      this[0] = new WebSocket(internal, pair);
+      this[1] = new WebSocket(internal, pair);
+    }
+  }
+
+  // Lets the native Response constructor and the server find the
+  // connection of a WebSocket returned in a response, without exposing it
+  // on the WebSocket
+  WebSocketHandle.setLookup((webSocket) => {
+    const pair = pairs.get(webSocket);
+    if (pair === undefined || pair.outbound || pair.accepted === webSocket) {
+      return null;
+    }
+    pair.returned ??= webSocket;
+    return pair.handle;
+  });
+
+  function upgradeWebSocket(request) {
+    if (request.headers.get("upgrade")?.toLowerCase() !== "websocket") {
+      throw new TypeError("The request is not a WebSocket upgrade request");
+    }
+
+    const { 0: client, 1: socket } = new WebSocketPair();
+    socket.accept();
+    const response = new Response(null, { status: 101, webSocket: client });
+    return { socket, response };
+  }
+
+  Object.assign(globalThis, {
+    MessageEvent,
+    CloseEvent,
+    ErrorEvent,
+    WebSocket,
+    WebSocketPair,
+    upgradeWebSocket,
+  });
+})();
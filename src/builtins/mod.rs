@@ -9,6 +9,7 @@ pub mod js_globals;
 pub mod navigator;
 pub mod performance;
 pub mod process;
+pub mod websocket;
 
 pub struct Modules {
     pub include_internal: bool,
@@ -26,6 +27,7 @@ impl Modules {
             && crypto::define(cx, global)
             && cache::define(cx, global)
             && navigator::define(cx, global, self.hardware_concurrency)
+            && websocket::define(cx, global)
     }
 }
 
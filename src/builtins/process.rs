@@ -1,4 +1,13 @@
-use ion::{conversions::ToValue, flags::PropertyFlags, Context, Object};
+use std::io::Write;
+
+use bytes::Bytes;
+use ion::{
+    conversions::ToValue, flags::PropertyFlags, function::Opt, function_spec, Context, Object,
+    Value,
+};
+use mozjs_sys::jsapi::JSFunctionSpec;
+use runtime::globals::fetch::hyper_body_to_stream;
+use tokio::io::AsyncReadExt;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
@@ -23,6 +32,55 @@ pub fn populate_env_object(cx: &Context, env: &Object) -> bool {
     true
 }
 
+fn argv(args: Vec<String>) -> Vec<String> {
+    let exe = std::env::current_exe()
+        .map(|path| path.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| "winterjs".to_string());
+    std::iter::once(exe).chain(args).collect()
+}
+
+/// The exit code set through `process.exitCode`, or zero.
+pub fn exit_code(cx: &Context) -> i32 {
+    let process = match Object::global(cx).get(cx, "process") {
+        Ok(Some(process)) if process.handle().is_object() => process.to_object(cx),
+        _ => return 0,
+    };
+    match process.get(cx, "exitCode") {
+        Ok(Some(code)) if code.handle().is_number() => code.handle().to_number() as i32,
+        _ => 0,
+    }
+}
+
+#[js_fn]
+fn exit(cx: &Context, Opt(code): Opt<f64>) -> ion::Result<()> {
+    let code = code.map_or_else(|| exit_code(cx), |code| code as i32);
+
+    // Nothing else gets a chance to flush them
+    _ = std::io::stdout().flush();
+    _ = std::io::stderr().flush();
+    std::process::exit(code)
+}
+
+/// A body that reads stdin when the stream is first read from, so that
+/// runtimes which never use `process.stdin` don't touch it.
+fn stdin_body() -> hyper::Body {
+    let chunks = futures::stream::unfold(Some(tokio::io::stdin()), |stdin| async move {
+        let mut stdin = stdin?;
+        let mut buf = vec![0; 16 * 1024];
+        match stdin.read(&mut buf).await {
+            Ok(0) => None,
+            Ok(n) => {
+                buf.truncate(n);
+                Some((Ok(Bytes::from(buf)), Some(stdin)))
+            }
+            Err(e) => Some((Err(e), None)),
+        }
+    });
+    hyper::Body::wrap_stream(chunks)
+}
+
+static METHODS: &[JSFunctionSpec] = &[function_spec!(exit, "exit", 1), JSFunctionSpec::ZERO];
+
 pub fn define(cx: &Context, global: &Object) -> bool {
     let process = Object::new(cx);
     let env = Object::new(cx);
@@ -42,3 +100,34 @@ pub fn define(cx: &Context, global: &Object) -> bool {
             PropertyFlags::ENUMERATE,
         )
 }
+
+/// Adds `process.argv`, `process.exitCode`, `process.exit` and
+/// `process.stdin`, which only make sense when WinterJS runs a single script.
+/// `args` go in `process.argv` after the executable's path.
+pub fn define_exec_extras(cx: &Context, global: &Object, args: Vec<String>) -> bool {
+    let process = match global.get(cx, "process") {
+        Ok(Some(process)) if process.handle().is_object() => process.to_object(cx),
+        _ => return false,
+    };
+
+    let Some(stdin) = hyper_body_to_stream(cx, stdin_body()) else {
+        return false;
+    };
+
+    process.define(
+        cx,
+        "argv",
+        &argv(args).as_value(cx),
+        PropertyFlags::ENUMERATE,
+    ) && process.define(
+        cx,
+        "exitCode",
+        &Value::undefined(cx),
+        PropertyFlags::ENUMERATE,
+    ) && process.define(
+        cx,
+        "stdin",
+        &stdin.get().as_value(cx),
+        PropertyFlags::ENUMERATE,
+    ) && unsafe { process.define_methods(cx, METHODS) }
+}
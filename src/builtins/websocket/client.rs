@@ -0,0 +1,130 @@
+//! Outbound WebSocket connections, made by `new WebSocket(url)`. Once the
+//! handshake is done, messages are passed like for connections accepted by
+//! the server (see [`connection::run`]).
+
+use std::sync::Arc;
+
+use anyhow::{bail, Context as _};
+use hyper_rustls::ConfigBuilderExt;
+use rustls::{pki_types::ServerName, ClientConfig};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+use tokio_rustls::TlsConnector;
+use tokio_tungstenite::tungstenite::{
+    client::IntoClientRequest, handshake::client::Request, http::header,
+};
+use url::{Host, Url};
+
+use super::connection::{self, Peer};
+
+/// Loaded on the first `wss:` connection and shared by all threads.
+static TLS_CONFIG: once_cell::sync::OnceCell<Arc<ClientConfig>> = once_cell::sync::OnceCell::new();
+
+/// Connects to `url`, which must be a `ws:` or `wss:` URL, asking for one of
+/// `protocols`, then passes messages until the connection is closed. If the
+/// connection can't be established, the Javascript code gets an error
+/// followed by an abnormal close.
+pub async fn connect(url: Url, protocols: Vec<String>, peer: Peer) {
+    let request = match build_request(&url, &protocols) {
+        Ok(request) => request,
+        Err(e) => return peer.fail(format!("{e:#}")),
+    };
+
+    let stream = match connect_tcp(&url).await {
+        Ok(stream) => stream,
+        Err(e) => return peer.fail(format!("{e:#}")),
+    };
+
+    if url.scheme() == "wss" {
+        match connect_tls(&url, stream).await {
+            Ok(stream) => handshake(request, stream, peer).await,
+            Err(e) => peer.fail(format!("{e:#}")),
+        }
+    } else {
+        handshake(request, stream, peer).await
+    }
+}
+
+fn build_request(url: &Url, protocols: &[String]) -> anyhow::Result<Request> {
+    let mut request = url
+        .as_str()
+        .into_client_request()
+        .context("Invalid WebSocket URL")?;
+    if !protocols.is_empty() {
+        request.headers_mut().insert(
+            header::SEC_WEBSOCKET_PROTOCOL,
+            protocols
+                .join(", ")
+                .parse()
+                .context("Invalid WebSocket subprotocol")?,
+        );
+    }
+    Ok(request)
+}
+
+fn host_name(url: &Url) -> anyhow::Result<String> {
+    match url.host() {
+        Some(Host::Domain(domain)) => Ok(domain.to_string()),
+        Some(Host::Ipv4(ip)) => Ok(ip.to_string()),
+        Some(Host::Ipv6(ip)) => Ok(ip.to_string()),
+        None => bail!("The WebSocket URL has no host"),
+    }
+}
+
+async fn connect_tcp(url: &Url) -> anyhow::Result<TcpStream> {
+    let host = host_name(url)?;
+    let port = url
+        .port_or_known_default()
+        .context("The WebSocket URL has no port")?;
+
+    let stream = TcpStream::connect((host.as_str(), port))
+        .await
+        .with_context(|| format!("Failed to connect to {host}:{port}"))?;
+    _ = stream.set_nodelay(true);
+    Ok(stream)
+}
+
+async fn connect_tls(
+    url: &Url,
+    stream: TcpStream,
+) -> anyhow::Result<tokio_rustls::client::TlsStream<TcpStream>> {
+    let config = TLS_CONFIG.get_or_try_init(|| {
+        anyhow::Ok(Arc::new(
+            ClientConfig::builder()
+                .with_native_roots()
+                .context("Failed to load root certificates")?
+                .with_no_client_auth(),
+        ))
+    })?;
+
+    let server_name =
+        ServerName::try_from(host_name(url)?).context("Invalid server name for TLS")?;
+    TlsConnector::from(config.clone())
+        .connect(server_name, stream)
+        .await
+        .context("TLS handshake failed")
+}
+
+async fn handshake<S>(request: Request, stream: S, peer: Peer)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    match tokio_tungstenite::client_async(request, stream).await {
+        Ok((stream, response)) => {
+            let protocol = response
+                .headers()
+                .get(header::SEC_WEBSOCKET_PROTOCOL)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default()
+                .to_string();
+            peer.opened(protocol);
+            connection::run(stream, peer).await;
+        }
+        Err(e) => {
+            tracing::debug!(error = %e, "WebSocket handshake failed");
+            peer.fail(e);
+        }
+    }
+}
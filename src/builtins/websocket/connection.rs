@@ -0,0 +1,184 @@
+//! The connection side of WebSockets. Messages are passed between the
+//! Javascript code and the connection through channels, so the connection
+//! can be driven from any thread.
+
+use std::time::Duration;
+
+use futures::{SinkExt, StreamExt};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::mpsc,
+};
+use tokio_tungstenite::{
+    tungstenite::{
+        self,
+        protocol::{frame::coding::CloseCode, CloseFrame},
+    },
+    WebSocketStream,
+};
+
+/// Reported when the close frame has no status code.
+pub const NO_STATUS_RECEIVED: u16 = 1005;
+
+/// Reported when the connection closes without a close frame.
+pub const ABNORMAL_CLOSURE: u16 = 1006;
+
+/// How long to wait for the other side to answer our close frame before
+/// giving up on the connection.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+
+    /// Closes the connection. Without a code, the close frame has no
+    /// status.
+    Close(Option<u16>, String),
+
+    /// An outbound connection was established, with the subprotocol
+    /// picked by the server. This is only sent to the Javascript code.
+    Open(String),
+
+    /// The connection failed. This is only sent to the Javascript code,
+    /// and is followed by an abnormal close.
+    Error(String),
+}
+
+/// The Javascript code's end of a connection.
+pub struct Channels {
+    pub outgoing: mpsc::UnboundedSender<Message>,
+    pub incoming: mpsc::UnboundedReceiver<Message>,
+}
+
+/// The connection's end, to be passed to [`run`] once there is a
+/// connection.
+pub struct Peer {
+    outgoing: mpsc::UnboundedReceiver<Message>,
+    incoming: mpsc::UnboundedSender<Message>,
+}
+
+impl std::fmt::Debug for Peer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Peer").finish_non_exhaustive()
+    }
+}
+
+pub fn channel() -> (Channels, Peer) {
+    let (outgoing_tx, outgoing_rx) = mpsc::unbounded_channel();
+    let (incoming_tx, incoming_rx) = mpsc::unbounded_channel();
+    let channels = Channels {
+        outgoing: outgoing_tx,
+        incoming: incoming_rx,
+    };
+    let peer = Peer {
+        outgoing: outgoing_rx,
+        incoming: incoming_tx,
+    };
+    (channels, peer)
+}
+
+impl Peer {
+    /// Tells the Javascript code an outbound connection was established.
+    pub fn opened(&self, protocol: String) {
+        _ = self.incoming.send(Message::Open(protocol));
+    }
+
+    /// Tells the Javascript code the connection failed before it was
+    /// established.
+    pub fn fail(self, error: impl std::fmt::Display) {
+        _ = self.incoming.send(Message::Error(error.to_string()));
+        _ = self
+            .incoming
+            .send(Message::Close(Some(ABNORMAL_CLOSURE), String::new()));
+    }
+}
+
+/// Passes messages between the connection and the Javascript code until
+/// the connection is closed. Pings are answered automatically.
+pub async fn run<S>(stream: WebSocketStream<S>, peer: Peer)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let Peer {
+        mut outgoing,
+        incoming,
+    } = peer;
+    let (mut sink, mut stream) = stream.split();
+
+    // Set once we've sent a close frame; we then wait for the other side's
+    // close frame before reporting the close
+    let mut closing = false;
+    let close_timeout = tokio::time::sleep(CLOSE_TIMEOUT);
+    tokio::pin!(close_timeout);
+
+    let result = loop {
+        tokio::select! {
+            message = outgoing.recv(), if !closing => {
+                let message = match message {
+                    Some(Message::Text(text)) => tungstenite::Message::Text(text),
+                    Some(Message::Binary(data)) => tungstenite::Message::Binary(data),
+                    Some(Message::Close(code, reason)) => {
+                        tungstenite::Message::Close(code.map(|code| CloseFrame {
+                            code: CloseCode::from(code),
+                            reason: reason.into(),
+                        }))
+                    }
+                    Some(Message::Open(_) | Message::Error(_)) => continue,
+                    // The Javascript code is gone, e.g. because its worker
+                    // thread was terminated
+                    None => tungstenite::Message::Close(Some(CloseFrame {
+                        code: CloseCode::Away,
+                        reason: "".into(),
+                    })),
+                };
+                if matches!(message, tungstenite::Message::Close(_)) {
+                    closing = true;
+                    close_timeout
+                        .as_mut()
+                        .reset(tokio::time::Instant::now() + CLOSE_TIMEOUT);
+                }
+                if let Err(e) = sink.send(message).await {
+                    break Err(e.to_string());
+                }
+            }
+
+            message = stream.next() => match message {
+                Some(Ok(tungstenite::Message::Text(text))) => {
+                    _ = incoming.send(Message::Text(text));
+                }
+                Some(Ok(tungstenite::Message::Binary(data))) => {
+                    _ = incoming.send(Message::Binary(data));
+                }
+                Some(Ok(tungstenite::Message::Close(frame))) => {
+                    // tungstenite answers the close frame by itself
+                    break Ok(match frame {
+                        Some(frame) => (u16::from(frame.code), frame.reason.into_owned()),
+                        None => (NO_STATUS_RECEIVED, String::new()),
+                    });
+                }
+                Some(Ok(_)) => (),
+                Some(Err(e)) => break Err(e.to_string()),
+                None => break Ok((ABNORMAL_CLOSURE, String::new())),
+            },
+
+            _ = &mut close_timeout, if closing => {
+                break Err("The other side did not answer the close frame in time".to_string());
+            }
+        }
+    };
+
+    // Sends our answer to the close frame, if there is one, and closes the
+    // connection
+    _ = sink.close().await;
+
+    match result {
+        Ok((code, reason)) => {
+            _ = incoming.send(Message::Close(Some(code), reason));
+        }
+        Err(e) => {
+            tracing::debug!(error = %e, "WebSocket connection failed");
+            Peer { outgoing, incoming }.fail(e);
+        }
+    }
+}
@@ -0,0 +1,199 @@
+//! The native side of WebSockets. The `WebSocket` and `WebSocketPair`
+//! classes are defined in JS (see `js_globals/websocket.js`), on top of the
+//! `WebSocketHandle` defined here. Both ends of a pair share one handle:
+//! the end the script accepts sends and receives messages through it, and
+//! the end returned in a response hands the [`Peer`] over to the server,
+//! which connects it to the client once the upgrade is done.
+//!
+//! Outbound connections made with `new WebSocket(url)` get a handle of their
+//! own, and the connection is driven by the worker's event loop, like
+//! `fetch`.
+//!
+//! Which WebSocket a response hands over is kept out of sight of the
+//! script: the handle of a `WebSocket` is only known to `websocket.js`, and
+//! [`response`] looks it up through a function registered by the script.
+//!
+//! Incoming messages are received through promises, so they're delivered by
+//! the worker's event loop like the results of any other IO.
+
+use std::rc::Rc;
+
+use ion::{
+    class::Reflector, conversions::ConversionBehavior, function::Opt, typedarray::ArrayBuffer,
+    ClassDefinition, Context, Error, ErrorKind, Function, Object, Promise, Result,
+};
+use mozjs::typedarray::ArrayBufferView;
+use mozjs_sys::jsapi::JSObject;
+use runtime::promise::future_to_promise;
+use tokio::sync::{mpsc, Mutex};
+
+use crate::{ion_err, ion_mk_err};
+
+pub mod client;
+pub mod connection;
+mod response;
+
+pub use connection::{Message, Peer};
+pub use response::take_response_peer;
+
+#[js_class]
+pub struct WebSocketHandle {
+    reflector: Reflector,
+
+    #[trace(no_trace)]
+    outgoing: mpsc::UnboundedSender<Message>,
+
+    #[trace(no_trace)]
+    incoming: Rc<Mutex<mpsc::UnboundedReceiver<Message>>>,
+
+    /// Taken by the server once the other end is returned in a response.
+    #[trace(no_trace)]
+    peer: Option<Peer>,
+
+    /// Whether the other end was put in a response.
+    #[trace(no_trace)]
+    returned: bool,
+}
+
+impl WebSocketHandle {
+    fn send(&self, message: Message) -> Result<()> {
+        self.outgoing
+            .send(message)
+            .map_err(|_| ion_mk_err!("The WebSocket is closed", Normal))
+    }
+}
+
+#[js_class]
+impl WebSocketHandle {
+    #[ion(constructor)]
+    pub fn constructor() -> Result<WebSocketHandle> {
+        ion_err!("Cannot construct this type", Type)
+    }
+
+    /// Creates the handle shared by both ends of a new `WebSocketPair`.
+    pub fn create(cx: &Context) -> *mut JSObject {
+        let (channels, peer) = connection::channel();
+        WebSocketHandle::new_object(
+            cx,
+            Box::new(WebSocketHandle {
+                reflector: Default::default(),
+                outgoing: channels.outgoing,
+                incoming: Rc::new(Mutex::new(channels.incoming)),
+                peer: Some(peer),
+                returned: false,
+            }),
+        )
+    }
+
+    /// Creates the handle of an outbound connection to `url`, which is
+    /// established in the background. Receiving from the handle yields an
+    /// `open` message once it's done.
+    pub fn connect(cx: &Context, url: String, protocols: Vec<String>) -> Result<*mut JSObject> {
+        let url = url::Url::parse(&url)
+            .map_err(|e| ion_mk_err!(format!("Invalid WebSocket URL: {e}"), Syntax))?;
+        let (channels, peer) = connection::channel();
+
+        // The returned promise is of no interest, but running the connection
+        // as a promise keeps the event loop going while it's open
+        unsafe {
+            future_to_promise::<_, _, _, Error>(cx, move |_| async move {
+                client::connect(url, protocols, peer).await;
+                Ok(())
+            });
+        }
+
+        Ok(WebSocketHandle::new_object(
+            cx,
+            Box::new(WebSocketHandle {
+                reflector: Default::default(),
+                outgoing: channels.outgoing,
+                incoming: Rc::new(Mutex::new(channels.incoming)),
+                peer: None,
+                returned: false,
+            }),
+        ))
+    }
+
+    /// Registers the function that finds the handle of a `WebSocket`, so
+    /// the handle doesn't need to be visible on the `WebSocket` itself.
+    #[ion(name = "setLookup")]
+    pub fn set_lookup(lookup: Function) {
+        response::set_handle_lookup(&lookup);
+    }
+
+    #[ion(name = "sendText")]
+    pub fn send_text(&self, text: String) -> Result<()> {
+        self.send(Message::Text(text))
+    }
+
+    #[ion(name = "sendBinary")]
+    pub fn send_binary(&self, data: ArrayBufferView) -> Result<()> {
+        self.send(Message::Binary(unsafe { data.as_slice() }.to_vec()))
+    }
+
+    pub fn close(
+        &self,
+        #[ion(convert = ConversionBehavior::EnforceRange)] Opt(code): Opt<u16>,
+        Opt(reason): Opt<String>,
+    ) -> Result<()> {
+        self.send(Message::Close(code, reason.unwrap_or_default()))
+    }
+
+    /// Resolves to the next message from the connection: an object with a
+    /// `type` of `open` along with the `protocol`, `text` or `binary` along
+    /// with the `data`, `error` along with a `message`, or `close` along
+    /// with the `code` and `reason`. Nothing is received after the close.
+    pub fn receive(&self, cx: &Context) -> Option<Promise> {
+        let incoming = self.incoming.clone();
+        unsafe {
+            future_to_promise::<_, _, _, Error>(cx, move |cx| async move {
+                let message = incoming.lock().await.recv().await;
+
+                let event = Object::new(&cx);
+                let defined = match message {
+                    Some(Message::Text(text)) => {
+                        event.set_as(&cx, "type", "text") && event.set_as(&cx, "data", &text)
+                    }
+                    Some(Message::Binary(data)) => {
+                        let data = ArrayBuffer::copy_from_bytes(&cx, &data).ok_or_else(|| {
+                            Error::new("Failed to allocate array", ErrorKind::Normal)
+                        })?;
+                        event.set_as(&cx, "type", "binary") && event.set_as(&cx, "data", &data)
+                    }
+                    Some(Message::Open(protocol)) => {
+                        event.set_as(&cx, "type", "open")
+                            && event.set_as(&cx, "protocol", &protocol)
+                    }
+                    Some(Message::Error(message)) => {
+                        event.set_as(&cx, "type", "error") && event.set_as(&cx, "message", &message)
+                    }
+                    Some(Message::Close(code, reason)) => {
+                        event.set_as(&cx, "type", "close")
+                            && event.set_as(
+                                &cx,
+                                "code",
+                                &code.unwrap_or(connection::NO_STATUS_RECEIVED),
+                            )
+                            && event.set_as(&cx, "reason", &reason)
+                    }
+                    // The connection went away without saying goodbye,
+                    // e.g. because the request wasn't a WebSocket upgrade
+                    None => {
+                        event.set_as(&cx, "type", "close")
+                            && event.set_as(&cx, "code", &connection::ABNORMAL_CLOSURE)
+                            && event.set_as(&cx, "reason", "")
+                    }
+                };
+                if !defined {
+                    ion_err!("Failed to define WebSocket message properties", Normal);
+                }
+
+                Ok((*event).get())
+            })
+        }
+    }
+}
+
+pub fn define(cx: &Context, global: &Object) -> bool {
+    WebSocketHandle::init_class(cx, global).0 && response::define(cx, global)
+}
@@ -0,0 +1,299 @@
+//! Responses that hand a WebSocket over to the server, created with
+//! `new Response(null, { status: 101, webSocket })` as in Cloudflare
+//! Workers.
+//!
+//! The fetch `Response` class only allows statuses from 200 to 599, so the
+//! global `Response` is a native constructor that creates responses with a
+//! `webSocket` itself and constructs all others with the fetch class. It
+//! shares the fetch class's prototype and inherits its static methods, so
+//! responses without a `webSocket` are exactly what the fetch class makes.
+
+use std::{cell::RefCell, os::raw::c_char};
+
+use anyhow::{anyhow, bail};
+use ion::{
+    exception::ThrowException, flags::PropertyFlags, ClassDefinition, Context, Function, Local,
+    Object, PermanentHeap, Value,
+};
+use mozjs::{
+    jsapi::{
+        CallArgs, HandleValueArray, JSContext, JSFunction, JSObject, JS_GetFunctionObject,
+        JS_NewFunction, JS_SetPrototype, JSFUN_CONSTRUCTOR,
+    },
+    jsval::{JSVal, NullValue, ObjectValue},
+    rust::wrappers::Construct1,
+};
+use runtime::globals::fetch::Response as FetchResponse;
+
+use super::{Peer, WebSocketHandle};
+use crate::{ion_err, ion_mk_err};
+
+thread_local! {
+    static FETCH_RESPONSE: RefCell<Option<PermanentHeap<*mut JSObject>>> = RefCell::new(None);
+
+    /// Finds the handle of a `WebSocket`, which only `js_globals/websocket.js`
+    /// can see. Returns null for anything but the end of a `WebSocketPair`
+    /// that wasn't accepted.
+    static HANDLE_LOOKUP: RefCell<Option<PermanentHeap<*mut JSFunction>>> = RefCell::new(None);
+}
+
+pub(super) fn set_handle_lookup(lookup: &Function) {
+    HANDLE_LOOKUP.set(Some(PermanentHeap::from_local(lookup)));
+}
+
+fn handle_of<'cx>(cx: &'cx Context, web_socket: &Object) -> Option<Object<'cx>> {
+    let lookup = HANDLE_LOOKUP.with(|lookup| {
+        lookup
+            .borrow()
+            .as_ref()
+            .map(|lookup| Function::from(lookup.root(cx)))
+    })?;
+    match lookup.call(cx, &Object::null(cx), &[Value::object(cx, web_socket)]) {
+        Ok(handle) if handle.handle().is_object() => Some(handle.to_object(cx)),
+        _ => None,
+    }
+}
+
+unsafe extern "C" fn construct(cx: *mut JSContext, argc: u32, vp: *mut JSVal) -> bool {
+    let cx = Context::new_unchecked(cx);
+    let args = CallArgs::from_vp(vp, argc);
+    if !args.constructing_() {
+        ion_mk_err!("Response constructor: 'new' is required", Type).throw(&cx);
+        return false;
+    }
+
+    let body = Value::from(Local::from_marked(args.get(0).ptr));
+    let init = Value::from(Local::from_marked(args.get(1).ptr));
+
+    let web_socket = match web_socket_init(&cx, &init) {
+        Ok(web_socket) => web_socket,
+        Err(e) => {
+            e.throw(&cx);
+            return false;
+        }
+    };
+
+    let response = match web_socket {
+        Some(web_socket) => {
+            match web_socket_response(&cx, &body, &init.to_object(&cx), &web_socket) {
+                Ok(response) => response,
+                Err(Some(e)) => {
+                    e.throw(&cx);
+                    return false;
+                }
+                Err(None) => return false,
+            }
+        }
+        None => {
+            let new_target = Value::from(Local::from_marked(args.new_target().ptr));
+            let args = std::slice::from_raw_parts(args.argv_, argc as usize);
+            match construct_fetch_response(&cx, Some(&new_target.to_object(&cx)), args) {
+                Some(response) => response,
+                None => return false,
+            }
+        }
+    };
+
+    args.rval().set(ObjectValue(response));
+    true
+}
+
+/// The `webSocket` of a response's `init`, if there is one.
+fn web_socket_init<'cx>(cx: &'cx Context, init: &Value) -> ion::Result<Option<Object<'cx>>> {
+    if !init.handle().is_object() {
+        return Ok(None);
+    }
+
+    match init.to_object(cx).get(cx, "webSocket")? {
+        Some(value) if value.handle().is_object() => Ok(Some(value.to_object(cx))),
+        Some(value) if !value.handle().is_null_or_undefined() => {
+            ion_err!("webSocket must be a WebSocket", Type)
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Constructs a response with the fetch class, or a subclass of it given as
+/// `new_target`. Returns `None` with an exception pending if that fails.
+fn construct_fetch_response(
+    cx: &Context,
+    new_target: Option<&Object>,
+    args: &[JSVal],
+) -> Option<*mut JSObject> {
+    let class: Object = FETCH_RESPONSE
+        .with(|class| class.borrow().as_ref().map(|class| class.root(cx)))?
+        .into();
+    let new_target = new_target.unwrap_or(&class);
+    let mut response = Object::null(cx);
+    let constructed = unsafe {
+        Construct1(
+            cx.as_ptr(),
+            Value::object(cx, &class).handle(),
+            new_target.handle(),
+            &HandleValueArray::from_rooted_slice(args),
+            response.handle_mut(),
+        )
+    };
+    constructed.then(|| response.handle().get())
+}
+
+/// Creates a response with status 101 that hands `web_socket` over to the
+/// server. Returns `Err(None)` with an exception pending if constructing the
+/// headers fails.
+fn web_socket_response(
+    cx: &Context,
+    body: &Value,
+    init: &Object,
+    web_socket: &Object,
+) -> Result<*mut JSObject, Option<ion::Error>> {
+    match init.get(cx, "status")? {
+        Some(status) if status.handle().is_number() && status.handle().to_number() == 101.0 => (),
+        _ => {
+            return Err(Some(ion_mk_err!(
+                "Responses with a webSocket must have status 101",
+                Range
+            )))
+        }
+    }
+    if !body.handle().is_null_or_undefined() {
+        return Err(Some(ion_mk_err!(
+            "Responses with a webSocket can't have a body",
+            Type
+        )));
+    }
+
+    let handle = handle_of(cx, web_socket).ok_or_else(|| {
+        ion_mk_err!(
+            "webSocket must be the end of a WebSocketPair that wasn't accepted",
+            Type
+        )
+    })?;
+    let handle = WebSocketHandle::get_mut_private(cx, &handle)
+        .ok_or_else(|| ion_mk_err!("webSocket must be a WebSocket", Type))?;
+    if handle.returned {
+        return Err(Some(ion_mk_err!(
+            "The WebSocketPair was already returned in a response",
+            Type
+        )));
+    }
+
+    // The fetch class takes care of validating the headers
+    let headers_init = Object::new(cx);
+    if let Some(headers) = init.get(cx, "headers")? {
+        headers_init.set(cx, "headers", &headers);
+    }
+    let headers_response = construct_fetch_response(
+        cx,
+        None,
+        &[NullValue(), ObjectValue(headers_init.handle().get())],
+    )
+    .ok_or(None)?;
+    let headers_response = FetchResponse::get_mut_private(cx, &cx.root(headers_response).into())
+        .ok_or_else(|| ion_mk_err!("Failed to construct the response headers", Normal))?;
+
+    let mut response = hyper::Response::builder().status(hyper::StatusCode::SWITCHING_PROTOCOLS);
+    for (name, value) in headers_response.get_headers_object(cx).iter() {
+        response = response.header(name, value);
+    }
+    let response = response
+        .body(hyper::Body::empty())
+        .map_err(|e| ion_mk_err!(e.to_string(), Normal))?;
+    let url = url::Url::parse("about:blank").unwrap();
+    let response = FetchResponse::from_hyper_response(cx, response, url)?;
+
+    handle.returned = true;
+    let response = FetchResponse::new_object(cx, Box::new(response));
+    let object: Object = cx.root(response).into();
+    if !object.define(
+        cx,
+        "webSocket",
+        &Value::object(cx, web_socket),
+        PropertyFlags::CONSTANT_ENUMERATED,
+    ) {
+        return Err(Some(ion_mk_err!(
+            "Failed to define the response's webSocket",
+            Normal
+        )));
+    }
+
+    Ok(response)
+}
+
+/// Takes the [`Peer`] of the WebSocket in a `Response` object's
+/// `webSocket`, if there is one.
+pub fn take_response_peer(cx: &Context, response: &Object) -> anyhow::Result<Option<Peer>> {
+    let web_socket = match response.get(cx, "webSocket") {
+        Ok(Some(value)) if value.handle().is_object() => value.to_object(cx),
+        _ => return Ok(None),
+    };
+
+    let handle = handle_of(cx, &web_socket).ok_or_else(|| {
+        anyhow!(
+            "Script error: the webSocket of a response must be a WebSocket from a WebSocketPair"
+        )
+    })?;
+    let Some(handle) = WebSocketHandle::get_mut_private(cx, &handle) else {
+        bail!("Script error: the webSocket of a response must be a WebSocket");
+    };
+    if !handle.returned {
+        bail!("Script error: responses with a webSocket must be created with status 101");
+    }
+
+    handle
+        .peer
+        .take()
+        .map(Some)
+        .ok_or_else(|| anyhow!("Script error: the WebSocket was already returned in a response"))
+}
+
+pub(super) fn define(cx: &Context, global: &Object) -> bool {
+    let fetch_response = match global.get(cx, "Response") {
+        Ok(Some(value)) if value.handle().is_object() => value.to_object(cx),
+        _ => return false,
+    };
+    let prototype = match fetch_response.get(cx, "prototype") {
+        Ok(Some(value)) if value.handle().is_object() => value.to_object(cx),
+        _ => return false,
+    };
+
+    let constructor = unsafe {
+        JS_NewFunction(
+            cx.as_ptr(),
+            Some(construct),
+            2,
+            JSFUN_CONSTRUCTOR,
+            b"Response\0".as_ptr() as *const c_char,
+        )
+    };
+    if constructor.is_null() {
+        return false;
+    }
+    let constructor: Object = cx.root(unsafe { JS_GetFunctionObject(constructor) }).into();
+
+    FETCH_RESPONSE.set(Some(PermanentHeap::from_local(&fetch_response)));
+
+    // Static methods such as `Response.json` are inherited
+    unsafe {
+        JS_SetPrototype(
+            cx.as_ptr(),
+            constructor.handle().into(),
+            fetch_response.handle().into(),
+        )
+    }
+    &&constructor.define(
+        cx,
+        "prototype",
+        &Value::object(cx, &prototype),
+        PropertyFlags::PERMANENT | PropertyFlags::READ_ONLY,
+    ) && prototype.define(
+        cx,
+        "constructor",
+        &Value::object(cx, &constructor),
+        PropertyFlags::empty(),
+    ) && global.define(
+        cx,
+        "Response",
+        &Value::object(cx, &constructor),
+        PropertyFlags::empty(),
+    )
+}
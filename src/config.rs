@@ -0,0 +1,666 @@
+//! The `winterjs.toml` configuration file for `serve`. Everything in the file
+//! can also be set through command-line flags or `WINTERJS_*` environment
+//! variables, which take precedence over the file.
+//!
+//! Relative paths in the file are resolved against the directory containing
+//! the file, not the current directory.
+
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context as _};
+use clap::ValueEnum;
+use serde::Deserialize;
+
+use crate::{
+    request_handlers::cloudflare::AssetOptions,
+    runners::watchdog::parse_duration,
+    server::{
+        access_log::{AccessLogFormat, AccessLogOutput},
+        client_addr::TrustedProxy,
+        limits::parse_byte_size,
+        tls::{CertificatePaths, SniCertificate},
+        ListenAddr,
+    },
+    CmdServe, HandlerName,
+};
+
+pub const DEFAULT_CONFIG_FILE: &str = "winterjs.toml";
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct ConfigFile {
+    path: Option<PathBuf>,
+    script: Option<bool>,
+    mode: Option<String>,
+    single_threaded: Option<bool>,
+    watch: Option<bool>,
+    code_cache_dir: Option<PathBuf>,
+    max_js_threads: Option<usize>,
+    processes: Option<usize>,
+    shutdown_timeout: Option<u64>,
+
+    #[serde(default)]
+    listen: Vec<String>,
+    unix_socket_mode: Option<String>,
+    http2: Option<bool>,
+    compress: Option<bool>,
+    compress_min_size: Option<ByteSize>,
+    #[serde(default)]
+    compress_skip_types: Vec<String>,
+    #[serde(default)]
+    trusted_proxies: Vec<String>,
+    proxy_protocol: Option<bool>,
+    max_body_size: Option<ByteSize>,
+    max_header_size: Option<ByteSize>,
+    header_read_timeout: Option<u64>,
+    idle_timeout: Option<u64>,
+    request_timeout: Option<DurationValue>,
+    cpu_time_limit: Option<DurationValue>,
+    zero_downtime_restart: Option<bool>,
+    tls: Option<TlsSection>,
+
+    access_log: Option<String>,
+    access_log_format: Option<String>,
+    metrics_addr: Option<SocketAddr>,
+    health_addr: Option<SocketAddr>,
+    health_paths: Option<bool>,
+    admin_addr: Option<SocketAddr>,
+
+    /// Environment variables to set for the Javascript code. Variables
+    /// that are already set in the environment are left alone.
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+
+    assets: Option<AssetsSection>,
+}
+
+/// A size in bytes, given either as a number or as a string with a `K`,
+/// `M` or `G` suffix like on the command line.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ByteSize {
+    Bytes(u64),
+    WithSuffix(String),
+}
+
+impl ByteSize {
+    fn parse(&self, field: &str) -> anyhow::Result<u64> {
+        match self {
+            Self::Bytes(bytes) => Ok(*bytes),
+            Self::WithSuffix(s) => parse_byte_size(s).map_err(|e| anyhow!("`{field}`: {e}")),
+        }
+    }
+}
+
+/// A duration, given either as a number of seconds or as a string with an
+/// `ms`, `s` or `m` suffix like on the command line.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DurationValue {
+    Seconds(u64),
+    WithUnit(String),
+}
+
+impl DurationValue {
+    fn parse(&self, field: &str) -> anyhow::Result<Duration> {
+        match self {
+            Self::Seconds(secs) => Ok(Duration::from_secs(*secs)),
+            Self::WithUnit(s) => parse_duration(s).map_err(|e| anyhow!("`{field}`: {e}")),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct TlsSection {
+    cert: PathBuf,
+    key: PathBuf,
+    #[serde(default)]
+    sni: Vec<SniSection>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct SniSection {
+    server_name: String,
+    cert: PathBuf,
+    key: PathBuf,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct AssetsSection {
+    compression: Option<bool>,
+    directory_listing: Option<bool>,
+    cache_control_headers: Option<bool>,
+    security_headers: Option<bool>,
+    redirect_trailing_slash: Option<bool>,
+}
+
+/// The validated contents of a configuration file.
+pub struct ServeConfig {
+    file: ConfigFile,
+    listen: Vec<ListenAddr>,
+    trusted_proxies: Vec<TrustedProxy>,
+    mode: Option<HandlerName>,
+    access_log: Option<AccessLogOutput>,
+    access_log_format: Option<AccessLogFormat>,
+    compress_min_size: Option<u64>,
+    max_body_size: Option<u64>,
+    max_header_size: Option<u64>,
+    request_timeout: Option<Duration>,
+    cpu_time_limit: Option<Duration>,
+    #[cfg(unix)]
+    unix_socket_mode: Option<u32>,
+}
+
+impl ServeConfig {
+    /// Loads the given configuration file, or `winterjs.toml` in the
+    /// current directory if there is one.
+    pub fn load(path: Option<&Path>) -> anyhow::Result<Option<Self>> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => {
+                let default = PathBuf::from(DEFAULT_CONFIG_FILE);
+                if !default.is_file() {
+                    return Ok(None);
+                }
+                default
+            }
+        };
+
+        let contents = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read config file '{}'", path.display()))?;
+        let file = toml::from_str::<ConfigFile>(&contents)
+            .with_context(|| format!("Failed to parse config file '{}'", path.display()))?;
+
+        let base_dir = path.parent().map(|p| p.to_path_buf()).unwrap_or_default();
+        let config = Self::validate(file, &base_dir)
+            .with_context(|| format!("Invalid config file '{}'", path.display()))?;
+
+        tracing::info!("Loaded configuration from '{}'", path.display());
+        Ok(Some(config))
+    }
+
+    fn validate(mut file: ConfigFile, base_dir: &Path) -> anyhow::Result<Self> {
+        let resolve = |path: &mut PathBuf| {
+            if path.is_relative() {
+                *path = base_dir.join(&*path);
+            }
+        };
+
+        if let Some(path) = &mut file.path {
+            resolve(path);
+            if !path.exists() {
+                bail!("`path`: '{}' does not exist", path.display());
+            }
+        }
+
+        if let Some(dir) = &mut file.code_cache_dir {
+            resolve(dir);
+        }
+
+        if file.max_js_threads == Some(0) {
+            bail!("`max-js-threads` must be at least 1");
+        }
+        if file.processes == Some(0) {
+            bail!("`processes` must be at least 1");
+        }
+
+        let mode = file
+            .mode
+            .as_deref()
+            .map(|mode| parse_value_enum::<HandlerName>("mode", mode))
+            .transpose()?;
+
+        let listen = file
+            .listen
+            .iter()
+            .map(|addr| {
+                let addr = addr
+                    .parse::<ListenAddr>()
+                    .map_err(|e| anyhow!("`listen`: invalid address '{addr}': {e}"))?;
+                Ok(match addr {
+                    #[cfg(unix)]
+                    ListenAddr::Unix(mut path) => {
+                        resolve(&mut path);
+                        ListenAddr::Unix(path)
+                    }
+                    addr => addr,
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let trusted_proxies = file
+            .trusted_proxies
+            .iter()
+            .map(|proxy| {
+                proxy
+                    .parse::<TrustedProxy>()
+                    .map_err(|e| anyhow!("`trusted-proxies`: {e}"))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        #[cfg(unix)]
+        let unix_socket_mode = file
+            .unix_socket_mode
+            .as_deref()
+            .map(crate::parse_octal_mode)
+            .transpose()
+            .map_err(|e| anyhow!("`unix-socket-mode`: {e}"))?;
+        #[cfg(not(unix))]
+        if file.unix_socket_mode.is_some() {
+            bail!("`unix-socket-mode` is not supported on this platform");
+        }
+        #[cfg(not(unix))]
+        if file.processes.is_some() || file.zero_downtime_restart.is_some() {
+            bail!("`processes` and `zero-downtime-restart` are not supported on this platform");
+        }
+
+        if let Some(tls) = &mut file.tls {
+            resolve(&mut tls.cert);
+            resolve(&mut tls.key);
+            for sni in &mut tls.sni {
+                if sni.server_name.is_empty() {
+                    bail!("`tls.sni`: `server-name` must not be empty");
+                }
+                sni.server_name.make_ascii_lowercase();
+                resolve(&mut sni.cert);
+                resolve(&mut sni.key);
+            }
+        }
+
+        let access_log = file
+            .access_log
+            .as_deref()
+            .map(|output| {
+                output
+                    .parse::<AccessLogOutput>()
+                    .map_err(|e| anyhow!("`access-log`: {e}"))
+            })
+            .transpose()?
+            .map(|output| match output {
+                AccessLogOutput::File(mut path) => {
+                    resolve(&mut path);
+                    AccessLogOutput::File(path)
+                }
+                output => output,
+            });
+        let access_log_format = file
+            .access_log_format
+            .as_deref()
+            .map(|format| parse_value_enum::<AccessLogFormat>("access-log-format", format))
+            .transpose()?;
+
+        let compress_min_size = file
+            .compress_min_size
+            .as_ref()
+            .map(|size| size.parse("compress-min-size"))
+            .transpose()?;
+        let max_body_size = file
+            .max_body_size
+            .as_ref()
+            .map(|size| size.parse("max-body-size"))
+            .transpose()?;
+        let max_header_size = file
+            .max_header_size
+            .as_ref()
+            .map(|size| size.parse("max-header-size"))
+            .transpose()?;
+        let request_timeout = file
+            .request_timeout
+            .as_ref()
+            .map(|timeout| timeout.parse("request-timeout"))
+            .transpose()?;
+        let cpu_time_limit = file
+            .cpu_time_limit
+            .as_ref()
+            .map(|limit| limit.parse("cpu-time-limit"))
+            .transpose()?;
+
+        for name in file.env.keys() {
+            if name.is_empty() || name.contains(['=', '\0']) {
+                bail!("`env`: invalid variable name '{name}'");
+            }
+        }
+
+        Ok(Self {
+            file,
+            listen,
+            trusted_proxies,
+            mode,
+            access_log,
+            access_log_format,
+            compress_min_size,
+            max_body_size,
+            max_header_size,
+            request_timeout,
+            cpu_time_limit,
+            #[cfg(unix)]
+            unix_socket_mode,
+        })
+    }
+
+    /// Fills in everything that wasn't specified on the command line, and
+    /// applies the settings that can only come from the file.
+    pub fn apply(self, cmd: &mut CmdServe) {
+        let Self {
+            file,
+            listen,
+            trusted_proxies,
+            mode,
+            access_log,
+            access_log_format,
+            compress_min_size,
+            max_body_size,
+            max_header_size,
+            request_timeout,
+            cpu_time_limit,
+            #[cfg(unix)]
+            unix_socket_mode,
+        } = self;
+
+        if cmd.js_path.is_none() {
+            cmd.js_path = file.path;
+        }
+        cmd.script = cmd.script.or(file.script);
+        cmd.mode = cmd.mode.take().or(mode);
+        cmd.single_threaded = cmd.single_threaded.or(file.single_threaded);
+        cmd.watch = cmd.watch.or(file.watch);
+        cmd.code_cache_dir = cmd.code_cache_dir.take().or(file.code_cache_dir);
+        cmd.max_js_threads = cmd.max_js_threads.or(file.max_js_threads);
+
+        // --port replaces the addresses in the file just like it replaces
+        // the default address
+        if cmd.listen.is_empty() && cmd.port.is_none() {
+            cmd.listen = listen;
+        }
+        cmd.http2 = cmd.http2.or(file.http2);
+        cmd.compress = cmd.compress.or(file.compress);
+        cmd.compress_min_size = cmd.compress_min_size.or(compress_min_size);
+        if cmd.compress_skip_types.is_empty() {
+            cmd.compress_skip_types = file.compress_skip_types;
+        }
+        cmd.proxy_protocol = cmd.proxy_protocol.or(file.proxy_protocol);
+        if cmd.trusted_proxies.is_empty() {
+            cmd.trusted_proxies = trusted_proxies;
+        }
+        cmd.max_body_size = cmd.max_body_size.or(max_body_size);
+        cmd.max_header_size = cmd.max_header_size.or(max_header_size);
+        cmd.header_read_timeout = cmd.header_read_timeout.or(file.header_read_timeout);
+        cmd.idle_timeout = cmd.idle_timeout.or(file.idle_timeout);
+        cmd.request_timeout = cmd.request_timeout.or(request_timeout);
+        cmd.cpu_time_limit = cmd.cpu_time_limit.or(cpu_time_limit);
+
+        #[cfg(unix)]
+        {
+            cmd.unix_socket_mode = cmd.unix_socket_mode.or(unix_socket_mode);
+            cmd.processes = cmd.processes.or(file.processes);
+            cmd.zero_downtime_restart = cmd.zero_downtime_restart.or(file.zero_downtime_restart);
+        }
+
+        #[cfg(not(target_os = "wasi"))]
+        {
+            cmd.shutdown_timeout = cmd.shutdown_timeout.or(file.shutdown_timeout);
+        }
+
+        if let Some(tls) = file.tls {
+            cmd.tls_cert = cmd.tls_cert.take().or(Some(tls.cert));
+            cmd.tls_key = cmd.tls_key.take().or(Some(tls.key));
+            if cmd.tls_sni.is_empty() {
+                cmd.tls_sni = tls
+                    .sni
+                    .into_iter()
+                    .map(|sni| SniCertificate {
+                        server_name: sni.server_name,
+                        paths: CertificatePaths {
+                            cert: sni.cert,
+                            key: sni.key,
+                        },
+                    })
+                    .collect();
+            }
+        }
+
+        cmd.access_log = cmd.access_log.take().or(access_log);
+        cmd.access_log_format = cmd.access_log_format.or(access_log_format);
+        cmd.metrics_addr = cmd.metrics_addr.or(file.metrics_addr);
+        cmd.health_addr = cmd.health_addr.or(file.health_addr);
+        cmd.health_paths = cmd.health_paths.or(file.health_paths);
+        cmd.admin_addr = cmd.admin_addr.or(file.admin_addr);
+
+        for (name, value) in file.env {
+            if std::env::var_os(&name).is_none() {
+                std::env::set_var(name, value);
+            }
+        }
+
+        if let Some(assets) = file.assets {
+            let defaults = AssetOptions::default();
+            crate::request_handlers::cloudflare::set_asset_options(AssetOptions {
+                compression: assets.compression.unwrap_or(defaults.compression),
+                directory_listing: assets
+                    .directory_listing
+                    .unwrap_or(defaults.directory_listing),
+                cache_control_headers: assets
+                    .cache_control_headers
+                    .unwrap_or(defaults.cache_control_headers),
+                security_headers: assets.security_headers.unwrap_or(defaults.security_headers),
+                redirect_trailing_slash: assets
+                    .redirect_trailing_slash
+                    .unwrap_or(defaults.redirect_trailing_slash),
+            });
+        }
+    }
+}
+
+/// Checks the options that only make sense together. This runs after the
+/// config file has been merged in, since either half may come from the file.
+pub fn check_serve_options(cmd: &CmdServe) -> anyhow::Result<()> {
+    match (&cmd.tls_cert, &cmd.tls_key) {
+        (Some(_), None) => bail!("--tls-cert requires --tls-key"),
+        (None, Some(_)) => bail!("--tls-key requires --tls-cert"),
+        (None, None) if !cmd.tls_sni.is_empty() => bail!("--tls-sni requires --tls-cert"),
+        _ => (),
+    }
+
+    if cmd.compress.is_none() {
+        if cmd.compress_min_size.is_some() {
+            bail!("--compress-min-size requires --compress");
+        }
+        if !cmd.compress_skip_types.is_empty() {
+            bail!("--compress-skip-type requires --compress");
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_value_enum<T: ValueEnum>(field: &str, value: &str) -> anyhow::Result<T> {
+    T::from_str(value, true).map_err(|_| {
+        let possible = T::value_variants()
+            .iter()
+            .filter_map(|v| v.to_possible_value())
+            .map(|v| format!("'{}'", v.get_name()))
+            .collect::<Vec<_>>()
+            .join(", ");
+        anyhow!("`{field}`: invalid value '{value}', expected one of {possible}")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    fn base_dir() -> &'static Path {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+    }
+
+    fn parse(contents: &str) -> anyhow::Result<ServeConfig> {
+        let file = toml::from_str::<ConfigFile>(contents)?;
+        ServeConfig::validate(file, base_dir())
+    }
+
+    fn serve_args(args: &[&str]) -> CmdServe {
+        CmdServe::try_parse_from(std::iter::once("serve").chain(args.iter().copied())).unwrap()
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        assert!(parse("max-js-threads = 4").is_ok());
+        assert!(parse("max-threads = 4").is_err());
+        assert!(parse("[tls]\ncert = 'cert.pem'\nkey = 'key.pem'\npassword = 'secret'").is_err());
+        assert!(parse("[assets]\ncompress = true").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn resolves_paths_against_the_config_directory() {
+        let config = parse(
+            r#"
+            path = "src/main.rs"
+            code-cache-dir = "cache"
+            access-log = "logs/access.log"
+            listen = ["unix:run/winterjs.sock", "127.0.0.1:8080"]
+
+            [tls]
+            cert = "certs/cert.pem"
+            key = "/etc/winterjs/key.pem"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.file.path, Some(base_dir().join("src/main.rs")));
+        assert_eq!(config.file.code_cache_dir, Some(base_dir().join("cache")));
+        assert!(matches!(
+            &config.access_log,
+            Some(AccessLogOutput::File(path)) if *path == base_dir().join("logs/access.log")
+        ));
+        assert_eq!(
+            config.listen,
+            vec![
+                ListenAddr::Unix(base_dir().join("run/winterjs.sock")),
+                ListenAddr::Tcp("127.0.0.1:8080".parse().unwrap()),
+            ]
+        );
+        let tls = config.file.tls.as_ref().unwrap();
+        assert_eq!(tls.cert, base_dir().join("certs/cert.pem"));
+        assert_eq!(tls.key, PathBuf::from("/etc/winterjs/key.pem"));
+
+        assert!(parse(r#"path = "does-not-exist.js""#).is_err());
+    }
+
+    #[test]
+    fn command_line_overrides_the_file() {
+        let contents = r#"
+            path = "src/main.rs"
+            max-js-threads = 4
+            http2 = true
+            compress = true
+            compress-min-size = "2K"
+            listen = ["127.0.0.1:8080"]
+
+            [tls]
+            cert = "cert.pem"
+            key = "key.pem"
+
+            [[tls.sni]]
+            server-name = "Example.com"
+            cert = "example.pem"
+            key = "example-key.pem"
+            "#;
+
+        let mut cmd = serve_args(&[
+            "--max-js-threads",
+            "8",
+            "--http2=false",
+            "--compress=false",
+            "--tls-cert",
+            "/other/cert.pem",
+            "--listen",
+            "0.0.0.0:9000",
+            "other.js",
+        ]);
+        parse(contents).unwrap().apply(&mut cmd);
+        assert_eq!(cmd.js_path, Some(PathBuf::from("other.js")));
+        assert_eq!(cmd.max_js_threads, Some(8));
+        assert_eq!(cmd.http2, Some(false));
+        assert_eq!(cmd.compress, Some(false));
+        assert_eq!(cmd.compress_min_size, Some(2048));
+        assert_eq!(
+            cmd.listen,
+            vec![ListenAddr::Tcp("0.0.0.0:9000".parse().unwrap())]
+        );
+        assert_eq!(cmd.tls_cert, Some(PathBuf::from("/other/cert.pem")));
+        assert_eq!(cmd.tls_key, Some(base_dir().join("key.pem")));
+        assert_eq!(cmd.tls_sni.len(), 1);
+        assert_eq!(cmd.tls_sni[0].server_name, "example.com");
+
+        // --port replaces the addresses in the file
+        let mut cmd = serve_args(&["--port", "9000"]);
+        parse(contents).unwrap().apply(&mut cmd);
+        assert!(cmd.listen.is_empty());
+        assert_eq!(cmd.js_path, Some(base_dir().join("src/main.rs")));
+        assert_eq!(cmd.max_js_threads, Some(4));
+        assert_eq!(cmd.http2, Some(true));
+        assert_eq!(cmd.compress, Some(true));
+    }
+
+    #[test]
+    fn checks_options_after_merging_the_file() {
+        let check = |contents: &str, args: &[&str]| {
+            let mut cmd = serve_args(args);
+            parse(contents).unwrap().apply(&mut cmd);
+            check_serve_options(&cmd)
+        };
+
+        assert!(check("", &[]).is_ok());
+        assert!(check("", &["--tls-cert", "cert.pem"]).is_err());
+        assert!(check("", &["--tls-key", "key.pem"]).is_err());
+        assert!(check("", &["--tls-sni", "example.com=cert.pem,key.pem"]).is_err());
+        assert!(check(
+            "[tls]\ncert = 'cert.pem'\nkey = 'key.pem'",
+            &["--tls-sni", "example.com=cert.pem,key.pem"]
+        )
+        .is_ok());
+
+        assert!(check("", &["--compress-min-size", "2K"]).is_err());
+        assert!(check("", &["--compress-skip-type", "image/*"]).is_err());
+        assert!(check("compress = true", &["--compress-min-size", "2K"]).is_ok());
+        assert!(check("", &["--compress", "--compress-skip-type", "image/*"]).is_ok());
+    }
+
+    #[test]
+    fn env_does_not_override_variables_that_are_set() {
+        std::env::set_var("WINTERJS_CONFIG_TEST_SET", "from environment");
+        std::env::remove_var("WINTERJS_CONFIG_TEST_UNSET");
+
+        let config = parse(
+            r#"
+            [env]
+            WINTERJS_CONFIG_TEST_SET = "from file"
+            WINTERJS_CONFIG_TEST_UNSET = "from file"
+            "#,
+        )
+        .unwrap();
+        config.apply(&mut serve_args(&[]));
+
+        assert_eq!(
+            std::env::var("WINTERJS_CONFIG_TEST_SET").unwrap(),
+            "from environment"
+        );
+        assert_eq!(
+            std::env::var("WINTERJS_CONFIG_TEST_UNSET").unwrap(),
+            "from file"
+        );
+
+        assert!(parse("[env]\n'A=B' = 'value'").is_err());
+    }
+}
@@ -12,9 +12,10 @@ use std::{
 use std::time::Duration;
 
 use anyhow::Context as _;
-use clap::{Parser, ValueEnum};
+use clap::{builder::BoolishValueParser, Parser, ValueEnum};
 use request_handlers::{
     cloudflare::CloudflareRequestHandler, wintercg::WinterCGRequestHandler, Either, UserCode,
+    UserCodeSource,
 };
 
 use server::BoxedDynRunner;
@@ -46,17 +47,27 @@ macro_rules! js_try {
 }
 
 mod builtins;
+mod config;
 mod request_handlers;
 mod runners;
 mod server;
 mod sm_utils;
+mod stencil_cache;
 
 fn main() {
     if let Err(e) = run() {
-        println!("{e:?}");
+        eprintln!("{e:?}");
+        exit(1);
     }
 }
 
+/// Exits with `code`, after flushing output that would otherwise be lost.
+fn exit(code: i32) -> ! {
+    use std::io::Write;
+    _ = std::io::stdout().flush();
+    std::process::exit(code)
+}
+
 fn run() -> Result<(), anyhow::Error> {
     // Initialize logging.
     if std::env::var("RUST_LOG").is_err() {
@@ -87,10 +98,97 @@ fn run() -> Result<(), anyhow::Error> {
                 .set(runtime::config::Config::default().log_level(runtime::config::LogLevel::Error))
                 .unwrap();
 
-            runners::exec::exec_script(cmd.js_path, cmd.script)
+            let code = runners::exec::exec_script(cmd.js_path, cmd.script, cmd.args)?;
+            if code != 0 {
+                exit(code);
+            }
+            Ok(())
+        }
+
+        #[cfg(not(target_os = "wasi"))]
+        Cmd::Repl(cmd) => {
+            runtime::config::CONFIG
+                .set(runtime::config::Config::default().log_level(runtime::config::LogLevel::Error))
+                .unwrap();
+
+            runners::repl::run_repl(cmd.history)
+        }
+
+        Cmd::Check(cmd) => {
+            runtime::config::CONFIG
+                .set(runtime::config::Config::default().log_level(runtime::config::LogLevel::Error))
+                .unwrap();
+
+            let source = UserCodeSource {
+                path: cmd.js_path,
+                script_mode: cmd.script,
+            };
+            if !runners::check::check(source, build_request_handler(cmd.mode))? {
+                exit(1);
+            }
+            Ok(())
+        }
+
+        Cmd::Invoke(cmd) => {
+            runtime::config::CONFIG
+                .set(runtime::config::Config::default().log_level(runtime::config::LogLevel::Error))
+                .unwrap();
+
+            let user_code = UserCode::from_path(&cmd.js_path, cmd.script)?;
+            let request = runners::invoke::InvokeRequest {
+                method: cmd.method,
+                url: cmd.url,
+                headers: cmd.headers,
+                body: cmd.body,
+            };
+            match cmd.mode {
+                Some(HandlerName::Cloudflare) => runners::invoke::invoke(
+                    CloudflareRequestHandler,
+                    user_code,
+                    request,
+                    cmd.format,
+                ),
+                Some(HandlerName::WinterCG) | None => {
+                    runners::invoke::invoke(WinterCGRequestHandler, user_code, request, cmd.format)
+                }
+            }
+        }
+
+        Cmd::Test(cmd) => {
+            runtime::config::CONFIG
+                .set(runtime::config::Config::default().log_level(runtime::config::LogLevel::Error))
+                .unwrap();
+
+            let passed = runners::test::run_tests(runners::test::TestOptions {
+                paths: cmd.paths,
+                script_mode: cmd.script,
+                handler: build_request_handler(cmd.mode),
+                filter: cmd.filter,
+                timeout: std::time::Duration::from_millis(cmd.timeout),
+                reporter: cmd.reporter,
+                output: cmd.output,
+            })?;
+            if !passed {
+                exit(1);
+            }
+            Ok(())
         }
 
-        Cmd::Serve(cmd) => {
+        Cmd::Serve(mut cmd) => {
+            if let Some(config) = config::ServeConfig::load(cmd.config.as_deref())? {
+                config.apply(&mut cmd);
+            }
+            config::check_serve_options(&cmd)?;
+
+            let js_path = cmd.js_path.context(
+                "No Javascript file to serve, specify one on the command line or \
+                set `path` in the config file",
+            )?;
+            let max_js_threads = cmd.max_js_threads.unwrap_or(16);
+            if max_js_threads == 0 {
+                anyhow::bail!("--max-js-threads must be at least 1");
+            }
+
             let interface = if let Some(iface) = cmd.ip {
                 iface
             } else if let Ok(value) = std::env::var("LISTEN_IP") {
@@ -111,14 +209,177 @@ fn run() -> Result<(), anyhow::Error> {
                 8080
             };
 
-            let addr: SocketAddr = (interface, port).into();
-            let config = crate::server::ServerConfig { addr };
+            // Sockets passed in by a supervisor take the place of the default
+            // address, but can be combined with explicit --listen addresses.
+            #[cfg(unix)]
+            let inherited = crate::server::socket_activation::take_listen_fds()?;
+            #[cfg(not(unix))]
+            let inherited = vec![];
+
+            let mut listen = cmd.listen;
+            listen.extend(inherited);
+            if listen.is_empty() {
+                let addr: SocketAddr = (interface, port).into();
+                listen.push(crate::server::ListenAddr::Tcp(addr));
+            }
+
+            // When taking over from a previous process, its sockets replace
+            // everything we'd otherwise listen on.
+            #[cfg(unix)]
+            if let Some(inherited) = crate::server::handoff::take_inherited_listeners()? {
+                listen = inherited;
+            }
+
+            // Prefork workers serve on whatever the supervisor tells them to,
+            // and share TCP ports with the other workers.
+            #[cfg(unix)]
+            let reuse_port = match crate::server::prefork::take_worker_config()? {
+                Some(worker) => {
+                    tracing::info!("Starting prefork worker {}", worker.index);
+                    listen = worker.listen;
+                    true
+                }
+                None => match cmd.processes {
+                    Some(processes) if processes > 1 => {
+                        if cmd.zero_downtime_restart.unwrap_or_default() {
+                            anyhow::bail!(
+                                "--processes can't be combined with --zero-downtime-restart"
+                            );
+                        }
+                        if cmd.metrics_addr.is_some()
+                            || cmd.health_addr.is_some()
+                            || cmd.admin_addr.is_some()
+                        {
+                            // Each process keeps its own metrics and state,
+                            // and they can't all listen on the same address.
+                            anyhow::bail!(
+                                "--processes can't be combined with --metrics-addr, \
+                                --health-addr or --admin-addr"
+                            );
+                        }
+                        return crate::server::prefork::supervise(
+                            processes,
+                            listen,
+                            cmd.unix_socket_mode,
+                        );
+                    }
+                    _ => false,
+                },
+            };
+            #[cfg(not(unix))]
+            let reuse_port = false;
+
+            let tls = match (cmd.tls_cert, cmd.tls_key) {
+                (Some(cert), Some(key)) => Some(crate::server::tls::TlsConfig {
+                    default_certificate: crate::server::tls::CertificatePaths { cert, key },
+                    sni_certificates: cmd.tls_sni,
+                }),
+                _ => None,
+            };
+
+            #[cfg(unix)]
+            let unix_socket_mode = cmd.unix_socket_mode;
+            #[cfg(not(unix))]
+            let unix_socket_mode = None;
+
+            #[cfg(unix)]
+            let zero_downtime_restart = cmd.zero_downtime_restart.unwrap_or_default();
+            #[cfg(not(unix))]
+            let zero_downtime_restart = false;
+
+            #[cfg(not(target_os = "wasi"))]
+            let shutdown_timeout = {
+                let timeout = cmd
+                    .shutdown_timeout
+                    .map(Duration::from_secs)
+                    .unwrap_or_else(|| Duration::from_secs(60));
+                if timeout.is_zero() {
+                    None
+                } else {
+                    Some(timeout)
+                }
+            };
+            #[cfg(target_os = "wasi")]
+            let shutdown_timeout = None;
+
+            let max_header_size = match cmd.max_header_size {
+                Some(size) if size < crate::server::limits::MIN_MAX_HEADER_SIZE as u64 => {
+                    anyhow::bail!(
+                        "--max-header-size must be at least {} bytes",
+                        crate::server::limits::MIN_MAX_HEADER_SIZE
+                    );
+                }
+                Some(size) => Some(usize::try_from(size).unwrap_or(usize::MAX)),
+                None => None,
+            };
+            let positive_secs =
+                |secs: Option<u64>| secs.filter(|secs| *secs > 0).map(Duration::from_secs);
+
+            let config = crate::server::ServerConfig {
+                listen,
+                unix_socket_mode,
+                tls,
+                http2: cmd.http2.unwrap_or_default(),
+                trusted_proxies: cmd.trusted_proxies,
+                proxy_protocol: cmd.proxy_protocol.unwrap_or_default(),
+                limits: crate::server::limits::RequestLimits {
+                    max_body_size: cmd.max_body_size,
+                    max_header_size,
+                    header_read_timeout: positive_secs(cmd.header_read_timeout),
+                    idle_timeout: positive_secs(cmd.idle_timeout),
+                },
+                compression: cmd.compress.unwrap_or_default().then(|| {
+                    let defaults = crate::server::compression::CompressionConfig::default();
+                    crate::server::compression::CompressionConfig {
+                        min_size: cmd.compress_min_size.unwrap_or(defaults.min_size),
+                        skip_content_types: match cmd.compress_skip_types.is_empty() {
+                            true => defaults.skip_content_types,
+                            false => cmd.compress_skip_types,
+                        },
+                    }
+                }),
+                access_log: cmd.access_log.map(|output| {
+                    crate::server::access_log::AccessLogConfig {
+                        output,
+                        format: cmd
+                            .access_log_format
+                            .unwrap_or(crate::server::access_log::AccessLogFormat::Combined),
+                    }
+                }),
+                health_addr: cmd.health_addr,
+                health_paths: cmd.health_paths.unwrap_or_default(),
+                metrics_addr: cmd.metrics_addr,
+                admin_addr: cmd.admin_addr,
+                reuse_port,
+                zero_downtime_restart,
+                shutdown_timeout,
+            };
 
             runtime::config::CONFIG
                 .set(runtime::config::Config::default().log_level(runtime::config::LogLevel::Error))
                 .unwrap();
 
-            let user_code = UserCode::from_path(&cmd.js_path, cmd.script)?;
+            let source = UserCodeSource {
+                path: js_path,
+                script_mode: cmd.script.unwrap_or_default(),
+            };
+            let user_code = source.load()?;
+            let single_threaded = cmd.single_threaded.unwrap_or_default();
+            let watch = cmd.watch.unwrap_or_default();
+            if watch && single_threaded {
+                anyhow::bail!("--watch can't be combined with --single-threaded");
+            }
+            let watch = watch.then(|| source.clone());
+            if watch.is_some() {
+                runners::watch::enable_module_tracking();
+            }
+            if let Some(dir) = cmd.code_cache_dir {
+                stencil_cache::set_cache_dir(dir);
+            }
+            let limits = runners::watchdog::ExecutionLimits {
+                wall_time: cmd.request_timeout.filter(|t| !t.is_zero()),
+                cpu_time: cmd.cpu_time_limit.filter(|t| !t.is_zero()),
+            };
 
             let runner: Either<
                 BoxedDynRunner,
@@ -126,14 +387,16 @@ fn run() -> Result<(), anyhow::Error> {
                     BoxedDynRunner,
                     Pin<Box<dyn runners::inline::InlineRunnerRequestHandlerFuture>>,
                 ),
-            > = match (cmd.mode, cmd.single_threaded) {
+            > = match (cmd.mode, single_threaded) {
                 (Some(HandlerName::Cloudflare), false) => {
                     tracing::info!("Starting in Cloudflare mode");
                     Either::Left(Box::new(
                         runners::single::SingleRunner::new_request_handler(
                             CloudflareRequestHandler,
-                            cmd.max_js_threads,
+                            max_js_threads,
                             user_code,
+                            source,
+                            limits,
                         ),
                     ))
                 }
@@ -142,6 +405,7 @@ fn run() -> Result<(), anyhow::Error> {
                     let (runner, future) = runners::inline::InlineRunner::new_request_handler(
                         CloudflareRequestHandler,
                         user_code,
+                        limits,
                     );
                     Either::Right((Box::new(runner), Box::pin(future)))
                 }
@@ -150,8 +414,10 @@ fn run() -> Result<(), anyhow::Error> {
                     Either::Left(Box::new(
                         runners::single::SingleRunner::new_request_handler(
                             WinterCGRequestHandler,
-                            cmd.max_js_threads,
+                            max_js_threads,
                             user_code,
+                            source,
+                            limits,
                         ),
                     ))
                 }
@@ -160,6 +426,7 @@ fn run() -> Result<(), anyhow::Error> {
                     let (runner, future) = runners::inline::InlineRunner::new_request_handler(
                         WinterCGRequestHandler,
                         user_code,
+                        limits,
                     );
                     Either::Right((Box::new(runner), Box::pin(future)))
                 }
@@ -180,15 +447,7 @@ fn run() -> Result<(), anyhow::Error> {
             // for native builds only.
             #[cfg(not(target_os = "wasi"))]
             {
-                let timeout = cmd
-                    .shutdown_timeout
-                    .map(Duration::from_secs)
-                    .unwrap_or_else(|| Duration::from_secs(60));
-                let timeout = if timeout.is_zero() {
-                    None
-                } else {
-                    Some(timeout)
-                };
+                let timeout = shutdown_timeout;
 
                 let runner_clone = match runner {
                     Either::Left(ref r) => Either::Left(r.clone()),
@@ -218,7 +477,12 @@ fn run() -> Result<(), anyhow::Error> {
                     .enable_all()
                     .build()
                     .expect("Failed building the Runtime")
-                    .block_on(crate::server::run_server(config, runner, rx)),
+                    .block_on(async move {
+                        if let Some(source) = watch {
+                            tokio::spawn(runners::watch::watch_for_changes(runner.clone(), source));
+                        }
+                        crate::server::run_server(config, runner, rx).await
+                    }),
                 Either::Right((runner, runner_future)) => {
                     tokio::runtime::Builder::new_current_thread()
                         .enable_all()
@@ -251,9 +515,22 @@ struct Args {
 
 /// Available commands.
 #[derive(clap::Subcommand, Debug)]
+#[allow(clippy::large_enum_variant)]
 enum Cmd {
     Serve(CmdServe),
     Exec(CmdExec),
+    #[cfg(not(target_os = "wasi"))]
+    Repl(CmdRepl),
+    Test(CmdTest),
+    Check(CmdCheck),
+    Invoke(CmdInvoke),
+}
+
+fn build_request_handler(mode: Option<HandlerName>) -> Box<dyn request_handlers::RequestHandler> {
+    match mode {
+        Some(HandlerName::Cloudflare) => Box::new(CloudflareRequestHandler),
+        Some(HandlerName::WinterCG) | None => Box::new(WinterCGRequestHandler),
+    }
 }
 
 /// Start a WinterJS webserver serving the given JS app.
@@ -268,21 +545,53 @@ struct CmdServe {
     #[clap(long, default_value = "127.0.0.1", env = "WINTERJS_IP")]
     ip: Option<IpAddr>,
 
-    /// Maximum amount of Javascript worker threads to spawn.
-    #[clap(long, default_value = "16", env = "WINTERJS_MAX_JS_THREADS")]
-    max_js_threads: usize,
-
-    // /// Watch the Javascript file for changes and automatically reload.
-    // #[clap(short, long, env = "WINTERJS_WATCH")]
-    // watch: bool,
-    /// Path to a Javascript file to serve.
+    /// Addresses to listen on. Overrides --ip and --port when specified.
+    /// Can be specified multiple times to listen on several addresses,
+    /// e.g. `--listen 0.0.0.0:8080 --listen [::]:8080`. Unix domain
+    /// sockets can be specified as `unix:<path>`, and already-listening
+    /// sockets inherited from the parent process as `fd:<number>`.
+    ///
+    /// Sockets passed in through systemd-style socket activation
+    /// (LISTEN_FDS/LISTEN_PID) are used automatically.
+    #[clap(short, long, env = "WINTERJS_LISTEN", value_delimiter = ',')]
+    listen: Vec<crate::server::ListenAddr>,
+
+    #[cfg(unix)]
+    /// File permissions for Unix domain sockets, in octal, e.g. 660.
+    #[clap(long, env = "WINTERJS_UNIX_SOCKET_MODE", value_parser = parse_octal_mode)]
+    unix_socket_mode: Option<u32>,
+
+    /// Maximum amount of Javascript worker threads to spawn. Defaults to 16.
+    #[clap(long, env = "WINTERJS_MAX_JS_THREADS")]
+    max_js_threads: Option<usize>,
+
+    /// Watch the Javascript code for changes and automatically reload it.
+    /// In module mode, this covers every module imported from the entry
+    /// module. Meant for local development.
+    #[clap(short, long, env = "WINTERJS_WATCH", num_args = 0..=1, require_equals = true, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    watch: Option<bool>,
+
+    /// Keep the compiled Javascript code in this directory, so servers
+    /// started later with the same code don't have to compile it again.
+    #[clap(long, env = "WINTERJS_CODE_CACHE_DIR")]
+    code_cache_dir: Option<PathBuf>,
+
+    /// Path to a Javascript file to serve. Can be left out if the config
+    /// file specifies one.
     #[clap(env = "WINTERJS_PATH")]
-    js_path: PathBuf,
+    js_path: Option<PathBuf>,
+
+    /// Path to a config file. Defaults to `winterjs.toml` in the current
+    /// directory, if it exists. Options specified on the command line or
+    /// through environment variables override the ones in the file; flags
+    /// the file turns on can be turned off again with e.g. `--http2=false`.
+    #[clap(short, long, env = "WINTERJS_CONFIG")]
+    config: Option<PathBuf>,
 
     /// Run in script mode. If this flag is not specified, the JS file will
     /// be loaded in module mode instead.
-    #[clap(short, long, env = "WINTERJS_SCRIPT")]
-    script: bool,
+    #[clap(short, long, env = "WINTERJS_SCRIPT", num_args = 0..=1, require_equals = true, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    script: Option<bool>,
 
     /// The operating mode of the server. Defaults to WinterCG mode if left
     /// out.
@@ -291,8 +600,154 @@ struct CmdServe {
 
     /// If this flag is specified, WinterJS will run in single-threaded mode,
     /// using only the main thread.
-    #[clap(long, env = "WINTERJS_SINGLE_THREADED")]
-    single_threaded: bool,
+    #[clap(long, env = "WINTERJS_SINGLE_THREADED", num_args = 0..=1, require_equals = true, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    single_threaded: Option<bool>,
+
+    /// Path to a PEM file containing the TLS certificate chain. When
+    /// specified along with --tls-key, the server will only accept HTTPS
+    /// connections.
+    #[clap(long, env = "WINTERJS_TLS_CERT")]
+    tls_cert: Option<PathBuf>,
+
+    /// Path to a PEM file containing the private key for --tls-cert.
+    #[clap(long, env = "WINTERJS_TLS_KEY")]
+    tls_key: Option<PathBuf>,
+
+    /// Additional certificates to serve based on the SNI server name, in
+    /// the form `<server name>=<cert path>,<key path>`. Wildcard names
+    /// such as `*.example.com` are supported. Can be specified multiple
+    /// times. Certificate files are reloaded automatically when they
+    /// change.
+    #[clap(long, env = "WINTERJS_TLS_SNI", value_delimiter = ';')]
+    tls_sni: Vec<crate::server::tls::SniCertificate>,
+
+    /// Accept HTTP/2 connections in addition to HTTP/1.1. With TLS, HTTP/2
+    /// is negotiated through ALPN; on plain connections, clients must use
+    /// HTTP/2 with prior knowledge (h2c).
+    #[clap(long, env = "WINTERJS_HTTP2", num_args = 0..=1, require_equals = true, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    http2: Option<bool>,
+
+    /// Compress responses with gzip, brotli or zstd, depending on what the
+    /// client accepts. Small responses and already compressed content
+    /// types such as images are sent as they are.
+    #[clap(long, env = "WINTERJS_COMPRESS", num_args = 0..=1, require_equals = true, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    compress: Option<bool>,
+
+    /// Don't compress responses known to be smaller than this. Accepts a
+    /// `K`, `M` or `G` suffix. Defaults to 1K.
+    #[clap(long, env = "WINTERJS_COMPRESS_MIN_SIZE", value_parser = crate::server::limits::parse_byte_size)]
+    compress_min_size: Option<u64>,
+
+    /// Don't compress responses with this content type. Accepts whole
+    /// families such as `image/*`. Can be specified multiple times, and
+    /// replaces the default list of already compressed types.
+    #[clap(
+        long = "compress-skip-type",
+        env = "WINTERJS_COMPRESS_SKIP_TYPES",
+        value_delimiter = ','
+    )]
+    compress_skip_types: Vec<String>,
+
+    /// Reject requests with a larger body with 413 Payload Too Large. The
+    /// limit is also enforced while streaming bodies of unknown length.
+    /// Accepts a `K`, `M` or `G` suffix, e.g. `10M`.
+    #[clap(long, env = "WINTERJS_MAX_BODY_SIZE", value_parser = crate::server::limits::parse_byte_size)]
+    max_body_size: Option<u64>,
+
+    /// Close connections that send larger request headers. Accepts a `K`,
+    /// `M` or `G` suffix, and must be at least 8K.
+    #[clap(long, env = "WINTERJS_MAX_HEADER_SIZE", value_parser = crate::server::limits::parse_byte_size)]
+    max_header_size: Option<u64>,
+
+    /// Close connections that don't send the full request headers within
+    /// this many seconds of starting to send them (HTTP/1 only).
+    #[clap(long, env = "WINTERJS_HEADER_READ_TIMEOUT")]
+    header_read_timeout: Option<u64>,
+
+    /// Close connections that have been idle for this many seconds between
+    /// requests.
+    #[clap(long, env = "WINTERJS_IDLE_TIMEOUT")]
+    idle_timeout: Option<u64>,
+
+    /// Answer requests that take longer than this to produce a response
+    /// with 504 Gateway Timeout, and stop Javascript code that runs this
+    /// long without yielding. Accepts an `ms`, `s` or `m` suffix; plain
+    /// numbers are seconds.
+    #[clap(long, env = "WINTERJS_REQUEST_TIMEOUT", value_parser = runners::watchdog::parse_duration)]
+    request_timeout: Option<Duration>,
+
+    /// Stop Javascript code that uses more than this much CPU time without
+    /// yielding, e.g. `50ms`. Not enforced on WASIX.
+    #[clap(long, env = "WINTERJS_CPU_TIME_LIMIT", value_parser = runners::watchdog::parse_duration)]
+    cpu_time_limit: Option<Duration>,
+
+    /// Use the client address from `X-Forwarded-For` for requests coming
+    /// from these proxies. Accepts IP addresses, networks in CIDR notation
+    /// such as `10.0.0.0/8`, and `unix` for Unix domain socket peers. Can
+    /// be specified multiple times.
+    #[clap(
+        long = "trusted-proxy",
+        env = "WINTERJS_TRUSTED_PROXIES",
+        value_delimiter = ','
+    )]
+    trusted_proxies: Vec<crate::server::client_addr::TrustedProxy>,
+
+    /// Expect every connection to start with a PROXY protocol (v1 or v2)
+    /// header, as sent by load balancers such as HAProxy or AWS NLB, and
+    /// use the client address from it. Connections without a valid header
+    /// are closed.
+    #[clap(long, env = "WINTERJS_PROXY_PROTOCOL", num_args = 0..=1, require_equals = true, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    proxy_protocol: Option<bool>,
+
+    /// Write a line to the access log for each request, to the given file
+    /// or to `stdout`.
+    #[clap(long, env = "WINTERJS_ACCESS_LOG")]
+    access_log: Option<crate::server::access_log::AccessLogOutput>,
+
+    /// Format of the access log lines. Defaults to `combined`.
+    #[clap(long, env = "WINTERJS_ACCESS_LOG_FORMAT")]
+    access_log_format: Option<crate::server::access_log::AccessLogFormat>,
+
+    /// Serve health and readiness probes at `/healthz` and `/readyz` on
+    /// this address. `/readyz` only succeeds once the Javascript code has
+    /// initialized, and fails again while shutting down.
+    #[clap(long, env = "WINTERJS_HEALTH_ADDR")]
+    health_addr: Option<SocketAddr>,
+
+    /// Answer the health and readiness probes at `/healthz` and `/readyz`
+    /// on the main listeners. Requests to those paths will not reach the
+    /// Javascript code.
+    #[clap(long, env = "WINTERJS_HEALTH_PATHS", num_args = 0..=1, require_equals = true, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    health_paths: Option<bool>,
+
+    /// Serve Prometheus metrics at `/metrics` on this address, e.g.
+    /// `127.0.0.1:9090`.
+    #[clap(long, env = "WINTERJS_METRICS_ADDR")]
+    metrics_addr: Option<SocketAddr>,
+
+    /// Serve admin endpoints on this address, e.g. `127.0.0.1:9091`.
+    /// `POST /reload` reloads the Javascript code, just like sending
+    /// SIGHUP does. The endpoints are not authenticated, so don't make
+    /// this address publicly reachable.
+    #[clap(long, env = "WINTERJS_ADMIN_ADDR")]
+    admin_addr: Option<SocketAddr>,
+
+    #[cfg(unix)]
+    /// Number of WinterJS processes to run. Each process listens on the
+    /// same addresses, with the kernel spreading connections across them,
+    /// and runs its own Javascript worker threads. A supervisor process
+    /// restarts any process that exits, so a crash only affects the
+    /// requests that process was handling.
+    #[clap(long, env = "WINTERJS_PROCESSES")]
+    processes: Option<usize>,
+
+    #[cfg(unix)]
+    /// On SIGUSR2, start a new WinterJS process with the same arguments and
+    /// hand the listening sockets over to it. Once the new process is
+    /// ready, this one stops accepting connections and shuts down cleanly,
+    /// so deployments don't drop any connections.
+    #[clap(long, env = "WINTERJS_ZERO_DOWNTIME_RESTART", num_args = 0..=1, require_equals = true, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    zero_downtime_restart: Option<bool>,
 
     #[cfg(not(target_os = "wasi"))]
     /// Clean shutdown timeout, i.e. how long to wait before forcefully
@@ -314,6 +769,130 @@ struct CmdExec {
     /// be loaded in module mode instead.
     #[clap(short, long, env = "WINTERJS_SCRIPT")]
     script: bool,
+
+    /// Arguments for the script, which it finds in `process.argv` after the
+    /// paths of WinterJS and of the script itself.
+    #[clap(trailing_var_arg = true, allow_hyphen_values = true)]
+    args: Vec<String>,
+}
+
+/// Start an interactive shell with the same builtins as `exec`. Top-level
+/// `await` is supported, and input continues over several lines while
+/// brackets, strings or comments are left open.
+#[cfg(not(target_os = "wasi"))]
+#[derive(clap::Parser, Debug)]
+struct CmdRepl {
+    /// The file to keep the history of the REPL's input in. Defaults to
+    /// `.winterjs_repl_history` in the home directory.
+    #[clap(long, env = "WINTERJS_REPL_HISTORY")]
+    history: Option<PathBuf>,
+}
+
+/// Run the tests in `*.test.js` files, with the same globals as `serve`.
+/// Tests are written with `describe`, `it` and `expect`. Exits with a
+/// non-zero status if any test fails.
+#[derive(clap::Parser, Debug)]
+struct CmdTest {
+    /// Test files, or directories to search for `*.test.js` files. Defaults
+    /// to the current directory.
+    paths: Vec<PathBuf>,
+
+    /// Load the test files in script mode. If this flag is not specified,
+    /// they will be loaded in module mode instead.
+    #[clap(short, long, env = "WINTERJS_SCRIPT")]
+    script: bool,
+
+    /// Which mode's globals the tests run with. Defaults to WinterCG mode if
+    /// left out.
+    #[clap(short = 'H', long, env = "WINTERJS_MODE")]
+    mode: Option<HandlerName>,
+
+    /// Only run tests whose full name, including the names of enclosing
+    /// `describe` blocks, contains this string.
+    #[clap(short, long, env = "WINTERJS_TEST_FILTER")]
+    filter: Option<String>,
+
+    /// The format of the test report.
+    #[clap(
+        long,
+        value_enum,
+        default_value = "tap",
+        env = "WINTERJS_TEST_REPORTER"
+    )]
+    reporter: runners::test::Reporter,
+
+    /// Write the report to this file instead of stdout.
+    #[clap(short, long, env = "WINTERJS_TEST_OUTPUT")]
+    output: Option<PathBuf>,
+
+    /// How long a single test or hook may run before it fails, in
+    /// milliseconds.
+    #[clap(long, default_value_t = 5000, env = "WINTERJS_TEST_TIMEOUT")]
+    timeout: u64,
+}
+
+/// Load a Javascript file or directory like `serve` does, without binding
+/// any ports, and report syntax errors, unresolved imports and missing
+/// request handlers. Exits with a non-zero status if there are problems.
+#[derive(clap::Parser, Debug)]
+struct CmdCheck {
+    /// Path to a Javascript file or directory to check.
+    #[clap(env = "WINTERJS_PATH")]
+    js_path: PathBuf,
+
+    /// Check in script mode. If this flag is not specified, the JS file will
+    /// be loaded in module mode instead.
+    #[clap(short, long, env = "WINTERJS_SCRIPT")]
+    script: bool,
+
+    /// The mode to check the code for. Defaults to WinterCG mode if left
+    /// out.
+    #[clap(short = 'H', long, env = "WINTERJS_MODE")]
+    mode: Option<HandlerName>,
+}
+
+/// Send a single request through a Javascript file's request handler
+/// without starting a server, print the response and exit.
+#[derive(clap::Parser, Debug)]
+struct CmdInvoke {
+    /// Path to a Javascript file or directory to send the request to.
+    #[clap(env = "WINTERJS_PATH")]
+    js_path: PathBuf,
+
+    /// The URL of the request. A path is taken to be on `localhost`.
+    #[clap(default_value = "/")]
+    url: String,
+
+    /// Load the code in script mode. If this flag is not specified, the JS
+    /// file will be loaded in module mode instead.
+    #[clap(short, long, env = "WINTERJS_SCRIPT")]
+    script: bool,
+
+    /// The operating mode of the handler. Defaults to WinterCG mode if left
+    /// out.
+    #[clap(short = 'H', long, env = "WINTERJS_MODE")]
+    mode: Option<HandlerName>,
+
+    /// The request method.
+    #[clap(short = 'X', long, default_value = "GET")]
+    method: http::Method,
+
+    /// A request header, as `Name: value`. Can be given more than once.
+    #[clap(long = "header", value_name = "HEADER")]
+    headers: Vec<String>,
+
+    /// A file to read the request body from, or `-` to read it from stdin.
+    #[clap(long)]
+    body: Option<PathBuf>,
+
+    /// How to print the response.
+    #[clap(long, value_enum, default_value = "text")]
+    format: runners::invoke::OutputFormat,
+}
+
+#[cfg(unix)]
+fn parse_octal_mode(s: &str) -> Result<u32, String> {
+    u32::from_str_radix(s, 8).map_err(|e| format!("invalid octal file mode: {e}"))
 }
 
 #[derive(Debug, Clone, ValueEnum)]
@@ -0,0 +1,68 @@
+//! The `request.cf` object. On Cloudflare, this carries what the edge knows
+//! about the incoming request; we fill in the parts we know ourselves.
+
+use ion::{conversions::ToValue, flags::PropertyFlags, Context, Object};
+
+use crate::server::{ClientAddr, ConnectionInfo};
+
+/// Attached to requests as an extension in Cloudflare mode, and turned into
+/// `request.cf` when the `Request` object is built.
+#[derive(Clone, Debug)]
+pub struct RequestCf {
+    http_protocol: &'static str,
+    tls: bool,
+    client_address: Option<String>,
+}
+
+impl RequestCf {
+    pub fn new(parts: &http::request::Parts) -> Self {
+        let http_protocol = match parts.version {
+            http::Version::HTTP_09 => "HTTP/0.9",
+            http::Version::HTTP_10 => "HTTP/1.0",
+            http::Version::HTTP_2 => "HTTP/2",
+            http::Version::HTTP_3 => "HTTP/3",
+            _ => "HTTP/1.1",
+        };
+
+        Self {
+            http_protocol,
+            tls: parts
+                .extensions
+                .get::<ConnectionInfo>()
+                .is_some_and(|info| info.tls),
+            client_address: parts
+                .extensions
+                .get::<ClientAddr>()
+                .map(|addr| addr.0.to_string()),
+        }
+    }
+
+    /// Defines `cf` on the given `Request` object.
+    pub fn define_on(&self, cx: &Context, request: &Object) -> bool {
+        let cf = Object::new(cx);
+        let client_address_defined = match &self.client_address {
+            Some(client_address) => cf.define(
+                cx,
+                "clientAddress",
+                &client_address.as_value(cx),
+                PropertyFlags::CONSTANT_ENUMERATED,
+            ),
+            None => true,
+        };
+
+        client_address_defined
+            && cf.define(
+                cx,
+                "httpProtocol",
+                &self.http_protocol.as_value(cx),
+                PropertyFlags::CONSTANT_ENUMERATED,
+            )
+            && cf.define(
+                cx,
+                "tls",
+                &self.tls.as_value(cx),
+                PropertyFlags::CONSTANT_ENUMERATED,
+            )
+            && request.define(cx, "cf", &cf.as_value(cx), PropertyFlags::ENUMERATE)
+    }
+}
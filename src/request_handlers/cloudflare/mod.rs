@@ -15,7 +15,7 @@ use self::routes::Routes;
 use super::{
     ByRefStandardModules, Either, PendingResponse, ReadyResponse, Request, RequestHandler, UserCode,
 };
-use anyhow::{bail, Context as _, Result};
+use anyhow::{anyhow, bail, Context as _, Result};
 use ion::{ClassDefinition, Context, Function, Object, Promise, TracedHeap, Value};
 use mozjs_sys::jsapi::JSFunction;
 use runtime::{globals::fetch::Response as FetchResponse, promise::future_to_promise, ContextExt};
@@ -24,6 +24,7 @@ use static_web_server::{
     handler::{RequestHandler as SwsRequestHandler, RequestHandlerOpts as SwsRequestHandlerOpts},
 };
 
+pub mod cf;
 mod context;
 mod env;
 mod routes;
@@ -34,6 +35,35 @@ thread_local! {
     static SWS_OPTS: OnceCell<Arc<SwsRequestHandlerOpts>> = OnceCell::new();
 }
 
+static ASSET_OPTIONS: once_cell::sync::OnceCell<AssetOptions> = once_cell::sync::OnceCell::new();
+
+/// Options for serving static assets through `env.ASSETS`.
+#[derive(Clone, Debug)]
+pub struct AssetOptions {
+    pub compression: bool,
+    pub directory_listing: bool,
+    pub cache_control_headers: bool,
+    pub security_headers: bool,
+    pub redirect_trailing_slash: bool,
+}
+
+impl Default for AssetOptions {
+    fn default() -> Self {
+        Self {
+            compression: true,
+            directory_listing: false,
+            cache_control_headers: true,
+            security_headers: true,
+            redirect_trailing_slash: false,
+        }
+    }
+}
+
+/// Must be called before any requests are handled to take effect.
+pub fn set_asset_options(options: AssetOptions) {
+    _ = ASSET_OPTIONS.set(options);
+}
+
 #[derive(Clone, Copy)]
 pub struct CloudflareRequestHandler;
 
@@ -76,6 +106,7 @@ impl CloudflareRequestHandler {
         SWS_OPTS.with(move |s| {
             s.get_or_init(|| {
                 let path = path.as_ref().to_path_buf();
+                let options = ASSET_OPTIONS.get().cloned().unwrap_or_default();
 
                 Arc::new(static_web_server::handler::RequestHandlerOpts {
                     advanced_opts: None,
@@ -88,16 +119,16 @@ impl CloudflareRequestHandler {
                     // hidden, otherwise every response is a 404
                     ignore_hidden_files: !path.is_hidden(),
                     root_dir: path,
-                    compression: true,
+                    compression: options.compression,
                     compression_static: false,
-                    dir_listing: false,
+                    dir_listing: options.directory_listing,
                     dir_listing_format: static_web_server::directory_listing::DirListFmt::Html,
                     dir_listing_order: 0,
-                    cache_control_headers: true,
+                    cache_control_headers: options.cache_control_headers,
                     cors: None,
                     log_remote_address: false,
-                    redirect_trailing_slash: false,
-                    security_headers: true,
+                    redirect_trailing_slash: options.redirect_trailing_slash,
+                    security_headers: options.security_headers,
                 })
             });
         })
@@ -181,9 +212,7 @@ impl RequestHandler for CloudflareRequestHandler {
                         routes,
                     };
                 }
-                None => {
-                    bail!("Currently, only functions with a single entrypoint in _worker.js are supported");
-                }
+                None => bail!(NO_WORKER_JS),
             },
         }
 
@@ -193,13 +222,55 @@ impl RequestHandler for CloudflareRequestHandler {
         Ok(())
     }
 
+    fn find_user_code_problems(&self, code: &UserCode) -> Vec<anyhow::Error> {
+        let UserCode::Directory(path) = code else {
+            return vec![];
+        };
+
+        let mut problems = vec![];
+        match discover_worker_js(path) {
+            Ok(Some(_)) => (),
+            Ok(None) => problems.push(anyhow!(NO_WORKER_JS)),
+            Err(e) => problems.push(e),
+        }
+        if let Err(e) = Routes::try_parse(path) {
+            problems.push(e);
+        }
+        problems
+    }
+
+    fn check_request_handler(&self, cx: &Context) -> Result<()> {
+        let private = Self::get_private(cx)?;
+        let has_fetch_function = match private.mode {
+            SingleSourceFile => private
+                .modules
+                .get(&PathBuf::new())
+                .is_some_and(|m| m.fetch_function.is_some()),
+        };
+
+        // Without a fetch function, requests go to the fetch event listener
+        if !has_fetch_function
+            && !super::service_workers::event_listener::has_fetch_event_callback()
+        {
+            bail!(
+                "The worker neither exports a default object with a fetch function, \
+                nor registers a fetch event listener"
+            );
+        }
+        Ok(())
+    }
+
     fn start_handling_request(
         &mut self,
         cx: Context,
-        request: Request,
+        mut request: Request,
     ) -> Result<Either<PendingResponse, ReadyResponse>> {
         let private = Self::get_private(&cx)?;
 
+        super::set_client_address_header(&mut request, "cf-connecting-ip");
+        let cf = cf::RequestCf::new(&request.parts);
+        request.parts.extensions.insert(cf);
+
         if let Some(ref routes) = private.routes {
             if !routes.should_route_to_function(request.parts.uri.path()) {
                 return Ok(Either::Left(PendingResponse {
@@ -258,6 +329,9 @@ impl ByRefStandardModules for CloudflareStandardModules {
     }
 }
 
+const NO_WORKER_JS: &str =
+    "Currently, only functions with a single entrypoint in _worker.js are supported";
+
 const WORKER_JS_SEARCH_PATHS: &[&str] = &["_worker.js", "_worker/index.js", "_worker.js/index.js"];
 
 fn discover_worker_js(root: impl AsRef<Path>) -> Result<Option<PathBuf>> {
@@ -358,8 +432,7 @@ fn build_response(cx: &Context, result: Object) -> Result<Either<PendingResponse
             promise: unsafe { Promise::from_unchecked(result.into_local()) },
         }))
     } else if FetchResponse::instance_of(cx, &result) {
-        let response = FetchResponse::get_mut_private(cx, &result).unwrap();
-        super::build_response_from_fetch_response(cx, response).map(Either::Right)
+        super::build_response_from_fetch_response(cx, &result).map(Either::Right)
     } else {
         bail!("Script error: Unsupported object received, must be an instance of Response")
     }
@@ -55,6 +55,20 @@ impl UserCode {
     }
 }
 
+/// Where the user code was loaded from, so it can be loaded again when
+/// reloading.
+#[derive(Clone, Debug)]
+pub struct UserCodeSource {
+    pub path: PathBuf,
+    pub script_mode: bool,
+}
+
+impl UserCodeSource {
+    pub fn load(&self) -> anyhow::Result<UserCode> {
+        UserCode::from_path(&self.path, self.script_mode)
+    }
+}
+
 pub struct Request {
     pub parts: http::request::Parts,
     pub body: hyper::Body,
@@ -82,6 +96,16 @@ pub trait RequestHandler: Clone + Send + Sync + 'static {
     /// Evaluate the user script(s) to prepare for request execution.
     fn evaluate_scripts(&mut self, cx: &Context, code: &UserCode) -> Result<()>;
 
+    /// Find problems with the user code that can be found without
+    /// evaluating it, such as missing files. Used by `winterjs check`.
+    fn find_user_code_problems(&self, _code: &UserCode) -> Vec<anyhow::Error> {
+        vec![]
+    }
+
+    /// Make sure the evaluated user code registered a way to handle
+    /// requests. Used by `winterjs check`, after `evaluate_scripts`.
+    fn check_request_handler(&self, cx: &Context) -> Result<()>;
+
     /// Start handling the given request.
     fn start_handling_request(
         &mut self,
@@ -170,8 +194,16 @@ impl StandardModules for Box<dyn ByRefStandardModules> {
 
 fn build_request_uri(request: &Request) -> Result<Uri> {
     let host = get_host(&request.parts.uri, &request.parts.headers)?;
+    let scheme = match request
+        .parts
+        .extensions
+        .get::<crate::server::ConnectionInfo>()
+    {
+        Some(info) if info.tls => "https",
+        _ => "http",
+    };
     Uri::builder()
-        .scheme("http")
+        .scheme(scheme)
         .authority(host)
         .path_and_query(
             request
@@ -185,7 +217,32 @@ fn build_request_uri(request: &Request) -> Result<Uri> {
         .context("Failed to build request URI")
 }
 
-fn build_fetch_request(cx: &Context, request: Request) -> Result<*mut JSObject> {
+/// Sets the given header to the client address, replacing any value the
+/// client sent, so scripts can trust it.
+fn set_client_address_header(request: &mut Request, name: &'static str) {
+    let client_addr = request
+        .parts
+        .extensions
+        .get::<crate::server::ClientAddr>()
+        .copied();
+    let headers = &mut request.parts.headers;
+    headers.remove(name);
+    if let Some(crate::server::ClientAddr(addr)) = client_addr {
+        headers.insert(
+            name,
+            http::HeaderValue::from_str(&addr.to_string())
+                .expect("IP addresses are valid header values"),
+        );
+    }
+}
+
+fn build_fetch_request(cx: &Context, mut request: Request) -> Result<*mut JSObject> {
+    set_client_address_header(&mut request, "x-real-ip");
+    let cf = request
+        .parts
+        .extensions
+        .remove::<cloudflare::cf::RequestCf>();
+
     let uri = build_request_uri(&request)?;
     tracing::debug!(%uri, "Computed request URI");
 
@@ -226,8 +283,15 @@ fn build_fetch_request(cx: &Context, request: Request) -> Result<*mut JSObject>
 
     let request = FetchRequest::constructor(cx, request_info, Opt(Some(request_init)))
         .map_err(|e| anyhow!("Failed to construct request: {e:?}"))?;
+    let request = FetchRequest::new_object(cx, Box::new(request));
 
-    Ok(FetchRequest::new_object(cx, Box::new(request)))
+    if let Some(cf) = cf {
+        if !cf.define_on(cx, &cx.root(request).into()) {
+            bail!("Failed to define request.cf");
+        }
+    }
+
+    Ok(request)
 }
 
 pub fn get_host<'a>(uri: &'a http::Uri, headers: &'a http::HeaderMap) -> Result<&'a str> {
@@ -248,11 +312,21 @@ pub fn get_host<'a>(uri: &'a http::Uri, headers: &'a http::HeaderMap) -> Result<
     }
 }
 
-fn build_response_from_fetch_response(
-    cx: &Context,
-    response: &mut runtime::globals::fetch::Response,
-) -> Result<ReadyResponse> {
-    let mut hyper_response = hyper::Response::builder().status(response.get_status());
+/// Builds the response from a `Response` object, which must be an instance
+/// of the fetch `Response` class.
+fn build_response_from_fetch_response(cx: &Context, value: &Object) -> Result<ReadyResponse> {
+    // The server takes care of the rest of the upgrade
+    let web_socket = crate::builtins::websocket::take_response_peer(cx, value)?;
+
+    let response = runtime::globals::fetch::Response::get_mut_private(cx, value)
+        .context("Script error: expected an instance of Response")?;
+
+    let mut hyper_response = match web_socket {
+        Some(peer) => hyper::Response::builder()
+            .status(hyper::StatusCode::SWITCHING_PROTOCOLS)
+            .extension(peer),
+        None => hyper::Response::builder().status(response.get_status()),
+    };
 
     let headers =
         anyhow::Context::context(hyper_response.headers_mut(), "Response has no headers")?;
@@ -30,6 +30,11 @@ fn add_event_listener<'cx: 'f, 'f>(event: String, callback: Function<'f>) -> ion
     })
 }
 
+/// Whether the user code registered a `fetch` event listener.
+pub fn has_fetch_event_callback() -> bool {
+    EVENT_CALLBACK.with(|cb| cb.borrow().is_some())
+}
+
 pub fn invoke_fetch_event_callback<'cx>(
     cx: &'cx Context,
     args: &[Value],
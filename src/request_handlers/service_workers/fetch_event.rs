@@ -7,16 +7,23 @@ pub struct FetchEvent {
     reflector: Reflector,
     pub(crate) request: Heap<*mut JSObject>,
     pub(crate) response: Option<Heap<*mut JSObject>>,
+    client_address: Option<String>,
 }
 
 impl FetchEvent {
     pub fn try_new(cx: &Context, request: super::super::Request) -> anyhow::Result<Self> {
+        let client_address = request
+            .parts
+            .extensions
+            .get::<crate::server::ClientAddr>()
+            .map(|addr| addr.0.to_string());
         let request = Heap::new(super::super::build_fetch_request(cx, request)?);
 
         Ok(Self {
             reflector: Default::default(),
             request,
             response: None,
+            client_address,
         })
     }
 }
@@ -36,6 +43,14 @@ impl FetchEvent {
         self.request.get()
     }
 
+    /// The address of the client that sent the request, as a string. This
+    /// takes trusted proxies into account, and is undefined if the address
+    /// isn't known.
+    #[ion(get)]
+    pub fn get_client_address(&self) -> Option<String> {
+        self.client_address.clone()
+    }
+
     #[ion(name = "respondWith")]
     pub fn respond_with(&mut self, cx: &Context, response: ion::Value) -> ion::Result<()> {
         match self.response {
@@ -58,7 +58,5 @@ pub fn build_response(cx: &Context, value: Object) -> anyhow::Result<ReadyRespon
         bail!("Script error: value provided to respondWith must be an instance of Response");
     }
 
-    let response = runtime::globals::fetch::Response::get_mut_private(cx, &value).unwrap();
-
-    super::build_response_from_fetch_response(cx, response)
+    super::build_response_from_fetch_response(cx, &value)
 }
@@ -1,4 +1,4 @@
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Result};
 use ion::{Context, Object, Value};
 
 use crate::sm_utils;
@@ -7,6 +7,8 @@ use super::{
     ByRefStandardModules, Either, PendingResponse, ReadyResponse, Request, RequestHandler, UserCode,
 };
 
+const NO_DIRECTORIES: &str = "WinterCG mode does not support directories";
+
 #[derive(Clone, Copy)]
 pub struct WinterCGRequestHandler;
 
@@ -23,12 +25,29 @@ impl RequestHandler for WinterCGRequestHandler {
             UserCode::Module(path) => {
                 sm_utils::evaluate_module(cx, path)?;
             }
-            UserCode::Directory(_) => bail!("WinterCG mode does not support directories"),
+            UserCode::Directory(_) => bail!(NO_DIRECTORIES),
         };
 
         Ok(())
     }
 
+    fn find_user_code_problems(&self, code: &UserCode) -> Vec<anyhow::Error> {
+        match code {
+            UserCode::Directory(_) => vec![anyhow!(NO_DIRECTORIES)],
+            UserCode::Script { .. } | UserCode::Module(_) => vec![],
+        }
+    }
+
+    fn check_request_handler(&self, _cx: &Context) -> Result<()> {
+        if !super::service_workers::event_listener::has_fetch_event_callback() {
+            bail!(
+                "No fetch event listener was registered, call \
+                addEventListener('fetch', ...) to handle requests"
+            );
+        }
+        Ok(())
+    }
+
     fn start_handling_request(
         &mut self,
         cx: Context,
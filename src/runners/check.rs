@@ -0,0 +1,124 @@
+//! `winterjs check`: loads the user code like `serve` does, without binding
+//! any ports, and reports the problems that would stop it from serving.
+
+use anyhow::Result;
+use tokio::task::LocalSet;
+
+use crate::{
+    builtins,
+    request_handlers::{RequestHandler, UserCode, UserCodeSource},
+    sm_utils::{error_report_option_to_anyhow_error, JsApp, ScriptError, TwoStandardModules},
+    stencil_cache::CachingLoader,
+};
+
+/// Checks the user code, prints every problem found, and returns whether
+/// there were none.
+pub fn check(source: UserCodeSource, handler: Box<dyn RequestHandler>) -> Result<bool> {
+    let path = source.path.clone();
+
+    // Like `exec`, the Javascript code runs on a thread of its own with a
+    // single-threaded runtime.
+    let problems = std::thread::spawn(move || {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(async move {
+                let local_set = LocalSet::new();
+                local_set.run_until(find_problems(source, handler)).await
+            })
+    })
+    .join()
+    .unwrap();
+
+    for problem in &problems {
+        match problem_location(problem) {
+            Some(location) => eprintln!("error: {location}: {problem:#}\n"),
+            None => eprintln!("error: {problem:#}\n"),
+        }
+    }
+
+    match problems.len() {
+        0 => {
+            eprintln!("No problems found in {}", path.display());
+            Ok(true)
+        }
+        1 => {
+            eprintln!("Found 1 problem in {}", path.display());
+            Ok(false)
+        }
+        n => {
+            eprintln!("Found {n} problems in {}", path.display());
+            Ok(false)
+        }
+    }
+}
+
+async fn find_problems(
+    source: UserCodeSource,
+    mut handler: Box<dyn RequestHandler>,
+) -> Vec<anyhow::Error> {
+    let user_code = match source.load() {
+        Ok(user_code) => user_code,
+        Err(e) => return vec![e],
+    };
+
+    // The code can't be loaded without the files it needs, so there's no
+    // point in evaluating it before these are fixed
+    let problems = handler.find_user_code_problems(&user_code);
+    if !problems.is_empty() {
+        return problems;
+    }
+
+    let is_module_mode = match user_code {
+        UserCode::Script { .. } => false,
+        UserCode::Directory(_) | UserCode::Module(_) => true,
+    };
+
+    let module_loader = is_module_mode.then(CachingLoader::default);
+    let standard_modules = TwoStandardModules(
+        builtins::Modules {
+            include_internal: is_module_mode,
+            hardware_concurrency: 1,
+        },
+        handler.get_standard_modules(),
+    );
+
+    let js_app = JsApp::build(module_loader, Some(standard_modules));
+    let cx = js_app.cx();
+    let rt = js_app.rt();
+
+    let mut problems = vec![];
+
+    // Syntax errors and unresolved imports show up here, along with
+    // anything thrown while the code initializes. Whatever did get
+    // evaluated is still run and checked for a request handler.
+    if let Err(e) = handler.evaluate_scripts(cx, &user_code) {
+        problems.push(e);
+    }
+
+    // The event loop stops at the first error, so it's run again until all
+    // the pending work is done
+    while let Err(e) = rt.run_event_loop().await {
+        let unknown = e.is_none();
+        problems.push(error_report_option_to_anyhow_error(cx, e));
+        if unknown {
+            break;
+        }
+    }
+
+    if let Err(e) = handler.check_request_handler(cx) {
+        problems.push(e);
+    }
+
+    problems
+}
+
+/// The `file:line:column` a problem was thrown from, if it came from the
+/// Javascript code.
+fn problem_location(problem: &anyhow::Error) -> Option<&str> {
+    problem
+        .chain()
+        .find_map(|e| e.downcast_ref::<ScriptError>())
+        .and_then(ScriptError::location)
+}
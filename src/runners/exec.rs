@@ -1,15 +1,21 @@
 use std::path::{Path, PathBuf};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
+use ion::Object;
 use tokio::task::LocalSet;
 
 use crate::{
     builtins,
     sm_utils::{error_report_option_to_anyhow_error, evaluate_module, evaluate_script, JsApp},
+    stencil_cache::CachingLoader,
 };
 
-async fn exec_script_inner(path: impl AsRef<Path>, script_mode: bool) -> Result<()> {
-    let module_loader = (!script_mode).then(runtime::module::Loader::default);
+async fn exec_script_inner(
+    path: impl AsRef<Path>,
+    script_mode: bool,
+    argv: Vec<String>,
+) -> Result<i32> {
+    let module_loader = (!script_mode).then(CachingLoader::default);
     let standard_modules = builtins::Modules {
         include_internal: !script_mode,
         hardware_concurrency: 1,
@@ -19,6 +25,10 @@ async fn exec_script_inner(path: impl AsRef<Path>, script_mode: bool) -> Result<
     let cx = js_app.cx();
     let rt = js_app.rt();
 
+    if !builtins::process::define_exec_extras(cx, &Object::global(cx), argv) {
+        bail!("Failed to define the process object");
+    }
+
     if script_mode {
         let code = std::fs::read_to_string(&path).context("Failed to read script file")?;
         evaluate_script(cx, code, path.as_ref().as_os_str())?;
@@ -30,10 +40,18 @@ async fn exec_script_inner(path: impl AsRef<Path>, script_mode: bool) -> Result<
         .await
         .map_err(|e| error_report_option_to_anyhow_error(cx, e))?;
 
-    Ok(())
+    Ok(builtins::process::exit_code(cx))
 }
 
-pub fn exec_script(path: PathBuf, script_mode: bool) -> Result<()> {
+/// Runs the script at `path` with `args` in `process.argv`, and returns the
+/// exit code it set through `process.exitCode`. Errors thrown by the script
+/// and unhandled errors from the event loop are returned as errors.
+pub fn exec_script(path: PathBuf, script_mode: bool, args: Vec<String>) -> Result<i32> {
+    let script_path = std::fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+    let argv = std::iter::once(script_path.to_string_lossy().into_owned())
+        .chain(args)
+        .collect();
+
     // The top-level tokio runtime is *not* single-threaded, so we
     // need to spawn a new thread with a new single-threaded runtime
     // to run the JS code.
@@ -45,7 +63,7 @@ pub fn exec_script(path: PathBuf, script_mode: bool) -> Result<()> {
             .block_on(async move {
                 let local_set = LocalSet::new();
                 local_set
-                    .run_until(exec_script_inner(path, script_mode))
+                    .run_until(exec_script_inner(path, script_mode, argv))
                     .await
             })
     })
@@ -19,6 +19,7 @@ use crate::request_handlers::{RequestHandler, UserCode};
 
 use super::{
     request_loop::{handle_requests, ControlMessage, RequestData},
+    watchdog::{self, ExecutionLimits},
     ResponseData,
 };
 
@@ -26,6 +27,7 @@ use super::{
 pub struct InlineRunner {
     channel: mpsc::UnboundedSender<ControlMessage>,
     finished: Arc<AtomicBool>,
+    wall_time: Option<Duration>,
 }
 
 pub trait InlineRunnerRequestHandlerFuture: Future<Output = ()> {}
@@ -36,21 +38,30 @@ impl InlineRunner {
     pub fn new_request_handler(
         handler: impl RequestHandler + Copy + Unpin,
         user_code: UserCode,
+        limits: ExecutionLimits,
     ) -> (Self, impl InlineRunnerRequestHandlerFuture) {
         let (tx, rx) = mpsc::unbounded_channel();
         let this = Self {
             channel: tx,
             finished: Arc::new(AtomicBool::new(false)),
+            wall_time: limits.wall_time,
         };
         let finished_clone = this.finished.clone();
         let fut = async move {
-            handle_requests(handler, user_code, rx, 1).await;
+            handle_requests(handler, user_code, rx, 1, limits, None).await;
             // Remember, we're running single-threaded, so no need
             // for any specific ordering logic.
             finished_clone.store(true, Ordering::Relaxed);
         };
         (this, fut)
     }
+
+    /// Lets the request handler future finish once the requests it's
+    /// handling are done and its event loop is empty. Unlike `shutdown`,
+    /// this doesn't wait for it.
+    pub fn finish(&self) {
+        _ = self.channel.send(ControlMessage::Shutdown);
+    }
 }
 
 #[async_trait]
@@ -68,11 +79,25 @@ impl crate::server::Runner for InlineRunner {
             tx,
         ))?;
 
-        let response = rx.await?;
+        let response = match self.wall_time {
+            Some(limit) => match tokio::time::timeout(limit, rx).await {
+                Ok(response) => response?,
+                Err(_) => {
+                    tracing::warn!("Request did not complete within {limit:?}");
+                    ResponseData::Done(watchdog::timeout_response())
+                }
+            },
+            None => rx.await?,
+        };
 
         // TODO: handle script errors
         match response {
-            ResponseData::Done(resp) => Ok(resp),
+            ResponseData::Done(mut resp) => {
+                // Everything runs on the same thread
+                resp.extensions_mut()
+                    .insert(crate::server::WorkerThreadId(0));
+                Ok(resp)
+            }
             ResponseData::RequestError(err) => Err(err),
             ResponseData::ScriptError(err) => {
                 if let Some(err) = err {
@@ -83,8 +108,14 @@ impl crate::server::Runner for InlineRunner {
         }
     }
 
+    async fn reload(&self) -> Result<(), anyhow::Error> {
+        // The single thread can't run two versions of the code side by side
+        anyhow::bail!("Reloading is not supported in single-threaded mode")
+    }
+
     async fn shutdown(&self, timeout: Option<Duration>) {
         tracing::info!("Shutting down...");
+        crate::server::health::set_shutting_down();
 
         if self.channel.send(ControlMessage::Shutdown).is_err() {
             // Channel already closed, future must have run to completion
@@ -0,0 +1,351 @@
+//! `winterjs invoke`: sends a single request through the user code without
+//! starting a server, and prints the response. The request goes through an
+//! [`InlineRunner`], like requests in single-threaded mode do.
+
+use std::{
+    io::{Read, Write},
+    net::{Ipv4Addr, SocketAddr},
+    path::PathBuf,
+};
+
+use anyhow::{bail, Context as _, Result};
+use base64::Engine;
+use bytes::Bytes;
+use http::{header, HeaderName, HeaderValue, Method, Uri};
+use tokio::{join, task::LocalSet};
+
+use crate::{
+    request_handlers::{RequestHandler, UserCode},
+    server::{ClientAddr, ConnectionInfo, Runner},
+};
+
+use super::{inline::InlineRunner, watchdog::ExecutionLimits};
+
+/// Invoked requests look like they came from the local machine.
+const CLIENT_ADDR: SocketAddr = SocketAddr::new(std::net::IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+
+pub struct InvokeRequest {
+    pub method: Method,
+    /// An absolute URL, or a path which is taken to be on `localhost`.
+    pub url: String,
+    /// Headers in `Name: value` form.
+    pub headers: Vec<String>,
+    /// A file to read the body from, or `-` for stdin.
+    pub body: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// The status line and headers, then the body, like `curl -i`.
+    Text,
+    /// A JSON object with the status, headers and body.
+    Json,
+}
+
+pub fn invoke(
+    handler: impl RequestHandler + Copy + Unpin,
+    user_code: UserCode,
+    request: InvokeRequest,
+    format: OutputFormat,
+) -> Result<()> {
+    let (parts, body) = build_request(request)?;
+
+    let (response, body) = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("Failed building the Runtime")
+        .block_on(async move {
+            let local_set = LocalSet::new();
+            local_set
+                .run_until(async move {
+                    let (runner, runner_future) = InlineRunner::new_request_handler(
+                        handler,
+                        user_code,
+                        ExecutionLimits::default(),
+                    );
+
+                    // The body may be streamed from Javascript code, so it
+                    // has to be read while the request handler is running
+                    let response_future = async {
+                        let response = send_request(&runner, parts, body).await;
+                        runner.finish();
+                        response
+                    };
+                    let (response, ()) = join!(response_future, runner_future);
+                    response
+                })
+                .await
+        })?;
+
+    let mut stdout = std::io::stdout().lock();
+    match format {
+        OutputFormat::Text => write_text(&mut stdout, &response, &body)?,
+        OutputFormat::Json => write_json(&mut stdout, &response, &body)?,
+    }
+    stdout.flush()?;
+
+    Ok(())
+}
+
+fn build_request(request: InvokeRequest) -> Result<(http::request::Parts, hyper::Body)> {
+    let url = if request.url.starts_with('/') {
+        format!("http://localhost{}", request.url)
+    } else {
+        request.url
+    };
+    let uri: Uri = url.parse().with_context(|| format!("Invalid URL: {url}"))?;
+    if uri.scheme().is_none() || uri.authority().is_none() {
+        bail!("The URL must be absolute, or a path starting with /");
+    }
+
+    let mut builder = http::Request::builder()
+        .method(request.method)
+        .uri(uri.clone());
+
+    for h in &request.headers {
+        let (name, value) = h
+            .split_once(':')
+            .with_context(|| format!("Invalid header, expected 'Name: value': {h}"))?;
+        let name = HeaderName::try_from(name.trim())
+            .with_context(|| format!("Invalid header name: {name}"))?;
+        let value = HeaderValue::try_from(value.trim())
+            .with_context(|| format!("Invalid value for header {name}"))?;
+        builder = builder.header(name, value);
+    }
+
+    let body =
+        match request.body {
+            Some(path) if path.as_os_str() == "-" => {
+                let mut body = vec![];
+                std::io::stdin()
+                    .read_to_end(&mut body)
+                    .context("Failed to read the request body from stdin")?;
+                Some(body)
+            }
+            Some(path) => Some(std::fs::read(&path).with_context(|| {
+                format!("Failed to read the request body from {}", path.display())
+            })?),
+            None => None,
+        };
+
+    let mut request = builder.body(()).context("Failed to build the request")?;
+
+    // Add what a client would have sent, unless it's been given explicitly
+    let headers = request.headers_mut();
+    if !headers.contains_key(header::HOST) {
+        let host = uri.authority().unwrap().as_str();
+        headers.insert(
+            header::HOST,
+            HeaderValue::try_from(host).context("Invalid host in URL")?,
+        );
+    }
+    if let Some(body) = &body {
+        if !headers.contains_key(header::CONTENT_LENGTH) {
+            headers.insert(header::CONTENT_LENGTH, HeaderValue::from(body.len()));
+        }
+    }
+
+    let extensions = request.extensions_mut();
+    extensions.insert(ConnectionInfo {
+        tls: uri.scheme_str() == Some("https"),
+    });
+    extensions.insert(ClientAddr(CLIENT_ADDR.ip()));
+
+    let (parts, ()) = request.into_parts();
+    let body = body
+        .map(hyper::Body::from)
+        .unwrap_or_else(hyper::Body::empty);
+    Ok((parts, body))
+}
+
+async fn send_request(
+    runner: &InlineRunner,
+    parts: http::request::Parts,
+    body: hyper::Body,
+) -> Result<(http::response::Parts, Bytes)> {
+    let response = runner.handle(CLIENT_ADDR, parts, body).await?;
+    let (parts, body) = response.into_parts();
+    let body = hyper::body::to_bytes(body)
+        .await
+        .context("Failed to read the response body")?;
+    Ok((parts, body))
+}
+
+fn write_text(out: &mut impl Write, response: &http::response::Parts, body: &[u8]) -> Result<()> {
+    writeln!(out, "{:?} {}", response.version, response.status)?;
+    for (name, value) in &response.headers {
+        writeln!(out, "{name}: {}", String::from_utf8_lossy(value.as_bytes()))?;
+    }
+    writeln!(out)?;
+    out.write_all(body)?;
+    Ok(())
+}
+
+fn write_json(out: &mut impl Write, response: &http::response::Parts, body: &[u8]) -> Result<()> {
+    let headers = response
+        .headers
+        .iter()
+        .map(|(name, value)| {
+            serde_json::json!([name.as_str(), String::from_utf8_lossy(value.as_bytes())])
+        })
+        .collect::<Vec<_>>();
+
+    // Bodies that aren't text can't go into a JSON string as they are
+    let (body, body_encoding) = match std::str::from_utf8(body) {
+        Ok(body) => (body.to_string(), "utf8"),
+        Err(_) => (base64::prelude::BASE64_STANDARD.encode(body), "base64"),
+    };
+
+    let output = serde_json::json!({
+        "status": response.status.as_u16(),
+        "statusText": response.status.canonical_reason().unwrap_or_default(),
+        "headers": headers,
+        "body": body,
+        "bodyEncoding": body_encoding,
+    });
+    serde_json::to_writer_pretty(&mut *out, &output)?;
+    writeln!(out)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(url: &str, headers: &[&str]) -> InvokeRequest {
+        InvokeRequest {
+            method: Method::GET,
+            url: url.to_string(),
+            headers: headers.iter().map(|h| h.to_string()).collect(),
+            body: None,
+        }
+    }
+
+    fn response(headers: &[(&'static str, &'static str)]) -> http::response::Parts {
+        let mut builder = http::Response::builder().status(404);
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(()).unwrap().into_parts().0
+    }
+
+    fn json(response: &http::response::Parts, body: &[u8]) -> serde_json::Value {
+        let mut out = vec![];
+        write_json(&mut out, response, body).unwrap();
+        serde_json::from_slice(&out).unwrap()
+    }
+
+    #[test]
+    fn paths_default_to_localhost() {
+        let (parts, _) = build_request(request("/hello?name=world", &[])).unwrap();
+        assert_eq!(parts.uri, "http://localhost/hello?name=world");
+        assert_eq!(parts.headers[header::HOST], "localhost");
+        assert!(!parts.extensions.get::<ConnectionInfo>().unwrap().tls);
+        assert_eq!(
+            parts.extensions.get::<ClientAddr>().unwrap().0,
+            CLIENT_ADDR.ip()
+        );
+
+        let (parts, _) = build_request(request("https://example.com:8443/", &[])).unwrap();
+        assert_eq!(parts.headers[header::HOST], "example.com:8443");
+        assert!(parts.extensions.get::<ConnectionInfo>().unwrap().tls);
+
+        assert!(build_request(request("hello", &[])).is_err());
+        assert!(build_request(request("example.com/hello", &[])).is_err());
+    }
+
+    #[test]
+    fn parses_headers() {
+        let (parts, _) = build_request(request(
+            "/",
+            &[
+                "Accept: text/html",
+                "x-empty:",
+                "X-Url:  http://example.com ",
+                "Host: example.com",
+            ],
+        ))
+        .unwrap();
+        assert_eq!(parts.headers["accept"], "text/html");
+        assert_eq!(parts.headers["x-empty"], "");
+        assert_eq!(parts.headers["x-url"], "http://example.com");
+        assert_eq!(parts.headers[header::HOST], "example.com");
+        assert_eq!(parts.headers.get_all(header::HOST).iter().count(), 1);
+
+        assert!(build_request(request("/", &["Accept text/html"])).is_err());
+        assert!(build_request(request("/", &["Bad Name: value"])).is_err());
+        assert!(build_request(request("/", &[": value"])).is_err());
+        assert!(build_request(request("/", &["X-Test: line\nbreak"])).is_err());
+    }
+
+    #[tokio::test]
+    async fn adds_content_length_for_bodies() {
+        let path = std::env::temp_dir().join(format!("winterjs-invoke-{}", std::process::id()));
+        std::fs::write(&path, "hello").unwrap();
+
+        let (parts, body) = build_request(InvokeRequest {
+            method: Method::POST,
+            body: Some(path.clone()),
+            ..request("/", &[])
+        })
+        .unwrap();
+        assert_eq!(parts.method, Method::POST);
+        assert_eq!(parts.headers[header::CONTENT_LENGTH], "5");
+        assert_eq!(hyper::body::to_bytes(body).await.unwrap(), "hello");
+
+        let (parts, _) = build_request(InvokeRequest {
+            method: Method::POST,
+            body: Some(path.clone()),
+            ..request("/", &["Content-Length: 3"])
+        })
+        .unwrap();
+        assert_eq!(parts.headers[header::CONTENT_LENGTH], "3");
+
+        let (parts, _) = build_request(request("/", &[])).unwrap();
+        assert!(!parts.headers.contains_key(header::CONTENT_LENGTH));
+
+        _ = std::fs::remove_file(&path);
+        assert!(build_request(InvokeRequest {
+            body: Some(path),
+            ..request("/", &[])
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn writes_text() {
+        let mut out = vec![];
+        write_text(
+            &mut out,
+            &response(&[("content-type", "text/plain")]),
+            b"hello",
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "HTTP/1.1 404 Not Found\ncontent-type: text/plain\n\nhello"
+        );
+    }
+
+    #[test]
+    fn writes_json() {
+        let response = response(&[("set-cookie", "a=1"), ("set-cookie", "b=2")]);
+        assert_eq!(
+            json(&response, b"hello"),
+            serde_json::json!({
+                "status": 404,
+                "statusText": "Not Found",
+                "headers": [["set-cookie", "a=1"], ["set-cookie", "b=2"]],
+                "body": "hello",
+                "bodyEncoding": "utf8",
+            })
+        );
+    }
+
+    #[test]
+    fn writes_binary_bodies_as_base64() {
+        let output = json(&response(&[]), &[0xff, 0x00, 0xfe]);
+        assert_eq!(output["body"], "/wD+");
+        assert_eq!(output["bodyEncoding"], "base64");
+    }
+}
@@ -1,10 +1,16 @@
+pub mod check;
 mod event_loop_stream;
 pub mod exec;
 pub mod inline;
+pub mod invoke;
+#[cfg(not(target_os = "wasi"))]
+pub mod repl;
 mod request_loop;
 mod request_queue;
 pub mod single;
+pub mod test;
 pub mod watch;
+pub mod watchdog;
 
 #[derive(Debug)]
 pub enum ResponseData {
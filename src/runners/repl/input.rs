@@ -0,0 +1,247 @@
+//! Just enough tokenizing of REPL input to tell whether it continues on the
+//! next line, and to find what top-level `await` support needs to rewrite.
+//! Regular expression literals aren't recognized, so brackets in them can
+//! make the REPL ask for more input.
+
+use std::ops::Range;
+
+#[derive(Default)]
+pub struct Scan {
+    /// The input stops inside brackets, a string, a template literal or a
+    /// comment.
+    pub incomplete: bool,
+    /// The input uses `await` somewhere.
+    pub awaits: bool,
+    /// The `let`, `const` and `var` keywords of top-level declarations of a
+    /// single name, such as `const a = 1` but not `const { a } = b`.
+    pub declarations: Vec<Range<usize>>,
+}
+
+fn is_identifier_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_' || b == b'$' || b >= 0x80
+}
+
+pub fn scan(code: &str) -> Scan {
+    let bytes = code.as_bytes();
+    let mut scan = Scan::default();
+    let mut depth = 0usize;
+    // The bracket depth at which each enclosing `${` of a template literal
+    // was opened
+    let mut substitutions: Vec<usize> = vec![];
+    // A declaration keyword just seen, which counts if a name follows
+    let mut keyword: Option<Range<usize>> = None;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let b = bytes[i];
+
+        if b.is_ascii_whitespace() {
+            i += 1;
+            continue;
+        }
+
+        if b == b'/' && bytes.get(i + 1) == Some(&b'/') {
+            i = code[i..].find('\n').map_or(bytes.len(), |end| i + end);
+            continue;
+        }
+        if b == b'/' && bytes.get(i + 1) == Some(&b'*') {
+            match code[i + 2..].find("*/") {
+                Some(end) => i += end + 4,
+                None => {
+                    scan.incomplete = true;
+                    return scan;
+                }
+            }
+            continue;
+        }
+
+        let declaration = keyword.take();
+
+        match b {
+            b'\'' | b'"' => match skip_string(bytes, i) {
+                Some(end) => i = end,
+                None => {
+                    scan.incomplete = true;
+                    return scan;
+                }
+            },
+
+            b'`' => match skip_template(bytes, i + 1) {
+                Template::End(end) => i = end,
+                Template::Substitution(start) => {
+                    substitutions.push(depth);
+                    i = start;
+                }
+                Template::Unterminated => {
+                    scan.incomplete = true;
+                    return scan;
+                }
+            },
+
+            b'}' if substitutions.last() == Some(&depth) => {
+                substitutions.pop();
+                match skip_template(bytes, i + 1) {
+                    Template::End(end) => i = end,
+                    Template::Substitution(start) => {
+                        substitutions.push(depth);
+                        i = start;
+                    }
+                    Template::Unterminated => {
+                        scan.incomplete = true;
+                        return scan;
+                    }
+                }
+            }
+
+            b'(' | b'[' | b'{' => {
+                depth += 1;
+                i += 1;
+            }
+
+            b')' | b']' | b'}' => {
+                // Unbalanced input is left for the parser to complain about
+                depth = depth.saturating_sub(1);
+                i += 1;
+            }
+
+            b if is_identifier_byte(b) => {
+                let start = i;
+                while i < bytes.len() && is_identifier_byte(bytes[i]) {
+                    i += 1;
+                }
+                let word = &code[start..i];
+
+                if let Some(declaration) = declaration {
+                    if !word.as_bytes()[0].is_ascii_digit() {
+                        scan.declarations.push(declaration);
+                    }
+                } else if word == "await" {
+                    scan.awaits = true;
+                } else if depth == 0
+                    && substitutions.is_empty()
+                    && matches!(word, "let" | "const" | "var")
+                    && code[..start].trim_end().as_bytes().last() != Some(&b'.')
+                {
+                    keyword = Some(start..i);
+                }
+            }
+
+            _ => i += 1,
+        }
+    }
+
+    scan.incomplete = depth > 0 || !substitutions.is_empty();
+    scan
+}
+
+/// Returns the position after the closing quote of the string starting at
+/// `start`, or `None` if the input ends first.
+fn skip_string(bytes: &[u8], start: usize) -> Option<usize> {
+    let quote = bytes[start];
+    let mut i = start + 1;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 2,
+            // A syntax error, but not one more input can fix
+            b'\n' => return Some(i),
+            b if b == quote => return Some(i + 1),
+            _ => i += 1,
+        }
+    }
+    None
+}
+
+enum Template {
+    /// The position after the closing backtick.
+    End(usize),
+    /// The position after the `${` of a substitution.
+    Substitution(usize),
+    Unterminated,
+}
+
+fn skip_template(bytes: &[u8], mut i: usize) -> Template {
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 2,
+            b'`' => return Template::End(i + 1),
+            b'$' if bytes.get(i + 1) == Some(&b'{') => return Template::Substitution(i + 2),
+            _ => i += 1,
+        }
+    }
+    Template::Unterminated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn incomplete(code: &str) -> bool {
+        scan(code).incomplete
+    }
+
+    #[test]
+    fn complete_input() {
+        assert!(!incomplete("1 + 1"));
+        assert!(!incomplete("function f() { return [1, 2]; }"));
+        assert!(!incomplete("'a string with ( and {'"));
+        assert!(!incomplete("// a comment with {"));
+        assert!(!incomplete("/* a ( comment */ f()"));
+        assert!(!incomplete("`a ${ { b: 1 }.b } template`"));
+        assert!(!incomplete("`nested ${`template ${1}`}`"));
+        assert!(!incomplete("\"escaped \\\" quote\""));
+    }
+
+    #[test]
+    fn input_continues_on_the_next_line() {
+        assert!(incomplete("function f() {"));
+        assert!(incomplete("f(1,"));
+        assert!(incomplete("[1, [2"));
+        assert!(incomplete("'unterminated"));
+        assert!(incomplete("/* unterminated"));
+        assert!(incomplete("`unterminated"));
+        assert!(incomplete("`a ${b"));
+        assert!(incomplete("`a ${b} c"));
+        assert!(incomplete("`a ${ { b: 1 }"));
+    }
+
+    #[test]
+    fn unbalanced_input_is_left_to_the_parser() {
+        assert!(!incomplete("f())"));
+        assert!(!incomplete("}"));
+        // A newline ends a string, which more input can't fix
+        assert!(!incomplete("'a\n"));
+    }
+
+    #[test]
+    fn finds_await() {
+        assert!(scan("await f()").awaits);
+        assert!(scan("const a = [await b]").awaits);
+        assert!(!scan("awaited()").awaits);
+        assert!(!scan("'await'").awaits);
+        assert!(!scan("// await").awaits);
+    }
+
+    #[test]
+    fn finds_top_level_declarations() {
+        let code = "const a = 1; let b = 2; var c";
+        let declarations = scan(code).declarations;
+        let keywords: Vec<_> = declarations.iter().map(|d| &code[d.clone()]).collect();
+        assert_eq!(keywords, ["const", "let", "var"]);
+        assert_eq!(declarations[0], 0..5);
+    }
+
+    #[test]
+    fn ignores_other_declarations() {
+        assert!(scan("const { a } = b").declarations.is_empty());
+        assert!(scan("let [a] = b").declarations.is_empty());
+        assert!(scan("for (let i = 0; i < 1; i++) {}")
+            .declarations
+            .is_empty());
+        assert!(scan("function f() { const a = 1 }").declarations.is_empty());
+        assert!(scan("`${(() => { let a = 1 })()}`").declarations.is_empty());
+        assert!(scan("obj.let = 1").declarations.is_empty());
+        assert!(scan("obj. const").declarations.is_empty());
+        assert!(scan("let 1").declarations.is_empty());
+        assert!(scan("'let a'").declarations.is_empty());
+    }
+}
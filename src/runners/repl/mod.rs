@@ -0,0 +1,259 @@
+//! `winterjs repl`: an interactive shell with the same builtins as `exec`.
+//! Input is evaluated as a script. When it uses top-level `await`, it is
+//! wrapped in an async function instead, and the event loop runs until the
+//! result is ready.
+
+use std::{
+    ffi::OsStr,
+    path::{Path, PathBuf},
+    task::{self, Poll},
+};
+
+use anyhow::{anyhow, Context as _, Result};
+use ion::{conversions::FromValue, script::Script};
+use rustyline::{error::ReadlineError, DefaultEditor};
+use tokio::task::LocalSet;
+
+use crate::{
+    builtins,
+    sm_utils::{
+        error_report_option_to_anyhow_error, error_report_to_anyhow_error, evaluate_script, JsApp,
+    },
+    stencil_cache::CachingLoader,
+};
+
+mod input;
+
+const REPL_FILE: &str = "repl";
+
+const HISTORY_FILE: &str = ".winterjs_repl_history";
+
+/// Keeps track of the promise returned by input with top-level `await`.
+const TRACKER: &str = r#"
+Object.defineProperty(globalThis, "__winterjs_repl", {
+  value: {
+    settled: true,
+    failed: false,
+    value: undefined,
+    track(promise) {
+      this.settled = false;
+      this.failed = false;
+      this.value = undefined;
+      promise.then(
+        (value) => {
+          this.settled = true;
+          this.value = value;
+        },
+        (error) => {
+          this.settled = true;
+          this.failed = true;
+          this.value = error;
+        }
+      );
+    },
+    result() {
+      const value = this.value;
+      this.value = undefined;
+      if (this.failed) throw value;
+      return value;
+    },
+  },
+});
+"#;
+
+const HELP: &str = "\
+.exit    Exit the REPL
+.help    Print this help message
+
+Press Ctrl+C to abort the current input, and Ctrl+D to exit.
+Input ending inside brackets, a string or a comment continues on the next line.";
+
+pub fn run_repl(history: Option<PathBuf>) -> Result<()> {
+    // Like `exec`, the Javascript code runs on a thread of its own with a
+    // single-threaded runtime.
+    std::thread::spawn(move || {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(async move {
+                let local_set = LocalSet::new();
+                local_set.run_until(run_repl_inner(history)).await
+            })
+    })
+    .join()
+    .unwrap()
+}
+
+async fn run_repl_inner(history: Option<PathBuf>) -> Result<()> {
+    let module_loader = CachingLoader::default();
+    let standard_modules = builtins::Modules {
+        include_internal: true,
+        hardware_concurrency: 1,
+    };
+
+    let js_app = JsApp::build(Some(module_loader), Some(standard_modules));
+    evaluate_script(js_app.cx(), TRACKER, OsStr::new("winterjs:repl"))?;
+
+    let mut editor = DefaultEditor::new().context("Failed to initialize the line editor")?;
+    let history = history
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(HISTORY_FILE)));
+    if let Some(path) = &history {
+        // There's no history the first time around
+        _ = editor.load_history(path);
+    }
+
+    println!(
+        "Welcome to WinterJS v{}. Type \".help\" for more information.",
+        env!("CARGO_PKG_VERSION")
+    );
+
+    let mut code = String::new();
+    loop {
+        let prompt = if code.is_empty() { "> " } else { "... " };
+        let line = match editor.readline(prompt) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => {
+                if code.is_empty() {
+                    println!("(To exit, press Ctrl+D or type .exit)");
+                }
+                code.clear();
+                continue;
+            }
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e).context("Failed to read input"),
+        };
+
+        if code.is_empty() {
+            match line.trim() {
+                "" => continue,
+                ".exit" => break,
+                ".help" => {
+                    println!("{HELP}");
+                    continue;
+                }
+                _ => (),
+            }
+        } else {
+            code.push('\n');
+        }
+        code.push_str(&line);
+
+        if input::scan(&code).incomplete {
+            continue;
+        }
+
+        let code = std::mem::take(&mut code);
+        _ = editor.add_history_entry(code.as_str());
+        match evaluate(&js_app, &code).await {
+            Ok(output) => println!("{output}"),
+            Err(e) => eprintln!("{e:#}"),
+        }
+    }
+
+    if let Some(path) = &history {
+        if let Err(e) = editor.save_history(path) {
+            tracing::warn!(error = %e, path = %path.display(), "Failed to save REPL history");
+        }
+    }
+
+    Ok(())
+}
+
+/// Evaluates one complete input, and returns its value formatted for
+/// printing.
+async fn evaluate(js_app: &JsApp, code: &str) -> Result<String> {
+    let cx = js_app.cx();
+
+    let value = match Script::compile(cx, Path::new(REPL_FILE), code) {
+        Ok(script) => {
+            let value = script
+                .evaluate(cx)
+                .map_err(|e| error_report_to_anyhow_error(cx, e))?;
+
+            // Runs the jobs of promises that are already settled, but
+            // doesn't wait for anything else
+            js_app
+                .rt()
+                .step_event_loop(&mut task::Context::from_waker(
+                    futures::task::noop_waker_ref(),
+                ))
+                .map_err(|e| error_report_option_to_anyhow_error(cx, e))?;
+
+            value
+        }
+
+        Err(e) => {
+            // `await` is only a syntax error outside async functions
+            let scan = input::scan(code);
+            if !scan.awaits {
+                return Err(error_report_to_anyhow_error(cx, e));
+            }
+
+            let wrapped = wrap_in_async_function(js_app, code, &scan);
+            evaluate_script(
+                cx,
+                format!("__winterjs_repl.track({wrapped})"),
+                OsStr::new(REPL_FILE),
+            )?;
+            wait_until_settled(js_app).await?;
+            evaluate_script(cx, "__winterjs_repl.result()", OsStr::new(REPL_FILE))?
+        }
+    };
+
+    Ok(
+        ion::format::format_value(cx, ion::format::Config::default().quoted(true), &value)
+            .to_string(),
+    )
+}
+
+/// Turns input with top-level `await` into a call to an async function. An
+/// expression becomes the function's return value. For anything else, the
+/// names declared at the top level are turned into globals, so later input
+/// can still use them.
+fn wrap_in_async_function(js_app: &JsApp, code: &str, scan: &input::Scan) -> String {
+    let expression = code.trim_end().trim_end_matches(';');
+    let wrapped = format!("(async () => (\n{expression}\n))()");
+    if Script::compile(js_app.cx(), Path::new(REPL_FILE), &wrapped).is_ok() {
+        return wrapped;
+    }
+
+    // `const a = 1` becomes `a = 1`, which assigns to a global outside
+    // strict mode
+    let mut body = String::with_capacity(code.len());
+    let mut copied = 0;
+    for keyword in &scan.declarations {
+        body.push_str(&code[copied..keyword.start]);
+        copied = keyword.end;
+    }
+    body.push_str(&code[copied..]);
+
+    format!("(async () => {{\n{body}\n}})()")
+}
+
+/// Runs the event loop until the promise of the last input settles.
+async fn wait_until_settled(js_app: &JsApp) -> Result<()> {
+    let cx = js_app.cx();
+    let rt = js_app.rt();
+
+    std::future::poll_fn(|wcx| {
+        if let Err(e) = rt.step_event_loop(wcx) {
+            return Poll::Ready(Err(error_report_option_to_anyhow_error(cx, e)));
+        }
+
+        let settled = evaluate_script(cx, "__winterjs_repl.settled", OsStr::new(REPL_FILE))
+            .and_then(|settled| {
+                bool::from_value(cx, &settled, true, ())
+                    .map_err(|e| anyhow!("Failed to read the REPL state: {e:?}"))
+            });
+        match settled {
+            Ok(true) => Poll::Ready(Ok(())),
+            Ok(false) if rt.event_loop_is_empty() => Poll::Ready(Err(anyhow!(
+                "The promise can never settle, nothing it could be waiting on is left"
+            ))),
+            Ok(false) => Poll::Pending,
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    })
+    .await
+}
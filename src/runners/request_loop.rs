@@ -9,11 +9,14 @@ use crate::{
     request_handlers::{Either, Request, RequestHandler, UserCode},
     runners::ResponseData,
     sm_utils::{error_report_option_to_anyhow_error, JsApp, TwoStandardModules},
+    stencil_cache::CachingLoader,
 };
 
 use super::{
     event_loop_stream::EventLoopStream,
     request_queue::{RequestFinishedHandler, RequestFinishedResult, RequestQueue},
+    watch::{self, TrackingLoader},
+    watchdog::{self, ExecutionLimits, Watchdog},
 };
 
 pub struct RequestData {
@@ -48,15 +51,31 @@ pub(super) async fn handle_requests<H: RequestHandler + Copy + Unpin>(
     user_code: UserCode,
     mut recv: tokio::sync::mpsc::UnboundedReceiver<ControlMessage>,
     max_request_threads: u32,
+    limits: ExecutionLimits,
+    mut initialized: Option<oneshot::Sender<anyhow::Result<()>>>,
 ) {
-    if let Err(e) = handle_requests_inner(handler, user_code, &mut recv, max_request_threads).await
+    if let Err(e) = watchdog::watch(handle_requests_inner(
+        handler,
+        user_code,
+        &mut recv,
+        max_request_threads,
+        limits,
+        &mut initialized,
+    ))
+    .await
     {
         // The request handling logic itself failed, so we send back the error
         // as long as the thread is alive and shutdown has not been requested.
         // This lets us report the error. The runner can shut us down as soon
         // as it discovers the error.
+        crate::server::metrics::record_script_init_failure();
 
-        let mut error = Some(e);
+        // If someone is waiting to hear whether the code initialized, they
+        // get the error instead
+        let mut error = match initialized.take() {
+            Some(tx) => tx.send(Err(e)).err().and_then(|result| result.err()),
+            None => Some(e),
+        };
 
         loop {
             match recv.recv().await {
@@ -74,25 +93,31 @@ async fn handle_requests_inner<H: RequestHandler + Copy + Unpin>(
     user_code: UserCode,
     recv: &mut tokio::sync::mpsc::UnboundedReceiver<ControlMessage>,
     max_request_threads: u32,
+    limits: ExecutionLimits,
+    initialized: &mut Option<oneshot::Sender<anyhow::Result<()>>>,
 ) -> Result<(), anyhow::Error> {
     let is_module_mode = match user_code {
         UserCode::Script { .. } => false,
         UserCode::Directory(_) | UserCode::Module(_) => true,
     };
 
-    let module_loader = is_module_mode.then(runtime::module::Loader::default);
-    let standard_modules = TwoStandardModules(
+    let standard_modules = Some(TwoStandardModules(
         builtins::Modules {
             include_internal: is_module_mode,
             hardware_concurrency: max_request_threads,
         },
         handler.get_standard_modules(),
-    );
+    ));
 
-    let js_app = JsApp::build(module_loader, Some(standard_modules));
+    let js_app = match (is_module_mode, watch::is_tracking_modules()) {
+        (true, true) => JsApp::build(Some(TrackingLoader::default()), standard_modules),
+        (true, false) => JsApp::build(Some(CachingLoader::default()), standard_modules),
+        (false, _) => JsApp::build(None::<CachingLoader>, standard_modules),
+    };
     let cx = js_app.cx();
     let rt = js_app.rt();
     let mut event_loop_stream = EventLoopStream { app: &js_app };
+    let watchdog = Watchdog::install(cx, limits);
 
     handler.evaluate_scripts(cx, &user_code)?;
 
@@ -104,6 +129,14 @@ async fn handle_requests_inner<H: RequestHandler + Copy + Unpin>(
         .await
         .map_err(|e| error_report_option_to_anyhow_error(cx, e))?;
 
+    crate::server::health::set_initialized();
+    if let Some(tx) = initialized.take() {
+        ignore_error(tx.send(Ok(())));
+    }
+    if let Some(watchdog) = &watchdog {
+        watchdog.start_enforcing();
+    }
+
     let mut request_queue = RequestQueue::new(cx);
 
     let mut shutdown_requested = false;
@@ -113,6 +146,8 @@ async fn handle_requests_inner<H: RequestHandler + Copy + Unpin>(
             break;
         }
 
+        watchdog::restart_clock();
+
         select! {
             msg = recv.recv() => {
                 match msg {
@@ -183,7 +218,7 @@ fn handle_new_request<H: RequestHandler + Copy + Unpin>(
             body: req.body,
         },
     ) {
-        Err(f) => ignore_error(resp_tx.send(ResponseData::RequestError(f))),
+        Err(f) => ignore_error(resp_tx.send(request_error(f))),
         Ok(Either::Left(pending)) => request_queue.push(
             pending,
             RequestFinishedCallback {
@@ -201,6 +236,16 @@ fn handle_new_request<H: RequestHandler + Copy + Unpin>(
     }
 }
 
+fn request_error(error: anyhow::Error) -> ResponseData {
+    // If the watchdog stopped the script, the request it was handling is the
+    // one that failed
+    if watchdog::script_terminated() {
+        ResponseData::Done(watchdog::timeout_response())
+    } else {
+        ResponseData::RequestError(error)
+    }
+}
+
 #[derive(Clone, Copy)]
 enum RequestCancelledReason {
     Unresolvable,
@@ -246,7 +291,7 @@ impl<H: RequestHandler + Copy + Unpin> RequestFinishedHandler for RequestFinishe
                 }
             }
             Err(f) => {
-                ignore_error(self.get_resp_tx().send(ResponseData::RequestError(f)));
+                ignore_error(self.get_resp_tx().send(request_error(f)));
                 RequestFinishedResult::Done
             }
         }
@@ -255,6 +300,7 @@ impl<H: RequestHandler + Copy + Unpin> RequestFinishedHandler for RequestFinishe
     fn request_cancelled(&mut self, reason: RequestCancelledReason) {
         match reason {
             RequestCancelledReason::Unresolvable => {
+                crate::server::metrics::record_request_unresolvable();
                 let response = hyper::Response::builder()
                     .status(500)
                     .body(hyper::Body::from("The request could not be completed"))
@@ -267,6 +313,7 @@ impl<H: RequestHandler + Copy + Unpin> RequestFinishedHandler for RequestFinishe
             }
 
             RequestCancelledReason::ServerShuttingDown => {
+                crate::server::metrics::record_request_cancelled_by_shutdown();
                 let response = hyper::Response::builder()
                     .status(503)
                     .body(hyper::Body::from("Server is shutting down"))
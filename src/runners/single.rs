@@ -8,18 +8,25 @@ use std::{
     time::{Duration, Instant},
 };
 
-use anyhow::anyhow;
+use anyhow::{anyhow, bail, Context as _};
 use async_trait::async_trait;
-use tokio::{sync::Mutex, task::LocalSet};
+use tokio::{
+    sync::{oneshot, Mutex},
+    task::LocalSet,
+};
 
 use crate::{
-    request_handlers::{RequestHandler, UserCode},
+    request_handlers::{RequestHandler, UserCode, UserCodeSource},
     runners::{request_loop::handle_requests, ResponseData},
 };
 
-use super::request_loop::{ControlMessage, RequestData};
+use super::{
+    request_loop::{ControlMessage, RequestData},
+    watchdog::{self, ExecutionLimits},
+};
 
 pub struct WorkerThreadInfo {
+    index: usize,
     thread: std::thread::JoinHandle<()>,
     channel: tokio::sync::mpsc::UnboundedSender<ControlMessage>,
     in_flight_requests: Arc<AtomicI32>,
@@ -34,63 +41,132 @@ impl WorkerThreadInfo {
 // TODO: replace failing threads
 pub struct SingleRunner<H: RequestHandler + Copy + Unpin> {
     threads: Vec<WorkerThreadInfo>,
+
+    /// Threads running a previous version of the user code, which finish
+    /// the requests they already have before quitting.
+    draining_threads: Vec<WorkerThreadInfo>,
+
     max_threads: usize,
     handler: H,
     user_code: UserCode,
+    source: UserCodeSource,
+    limits: ExecutionLimits,
+    reloading: bool,
     shut_down: bool,
 }
 
 pub type SharedSingleRunner<H> = Arc<Mutex<SingleRunner<H>>>;
 
 impl<H: RequestHandler + Copy + Unpin> SingleRunner<H> {
-    pub fn new(max_threads: usize, handler: H, user_code: UserCode) -> Self {
+    pub fn new(
+        max_threads: usize,
+        handler: H,
+        user_code: UserCode,
+        source: UserCodeSource,
+        limits: ExecutionLimits,
+    ) -> Self {
         if max_threads == 0 {
             panic!("max_threads must be at least 1");
         }
 
-        Self {
+        let mut this = Self {
             threads: vec![],
+            draining_threads: vec![],
             max_threads,
             handler,
             user_code,
+            source,
+            limits,
+            reloading: false,
             shut_down: false,
-        }
+        };
+
+        // Start initializing the user code right away instead of on the
+        // first request, so we can report readiness.
+        this.spawn_thread();
+
+        this
     }
 
     pub fn new_request_handler(
         handler: H,
         max_threads: usize,
         user_code: UserCode,
+        source: UserCodeSource,
+        limits: ExecutionLimits,
     ) -> SharedSingleRunner<H> {
-        Arc::new(Mutex::new(Self::new(max_threads, handler, user_code)))
+        Arc::new(Mutex::new(Self::new(
+            max_threads,
+            handler,
+            user_code,
+            source,
+            limits,
+        )))
     }
 
-    fn spawn_thread(&mut self) -> &WorkerThreadInfo {
+    /// Starts a worker thread for the given user code. The returned channel
+    /// reports whether the code initialized successfully.
+    fn start_worker(
+        &self,
+        index: usize,
+        user_code: UserCode,
+    ) -> (WorkerThreadInfo, oneshot::Receiver<anyhow::Result<()>>) {
         let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let (initialized_tx, initialized_rx) = oneshot::channel();
         let handler = self.handler;
-        let user_code = self.user_code.clone();
         let max_threads = self.max_threads;
+        let limits = self.limits;
+        let in_flight_requests = Arc::new(AtomicI32::new(0));
+        let thread_in_flight_requests = in_flight_requests.clone();
         let join_handle = std::thread::spawn(move || {
-            tokio::runtime::Builder::new_current_thread()
+            let result = tokio::runtime::Builder::new_current_thread()
                 .enable_all()
                 .build()
                 .unwrap()
                 .block_on(async move {
                     let local_set = LocalSet::new();
                     local_set
-                        .run_until(handle_requests(handler, user_code, rx, max_threads as u32))
+                        .run_until(handle_requests(
+                            handler,
+                            user_code,
+                            rx,
+                            max_threads as u32,
+                            limits,
+                            Some(initialized_tx),
+                        ))
                         .await
-                })
+                });
+            crate::server::metrics::unregister_worker(index, &thread_in_flight_requests);
+            result
         });
+        crate::server::metrics::record_thread_spawned();
+        tracing::debug!("Starting new handler thread #{index}");
         let worker = WorkerThreadInfo {
+            index,
             thread: join_handle,
             channel: tx,
-            in_flight_requests: Arc::new(AtomicI32::new(0)),
+            in_flight_requests,
         };
+        (worker, initialized_rx)
+    }
+
+    fn add_thread(&mut self, worker: WorkerThreadInfo) -> &WorkerThreadInfo {
+        crate::server::metrics::register_worker(worker.index, worker.in_flight_requests.clone());
         self.threads.push(worker);
-        let spawned_index = self.threads.len() - 1;
-        tracing::debug!("Starting new handler thread #{spawned_index}");
-        &self.threads[spawned_index]
+        &self.threads[self.threads.len() - 1]
+    }
+
+    fn spawn_thread(&mut self) -> &WorkerThreadInfo {
+        let (worker, _) = self.start_worker(self.threads.len(), self.user_code.clone());
+        self.add_thread(worker)
+    }
+
+    /// Lets the thread finish its in-flight requests and quit.
+    fn drain_thread(&mut self, worker: WorkerThreadInfo) {
+        if !worker.is_finished() {
+            _ = worker.channel.send(ControlMessage::Shutdown);
+        }
+        self.draining_threads.push(worker);
     }
 
     fn find_or_spawn_thread(&mut self) -> Option<&WorkerThreadInfo> {
@@ -148,6 +224,7 @@ impl<H: RequestHandler + Copy + Unpin> crate::server::Runner for SharedSingleRun
         body: hyper::Body,
     ) -> Result<hyper::Response<hyper::Body>, anyhow::Error> {
         let mut this = self.lock().await;
+        let wall_time = this.limits.wall_time;
         let Some(thread) = this.find_or_spawn_thread() else {
             let response = hyper::Response::builder()
                 .status(503)
@@ -156,6 +233,7 @@ impl<H: RequestHandler + Copy + Unpin> crate::server::Runner for SharedSingleRun
             return Ok(response);
         };
 
+        let worker_id = crate::server::WorkerThreadId(thread.index);
         let request_count = thread.in_flight_requests.clone();
         let increment_guard = IncrementGuard::new(request_count);
 
@@ -169,13 +247,25 @@ impl<H: RequestHandler + Copy + Unpin> crate::server::Runner for SharedSingleRun
         // explicitly drop mutex guard to unlock mutex
         drop(this);
 
-        let response = rx.await?;
+        let response = match wall_time {
+            Some(limit) => match tokio::time::timeout(limit, rx).await {
+                Ok(response) => response?,
+                Err(_) => {
+                    tracing::warn!("Request did not complete within {limit:?}");
+                    ResponseData::Done(watchdog::timeout_response())
+                }
+            },
+            None => rx.await?,
+        };
 
         drop(increment_guard);
 
         // TODO: handle script errors
         match response {
-            ResponseData::Done(resp) => Ok(resp),
+            ResponseData::Done(mut resp) => {
+                resp.extensions_mut().insert(worker_id);
+                Ok(resp)
+            }
             ResponseData::RequestError(err) => Err(err),
             ResponseData::ScriptError(err) => {
                 if let Some(err) = err {
@@ -186,12 +276,65 @@ impl<H: RequestHandler + Copy + Unpin> crate::server::Runner for SharedSingleRun
         }
     }
 
+    async fn reload(&self) -> Result<(), anyhow::Error> {
+        let (user_code, worker, initialized) = {
+            let mut this = self.lock().await;
+            if this.shut_down {
+                bail!("Server is shutting down");
+            }
+            if this.reloading {
+                bail!("A reload is already in progress");
+            }
+
+            let user_code = this.source.load()?;
+            tracing::info!("Reloading user code");
+            super::watch::forget_loaded_modules();
+            let (worker, initialized) = this.start_worker(0, user_code.clone());
+            this.reloading = true;
+            this.draining_threads.retain(|t| !t.is_finished());
+            (user_code, worker, initialized)
+        };
+
+        // Requests keep going to the current threads while the new code
+        // initializes
+        let result = initialized
+            .await
+            .unwrap_or_else(|_| Err(anyhow!("Handler thread quit unexpectedly")));
+
+        let mut this = self.lock().await;
+        this.reloading = false;
+        if let Err(e) = result {
+            this.drain_thread(worker);
+            return Err(e).context("Failed to initialize the new user code");
+        }
+        if this.shut_down {
+            this.drain_thread(worker);
+            bail!("Server is shutting down");
+        }
+
+        this.user_code = user_code;
+        let old_threads = std::mem::take(&mut this.threads);
+        let old_thread_count = old_threads.len();
+        for thread in old_threads {
+            this.drain_thread(thread);
+        }
+        this.add_thread(worker);
+        tracing::info!("Reloaded user code, draining {old_thread_count} old handler thread(s)");
+
+        Ok(())
+    }
+
+    fn supports_reload(&self) -> bool {
+        true
+    }
+
     async fn shutdown(&self, timeout: Option<Duration>) {
         tracing::info!("Shutting down...");
+        crate::server::health::set_shutting_down();
 
         let mut this = self.lock().await;
         this.shut_down = true;
-        for thread in &this.threads {
+        for thread in this.threads.iter().chain(&this.draining_threads) {
             if !thread.is_finished() {
                 _ = thread.channel.send(ControlMessage::Shutdown);
             }
@@ -204,14 +347,15 @@ impl<H: RequestHandler + Copy + Unpin> crate::server::Runner for SharedSingleRun
 
         loop {
             let this = self.lock().await;
-            if this.threads.iter().any(|t| !t.is_finished()) {
+            let threads = this.threads.iter().chain(&this.draining_threads);
+            if threads.clone().any(|t| !t.is_finished()) {
                 if let Some(timeout) = timeout {
                     if shutdown_started.elapsed() >= timeout {
                         tracing::warn!(
                             "Clean shutdown timeout was reached before all \
                             requests could finish processing"
                         );
-                        for t in &this.threads {
+                        for t in threads {
                             if !t.is_finished() {
                                 _ = t.channel.send(ControlMessage::Terminate);
                             }
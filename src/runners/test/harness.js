@@ -0,0 +1,624 @@
+// The test framework behind `winterjs test`. Test files declare their tests
+// with `describe`/`it`/`test`, hooks with `beforeAll`/`afterAll`/
+// `beforeEach`/`afterEach`, and check values with `expect`, as in Jest.
+//
+// Once a test file has been evaluated, the runner calls
+// `__winterjs_test.run()` and collects the outcome with
+// `__winterjs_test.results()`, as JSON.
+
+(function () {
+  function makeSuite(name, parent, mode) {
+    return {
+      type: "suite",
+      name,
+      parent,
+      mode,
+      children: [],
+      hooks: { beforeAll: [], afterAll: [], beforeEach: [], afterEach: [] },
+    };
+  }
+
+  const root = makeSuite(null, null, "run");
+  let current = root;
+  let hasOnly = false;
+  let running = false;
+  let finished = false;
+  const results = [];
+
+  function checkDeclaration(what) {
+    if (running) {
+      throw new Error(`${what} can't be declared while tests are running`);
+    }
+  }
+
+  function addSuite(name, fn, mode) {
+    checkDeclaration("Suites");
+    const suite = makeSuite(String(name), current, mode);
+    current.children.push(suite);
+    hasOnly ||= mode === "only";
+
+    const parent = current;
+    current = suite;
+    try {
+      const result = fn();
+      if (typeof result?.then === "function") {
+        throw new TypeError(
+          `describe("${suite.name}") returned a promise; tests must be declared synchronously`
+        );
+      }
+    } finally {
+      current = parent;
+    }
+  }
+
+  function addTest(name, fn, mode, timeout) {
+    checkDeclaration("Tests");
+    if (fn === undefined) {
+      mode = "todo";
+    } else if (typeof fn !== "function") {
+      throw new TypeError(`The body of test "${name}" must be a function`);
+    }
+    current.children.push({ type: "test", name: String(name), fn, mode, timeout });
+    hasOnly ||= mode === "only";
+  }
+
+  function addHook(kind, fn) {
+    checkDeclaration("Hooks");
+    if (typeof fn !== "function") {
+      throw new TypeError(`${kind} must be given a function`);
+    }
+    current.hooks[kind].push(fn);
+  }
+
+  const describe = (name, fn) => addSuite(name, fn, "run");
+  describe.skip = (name, fn) => addSuite(name, fn, "skip");
+  describe.only = (name, fn) => addSuite(name, fn, "only");
+
+  const it = (name, fn, timeout) => addTest(name, fn, "run", timeout);
+  it.skip = (name, fn, timeout) => addTest(name, fn, "skip", timeout);
+  it.only = (name, fn, timeout) => addTest(name, fn, "only", timeout);
+  it.todo = (name) => addTest(name, undefined, "todo");
+
+  // Running tests
+
+  function serializeError(error) {
+    if (error instanceof Error) {
+      return { message: `${error.name}: ${error.message}`, stack: error.stack };
+    }
+    return { message: `Uncaught ${format(error)}` };
+  }
+
+  async function callWithTimeout(fn, timeout) {
+    let timer;
+    const timedOut = new Promise((_, reject) => {
+      timer = setTimeout(
+        () => reject(new Error(`Timed out after ${timeout}ms`)),
+        timeout
+      );
+    });
+    try {
+      await Promise.race([Promise.resolve().then(() => fn()), timedOut]);
+    } finally {
+      clearTimeout(timer);
+    }
+  }
+
+  // Whether a test should run, given where it is in the tree: explicitly
+  // skipped tests are reported as such, while tests left out by `only` or
+  // the filter aren't reported at all.
+  function selection(test, path, skipped, only, options) {
+    if (hasOnly && !only && test.mode !== "only") {
+      return "excluded";
+    }
+    if (options.filter && !path.join(" > ").includes(options.filter)) {
+      return "excluded";
+    }
+    if (test.mode === "todo") {
+      return "todo";
+    }
+    if (skipped || test.mode === "skip") {
+      return "skip";
+    }
+    return "run";
+  }
+
+  function anyTestRuns(suite, path, skipped, only, options) {
+    return suite.children.some((child) => {
+      const childPath = [...path, child.name];
+      const childSkipped = skipped || child.mode === "skip";
+      const childOnly = only || child.mode === "only";
+      return child.type === "suite"
+        ? anyTestRuns(child, childPath, childSkipped, childOnly, options)
+        : selection(child, childPath, skipped, only, options) === "run";
+    });
+  }
+
+  function eachHooks(suite, kind) {
+    const hooks = [];
+    for (let s = suite; s !== null; s = s.parent) {
+      hooks.unshift(...s.hooks[kind]);
+    }
+    return kind === "afterEach" ? hooks.reverse() : hooks;
+  }
+
+  async function runTest(test, suite, path, skipped, only, options, setupError) {
+    const status = selection(test, path, skipped, only, options);
+    if (status === "excluded") {
+      return;
+    }
+    if (status !== "run") {
+      results.push({ name: path, status, duration: 0 });
+      return;
+    }
+
+    const timeout = test.timeout ?? options.timeout;
+    const started = performance.now();
+    let error = setupError;
+    if (error === undefined) {
+      try {
+        for (const hook of eachHooks(suite, "beforeEach")) {
+          await callWithTimeout(hook, timeout);
+        }
+        await callWithTimeout(test.fn, timeout);
+      } catch (e) {
+        error = e;
+      }
+      // Cleanup runs even if the test failed
+      for (const hook of eachHooks(suite, "afterEach")) {
+        try {
+          await callWithTimeout(hook, timeout);
+        } catch (e) {
+          error ??= e;
+        }
+      }
+    }
+
+    results.push({
+      name: path,
+      status: error === undefined ? "pass" : "fail",
+      duration: performance.now() - started,
+      error: error === undefined ? undefined : serializeError(error),
+    });
+  }
+
+  async function runSuite(suite, path, skipped, only, options, setupError) {
+    const runs = anyTestRuns(suite, path, skipped, only, options);
+
+    if (runs && setupError === undefined) {
+      for (const hook of suite.hooks.beforeAll) {
+        try {
+          await callWithTimeout(hook, options.timeout);
+        } catch (e) {
+          // The suite's tests fail with the error instead of running
+          setupError = e;
+          break;
+        }
+      }
+    }
+
+    for (const child of suite.children) {
+      const childPath = [...path, child.name];
+      if (child.type === "suite") {
+        await runSuite(
+          child,
+          childPath,
+          skipped || child.mode === "skip",
+          only || child.mode === "only",
+          options,
+          setupError
+        );
+      } else {
+        await runTest(child, suite, childPath, skipped, only, options, setupError);
+      }
+    }
+
+    if (runs) {
+      for (const hook of suite.hooks.afterAll) {
+        try {
+          await callWithTimeout(hook, options.timeout);
+        } catch (e) {
+          results.push({
+            name: [...path, "afterAll"],
+            status: "fail",
+            duration: 0,
+            error: serializeError(e),
+          });
+        }
+      }
+    }
+  }
+
+  async function run(filter, timeout) {
+    running = true;
+    try {
+      await runSuite(root, [], false, false, { filter, timeout });
+    } finally {
+      finished = true;
+    }
+  }
+
+  // Assertions
+
+  class AssertionError extends Error {
+    name = "AssertionError";
+  }
+
+  function format(value, depth = 0) {
+    switch (typeof value) {
+      case "string":
+        return JSON.stringify(value);
+      case "bigint":
+        return `${value}n`;
+      case "function":
+        return `[Function ${value.name || "anonymous"}]`;
+      case "symbol":
+        return value.toString();
+      case "object":
+        break;
+      default:
+        return String(value);
+    }
+    if (value === null) {
+      return "null";
+    }
+    if (value instanceof Error) {
+      return `${value.name}: ${value.message}`;
+    }
+    if (value instanceof Date || value instanceof RegExp) {
+      return String(value);
+    }
+    if (depth > 2) {
+      return Array.isArray(value) ? "[...]" : "{...}";
+    }
+    if (Array.isArray(value) || ArrayBuffer.isView(value)) {
+      const items = Array.from(value, (item) => format(item, depth + 1));
+      const prefix = Array.isArray(value) ? "" : `${value.constructor.name} `;
+      return `${prefix}[${items.join(", ")}]`;
+    }
+    if (value instanceof Map) {
+      const entries = Array.from(
+        value,
+        ([k, v]) => `${format(k, depth + 1)} => ${format(v, depth + 1)}`
+      );
+      return `Map {${entries.join(", ")}}`;
+    }
+    if (value instanceof Set) {
+      const items = Array.from(value, (item) => format(item, depth + 1));
+      return `Set {${items.join(", ")}}`;
+    }
+    const entries = Object.keys(value).map(
+      (key) => `${key}: ${format(value[key], depth + 1)}`
+    );
+    const name = value.constructor?.name;
+    const prefix = name && name !== "Object" ? `${name} ` : "";
+    return `${prefix}{${entries.join(", ")}}`;
+  }
+
+  // Deep equality, as in Jest's toEqual. In strict mode, as in
+  // toStrictEqual, prototypes must match and undefined properties count.
+  function equals(a, b, strict, seen = new Map()) {
+    if (Object.is(a, b)) {
+      return true;
+    }
+    if (
+      typeof a !== "object" ||
+      typeof b !== "object" ||
+      a === null ||
+      b === null
+    ) {
+      return false;
+    }
+    if (seen.get(a) === b) {
+      return true;
+    }
+    seen.set(a, b);
+
+    if (strict && Object.getPrototypeOf(a) !== Object.getPrototypeOf(b)) {
+      return false;
+    }
+    if (Array.isArray(a) !== Array.isArray(b)) {
+      return false;
+    }
+    if (a instanceof Date || b instanceof Date) {
+      return (
+        a instanceof Date && b instanceof Date && a.getTime() === b.getTime()
+      );
+    }
+    if (a instanceof RegExp || b instanceof RegExp) {
+      return String(a) === String(b);
+    }
+    if (a instanceof Error && b instanceof Error) {
+      return a.name === b.name && a.message === b.message;
+    }
+    if (a instanceof ArrayBuffer && b instanceof ArrayBuffer) {
+      return equals(new Uint8Array(a), new Uint8Array(b), strict, seen);
+    }
+    if (ArrayBuffer.isView(a) || ArrayBuffer.isView(b)) {
+      return (
+        ArrayBuffer.isView(a) &&
+        ArrayBuffer.isView(b) &&
+        a.constructor === b.constructor &&
+        a.length === b.length &&
+        Array.prototype.every.call(a, (item, i) => Object.is(item, b[i]))
+      );
+    }
+    if (a instanceof Map || b instanceof Map) {
+      return (
+        a instanceof Map &&
+        b instanceof Map &&
+        a.size === b.size &&
+        [...a].every(
+          ([key, value]) => b.has(key) && equals(value, b.get(key), strict, seen)
+        )
+      );
+    }
+    if (a instanceof Set || b instanceof Set) {
+      return (
+        a instanceof Set &&
+        b instanceof Set &&
+        a.size === b.size &&
+        [...a].every(
+          (value) =>
+            b.has(value) || [...b].some((other) => equals(value, other, strict, seen))
+        )
+      );
+    }
+
+    const keys = (object) =>
+      Object.keys(object).filter((key) => strict || object[key] !== undefined);
+    const keysA = keys(a);
+    const keysB = keys(b);
+    return (
+      keysA.length === keysB.length &&
+      keysA.every(
+        (key) =>
+          Object.prototype.hasOwnProperty.call(b, key) &&
+          equals(a[key], b[key], strict, seen)
+      )
+    );
+  }
+
+  // Whether `object` has at least the properties of `subset`
+  function matchesObject(object, subset) {
+    if (typeof subset !== "object" || subset === null) {
+      return equals(object, subset, false);
+    }
+    if (typeof object !== "object" || object === null) {
+      return false;
+    }
+    if (Array.isArray(subset)) {
+      return (
+        Array.isArray(object) &&
+        object.length === subset.length &&
+        subset.every((item, i) => matchesObject(object[i], item))
+      );
+    }
+    return Object.keys(subset).every(
+      (key) => key in object && matchesObject(object[key], subset[key])
+    );
+  }
+
+  function getPath(object, path) {
+    const keys = Array.isArray(path) ? path : String(path).split(".");
+    let value = object;
+    for (const key of keys) {
+      if (value === null || value === undefined || !(key in Object(value))) {
+        return { found: false };
+      }
+      value = value[key];
+    }
+    return { found: true, value };
+  }
+
+  function compare(expected, received) {
+    return (not) =>
+      `Expected: ${not ? "not " : ""}${format(expected)}\nReceived: ${format(received)}`;
+  }
+
+  function describeReceived(received) {
+    return () => `Received: ${format(received)}`;
+  }
+
+  // Each matcher checks `received`, and returns whether it passed along
+  // with a function building the failure message.
+  const matchers = {
+    toBe(received, expected) {
+      return { pass: Object.is(received, expected), message: compare(expected, received) };
+    },
+    toEqual(received, expected) {
+      return { pass: equals(received, expected, false), message: compare(expected, received) };
+    },
+    toStrictEqual(received, expected) {
+      return { pass: equals(received, expected, true), message: compare(expected, received) };
+    },
+    toMatchObject(received, expected) {
+      return { pass: matchesObject(received, expected), message: compare(expected, received) };
+    },
+    toBeTruthy(received) {
+      return { pass: !!received, message: describeReceived(received) };
+    },
+    toBeFalsy(received) {
+      return { pass: !received, message: describeReceived(received) };
+    },
+    toBeNull(received) {
+      return { pass: received === null, message: describeReceived(received) };
+    },
+    toBeUndefined(received) {
+      return { pass: received === undefined, message: describeReceived(received) };
+    },
+    toBeDefined(received) {
+      return { pass: received !== undefined, message: describeReceived(received) };
+    },
+    toBeNaN(received) {
+      return { pass: Number.isNaN(received), message: describeReceived(received) };
+    },
+    toBeGreaterThan(received, expected) {
+      return { pass: received > expected, message: compare(`> ${expected}`, received) };
+    },
+    toBeGreaterThanOrEqual(received, expected) {
+      return { pass: received >= expected, message: compare(`>= ${expected}`, received) };
+    },
+    toBeLessThan(received, expected) {
+      return { pass: received < expected, message: compare(`< ${expected}`, received) };
+    },
+    toBeLessThanOrEqual(received, expected) {
+      return { pass: received <= expected, message: compare(`<= ${expected}`, received) };
+    },
+    toBeCloseTo(received, expected, digits = 2) {
+      return {
+        pass: Math.abs(expected - received) < 10 ** -digits / 2,
+        message: compare(expected, received),
+      };
+    },
+    toBeInstanceOf(received, expected) {
+      return {
+        pass: received instanceof expected,
+        message: (not) =>
+          `Expected: ${not ? "not " : ""}an instance of ${expected.name}\n` +
+          `Received: ${format(received)}`,
+      };
+    },
+    toContain(received, expected) {
+      const pass =
+        typeof received === "string"
+          ? received.includes(expected)
+          : Array.from(received ?? []).includes(expected);
+      return { pass, message: compare(expected, received) };
+    },
+    toContainEqual(received, expected) {
+      const pass = Array.from(received ?? []).some((item) =>
+        equals(item, expected, false)
+      );
+      return { pass, message: compare(expected, received) };
+    },
+    toHaveLength(received, expected) {
+      return {
+        pass: received?.length === expected,
+        message: compare(`length ${expected}`, received),
+      };
+    },
+    toHaveProperty(received, path, ...value) {
+      const { found, value: actual } = getPath(received, path);
+      const pass = found && (value.length === 0 || equals(actual, value[0], false));
+      return {
+        pass,
+        message: (not) =>
+          `Expected: ${not ? "not " : ""}property ${format(path)}` +
+          (value.length === 0 ? "" : ` equal to ${format(value[0])}`) +
+          `\nReceived: ${found ? format(actual) : format(received)}`,
+      };
+    },
+    toMatch(received, expected) {
+      const pass =
+        typeof received === "string" &&
+        (expected instanceof RegExp
+          ? expected.test(received)
+          : received.includes(expected));
+      return { pass, message: compare(expected, received) };
+    },
+    toThrow(received, expected) {
+      let thrown = false;
+      let error;
+      if (this.rejected) {
+        // With `.rejects`, the rejection reason is what was thrown
+        thrown = true;
+        error = received;
+      } else if (typeof received === "function") {
+        try {
+          received();
+        } catch (e) {
+          thrown = true;
+          error = e;
+        }
+      } else {
+        throw new TypeError("expect(received).toThrow() needs a function");
+      }
+
+      const message = error?.message ?? String(error);
+      let pass = thrown;
+      if (thrown && typeof expected === "string") {
+        pass = message.includes(expected);
+      } else if (thrown && expected instanceof RegExp) {
+        pass = expected.test(message);
+      } else if (thrown && expected instanceof Error) {
+        pass = message === expected.message;
+      } else if (thrown && typeof expected === "function") {
+        pass = error instanceof expected;
+      }
+      return {
+        pass,
+        message: (not) =>
+          `Expected: ${not ? "no error" : expected === undefined ? "an error" : format(expected)}\n` +
+          `Received: ${thrown ? format(error) : "no error"}`,
+      };
+    },
+  };
+
+  function expectation(received, not, settle) {
+    const result = {};
+    for (const [name, matcher] of Object.entries(matchers)) {
+      result[name] = (...args) =>
+        settle(received, (value, context) => {
+          const { pass, message } = matcher.call(context, value, ...args);
+          if (pass === not) {
+            throw new AssertionError(
+              `expect(received).${not ? "not." : ""}${name}()\n\n${message(not)}`
+            );
+          }
+        });
+    }
+    return result;
+  }
+
+  const now = (received, check) => check(received, {});
+
+  const resolves = (received, check) =>
+    Promise.resolve(received).then(
+      (value) => check(value, {}),
+      (error) => {
+        throw new AssertionError(
+          `Expected the promise to resolve, but it rejected with ${format(error)}`
+        );
+      }
+    );
+
+  const rejects = (received, check) =>
+    Promise.resolve(received).then(
+      (value) => {
+        throw new AssertionError(
+          `Expected the promise to reject, but it resolved to ${format(value)}`
+        );
+      },
+      (error) => check(error, { rejected: true })
+    );
+
+  function expect(received) {
+    return Object.assign(expectation(received, false, now), {
+      not: expectation(received, true, now),
+      resolves: Object.assign(expectation(received, false, resolves), {
+        not: expectation(received, true, resolves),
+      }),
+      rejects: Object.assign(expectation(received, false, rejects), {
+        not: expectation(received, true, rejects),
+      }),
+    });
+  }
+
+  Object.assign(globalThis, {
+    describe,
+    it,
+    test: it,
+    expect,
+    beforeAll: (fn) => addHook("beforeAll", fn),
+    afterAll: (fn) => addHook("afterAll", fn),
+    beforeEach: (fn) => addHook("beforeEach", fn),
+    afterEach: (fn) => addHook("afterEach", fn),
+  });
+
+  Object.defineProperty(globalThis, "__winterjs_test", {
+    value: {
+      run,
+      results: () => JSON.stringify({ finished, results }),
+    },
+  });
+})();
@@ -0,0 +1,211 @@
+//! `winterjs test`: runs the tests in `*.test.js` files. Each file gets a
+//! fresh runtime with the same globals as `serve`, plus the test framework
+//! from `harness.js`.
+
+use std::{
+    ffi::OsStr,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context as _, Result};
+use ion::conversions::FromValue;
+use tokio::task::LocalSet;
+
+use crate::{
+    builtins,
+    request_handlers::RequestHandler,
+    sm_utils::{
+        error_report_option_to_anyhow_error, evaluate_module, evaluate_script, JsApp,
+        TwoStandardModules,
+    },
+    stencil_cache::CachingLoader,
+};
+
+use self::report::{FileResults, TestResult};
+
+pub mod report;
+
+pub use report::Reporter;
+
+const HARNESS: &str = include_str!("harness.js");
+
+const TEST_FILE_SUFFIX: &str = ".test.js";
+
+pub struct TestOptions {
+    /// Test files, or directories to search for test files.
+    pub paths: Vec<PathBuf>,
+    pub script_mode: bool,
+    pub handler: Box<dyn RequestHandler>,
+    /// Only tests whose full name contains this string are run.
+    pub filter: Option<String>,
+    pub timeout: Duration,
+    pub reporter: Reporter,
+    /// Where to write the report. Defaults to stdout.
+    pub output: Option<PathBuf>,
+}
+
+/// Runs all the tests, and returns whether they all passed.
+pub fn run_tests(options: TestOptions) -> Result<bool> {
+    let files = find_test_files(&options.paths)?;
+    if files.is_empty() {
+        eprintln!("No test files found");
+        return Ok(false);
+    }
+
+    let mut reporter = report::start(options.reporter, options.output.as_deref())?;
+    for path in files {
+        let started = Instant::now();
+        let results = match run_file(&path, &options) {
+            Ok(results) => results,
+            // The file couldn't even be loaded, which fails it as a whole
+            Err(e) => vec![TestResult::file_error(format!("{e:#}"))],
+        };
+        reporter.file_done(FileResults {
+            path,
+            results,
+            duration: started.elapsed(),
+        })?;
+    }
+
+    reporter.finish()
+}
+
+fn find_test_files(paths: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    if paths.is_empty() {
+        find_in_dir(Path::new("."), &mut files)?;
+    }
+    for path in paths {
+        let metadata = std::fs::metadata(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        if metadata.is_dir() {
+            find_in_dir(path, &mut files)?;
+        } else {
+            // Files named explicitly are run whatever their name
+            files.push(path.clone());
+        }
+    }
+    Ok(files)
+}
+
+fn find_in_dir(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    let mut entries = std::fs::read_dir(dir)
+        .with_context(|| format!("Failed to read directory {}", dir.display()))?
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Failed to read directory {}", dir.display()))?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if name.starts_with('.') || name == "node_modules" {
+            continue;
+        }
+
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            find_in_dir(&path, files)?;
+        } else if name.ends_with(TEST_FILE_SUFFIX) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn run_file(path: &Path, options: &TestOptions) -> Result<Vec<TestResult>> {
+    let path = path.to_owned();
+    let script_mode = options.script_mode;
+    let handler = options.handler.clone();
+    let filter = options.filter.clone();
+    let timeout = options.timeout;
+
+    // Like `exec`, each file runs on a thread of its own with a
+    // single-threaded runtime, which also gives every file a fresh
+    // Javascript runtime.
+    std::thread::spawn(move || {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(async move {
+                let local_set = LocalSet::new();
+                local_set
+                    .run_until(run_file_inner(path, script_mode, handler, filter, timeout))
+                    .await
+            })
+    })
+    .join()
+    .map_err(|_| anyhow!("The test runner thread panicked"))?
+}
+
+async fn run_file_inner(
+    path: PathBuf,
+    script_mode: bool,
+    handler: Box<dyn RequestHandler>,
+    filter: Option<String>,
+    timeout: Duration,
+) -> Result<Vec<TestResult>> {
+    let module_loader = (!script_mode).then(CachingLoader::default);
+    let standard_modules = TwoStandardModules(
+        builtins::Modules {
+            include_internal: !script_mode,
+            hardware_concurrency: 1,
+        },
+        handler.get_standard_modules(),
+    );
+
+    let js_app = JsApp::build(module_loader, Some(standard_modules));
+    let cx = js_app.cx();
+    let rt = js_app.rt();
+
+    evaluate_script(cx, HARNESS, OsStr::new("winterjs:test"))?;
+
+    if script_mode {
+        let code = std::fs::read_to_string(&path).context("Failed to read test file")?;
+        evaluate_script(cx, code, path.as_os_str())?;
+    } else {
+        evaluate_module(cx, &path)?;
+    }
+
+    // Lets top-level await settle before the tests run
+    rt.run_event_loop()
+        .await
+        .map_err(|e| error_report_option_to_anyhow_error(cx, e))?;
+
+    evaluate_script(
+        cx,
+        format!(
+            "__winterjs_test.run({}, {})",
+            serde_json::to_string(&filter)?,
+            timeout.as_millis()
+        ),
+        OsStr::new("winterjs:test"),
+    )?;
+
+    // Errors nobody handled, such as rejections of promises the tests
+    // didn't wait for, fail the file but don't stop the remaining tests
+    let mut unhandled = vec![];
+    while let Err(e) = rt.run_event_loop().await {
+        unhandled.push(error_report_option_to_anyhow_error(cx, e));
+    }
+
+    let results = evaluate_script(cx, "__winterjs_test.results()", OsStr::new("winterjs:test"))?;
+    let results = String::from_value(cx, &results, true, ())
+        .map_err(|e| anyhow!("Failed to read test results: {e:?}"))?;
+    let report::HarnessResults {
+        finished,
+        mut results,
+    } = serde_json::from_str(&results).context("Failed to parse test results")?;
+
+    if !finished {
+        results.push(TestResult::file_error(
+            "The tests did not finish: a test is waiting on a promise that can never be resolved",
+        ));
+    }
+    for e in unhandled {
+        results.push(TestResult::file_error(format!("Unhandled error: {e:#}")));
+    }
+
+    Ok(results)
+}
@@ -0,0 +1,472 @@
+//! Reports of `winterjs test` runs, in TAP or JUnit XML format. TAP output
+//! is written as each file finishes, JUnit output once all files are done.
+
+use std::{
+    fmt::Write as _,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context as _, Result};
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum Reporter {
+    /// Test Anything Protocol, version 13.
+    Tap,
+    /// JUnit XML, as understood by most CI systems.
+    Junit,
+}
+
+/// What the harness reports once a file's tests have run.
+#[derive(Deserialize)]
+pub(super) struct HarnessResults {
+    pub finished: bool,
+    pub results: Vec<TestResult>,
+}
+
+#[derive(Deserialize)]
+pub struct TestResult {
+    /// The names of the enclosing `describe` blocks, followed by the name
+    /// of the test. Empty for errors concerning the whole file.
+    pub name: Vec<String>,
+    pub status: Status,
+    /// In milliseconds.
+    pub duration: f64,
+    #[serde(default)]
+    pub error: Option<TestError>,
+}
+
+impl TestResult {
+    pub fn file_error(message: impl Into<String>) -> Self {
+        Self {
+            name: vec![],
+            status: Status::Fail,
+            duration: 0.0,
+            error: Some(TestError {
+                message: message.into(),
+                stack: None,
+            }),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Pass,
+    Fail,
+    Skip,
+    Todo,
+}
+
+#[derive(Deserialize)]
+pub struct TestError {
+    pub message: String,
+    #[serde(default)]
+    pub stack: Option<String>,
+}
+
+pub struct FileResults {
+    pub path: PathBuf,
+    pub results: Vec<TestResult>,
+    pub duration: Duration,
+}
+
+#[derive(Default)]
+struct Counts {
+    total: usize,
+    passed: usize,
+    failed: usize,
+    skipped: usize,
+    todo: usize,
+}
+
+impl Counts {
+    fn add(&mut self, status: Status) {
+        self.total += 1;
+        match status {
+            Status::Pass => self.passed += 1,
+            Status::Fail => self.failed += 1,
+            Status::Skip => self.skipped += 1,
+            Status::Todo => self.todo += 1,
+        }
+    }
+}
+
+pub(super) struct Report {
+    reporter: Reporter,
+    out: Box<dyn Write>,
+    counts: Counts,
+    /// Kept until the end for JUnit output.
+    files: Vec<FileResults>,
+}
+
+pub(super) fn start(reporter: Reporter, output: Option<&Path>) -> Result<Report> {
+    let out: Box<dyn Write> = match output {
+        Some(path) => {
+            Box::new(BufWriter::new(File::create(path).with_context(|| {
+                format!("Failed to create report file {}", path.display())
+            })?))
+        }
+        None => Box::new(io::stdout()),
+    };
+
+    let mut report = Report {
+        reporter,
+        out,
+        counts: Counts::default(),
+        files: vec![],
+    };
+    if let Reporter::Tap = reporter {
+        writeln!(report.out, "TAP version 13")?;
+    }
+    Ok(report)
+}
+
+impl Report {
+    pub fn file_done(&mut self, file: FileResults) -> Result<()> {
+        for result in &file.results {
+            self.counts.add(result.status);
+            if let Reporter::Tap = self.reporter {
+                write_tap_result(&mut self.out, self.counts.total, &file.path, result)?;
+            }
+        }
+        self.out.flush()?;
+
+        if let Reporter::Junit = self.reporter {
+            self.files.push(file);
+        }
+        Ok(())
+    }
+
+    /// Writes out the rest of the report, and returns whether all tests
+    /// passed.
+    pub fn finish(mut self) -> Result<bool> {
+        let counts = &self.counts;
+        match self.reporter {
+            Reporter::Tap => {
+                writeln!(self.out, "1..{}", counts.total)?;
+                writeln!(self.out, "# tests {}", counts.total)?;
+                writeln!(self.out, "# pass {}", counts.passed)?;
+                writeln!(self.out, "# fail {}", counts.failed)?;
+                writeln!(self.out, "# skip {}", counts.skipped)?;
+                writeln!(self.out, "# todo {}", counts.todo)?;
+            }
+            Reporter::Junit => write_junit(&mut self.out, counts, &self.files)?,
+        }
+        self.out.flush()?;
+
+        Ok(self.counts.failed == 0)
+    }
+}
+
+fn full_name(path: &Path, result: &TestResult) -> String {
+    let mut name = path.display().to_string();
+    for part in &result.name {
+        name.push_str(" > ");
+        name.push_str(part);
+    }
+    name
+}
+
+fn write_tap_result(
+    out: &mut dyn Write,
+    number: usize,
+    path: &Path,
+    result: &TestResult,
+) -> io::Result<()> {
+    // Names can't span lines, and a # would start a directive
+    let name = full_name(path, result)
+        .replace(['\n', '\r'], " ")
+        .replace('#', "\\#");
+    match result.status {
+        Status::Pass => writeln!(out, "ok {number} - {name}"),
+        Status::Skip => writeln!(out, "ok {number} - {name} # SKIP"),
+        Status::Todo => writeln!(out, "not ok {number} - {name} # TODO"),
+        Status::Fail => {
+            writeln!(out, "not ok {number} - {name}")?;
+            writeln!(out, "  ---")?;
+            writeln!(out, "  duration_ms: {:.3}", result.duration)?;
+            if let Some(error) = &result.error {
+                write_yaml_block(out, "message", &error.message)?;
+                if let Some(stack) = error.stack.as_deref().filter(|s| !s.is_empty()) {
+                    write_yaml_block(out, "stack", stack)?;
+                }
+            }
+            writeln!(out, "  ...")
+        }
+    }
+}
+
+fn write_yaml_block(out: &mut dyn Write, key: &str, value: &str) -> io::Result<()> {
+    writeln!(out, "  {key}: |-")?;
+    for line in value.trim_end().lines() {
+        writeln!(out, "    {line}")?;
+    }
+    Ok(())
+}
+
+fn write_junit(out: &mut dyn Write, counts: &Counts, files: &[FileResults]) -> io::Result<()> {
+    let total_time: Duration = files.iter().map(|file| file.duration).sum();
+
+    writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(
+        out,
+        r#"<testsuites name="winterjs" tests="{}" failures="{}" skipped="{}" time="{:.3}">"#,
+        counts.total,
+        counts.failed,
+        counts.skipped + counts.todo,
+        total_time.as_secs_f64()
+    )?;
+
+    for file in files {
+        let mut file_counts = Counts::default();
+        for result in &file.results {
+            file_counts.add(result.status);
+        }
+        let file_name = file.path.display().to_string();
+
+        writeln!(
+            out,
+            r#"  <testsuite name="{}" tests="{}" failures="{}" skipped="{}" time="{:.3}">"#,
+            xml_escape(&file_name),
+            file_counts.total,
+            file_counts.failed,
+            file_counts.skipped + file_counts.todo,
+            file.duration.as_secs_f64()
+        )?;
+
+        for result in &file.results {
+            // The enclosing describe blocks go into the class name
+            let (class_name, name) = match result.name.split_last() {
+                Some((name, suites)) => {
+                    let mut class_name = file_name.clone();
+                    for suite in suites {
+                        class_name.push_str(" > ");
+                        class_name.push_str(suite);
+                    }
+                    (class_name, name.clone())
+                }
+                None => (file_name.clone(), file_name.clone()),
+            };
+
+            write!(
+                out,
+                r#"    <testcase classname="{}" name="{}" time="{:.3}""#,
+                xml_escape(&class_name),
+                xml_escape(&name),
+                result.duration / 1000.0
+            )?;
+            match result.status {
+                Status::Pass => writeln!(out, " />")?,
+                Status::Skip => writeln!(out, ">\n      <skipped />\n    </testcase>")?,
+                Status::Todo => writeln!(
+                    out,
+                    ">\n      <skipped message=\"todo\" />\n    </testcase>"
+                )?,
+                Status::Fail => {
+                    let (message, details) = match &result.error {
+                        Some(error) => {
+                            let mut details = error.message.clone();
+                            if let Some(stack) = &error.stack {
+                                _ = write!(details, "\n{stack}");
+                            }
+                            (error.message.as_str(), details)
+                        }
+                        None => ("Test failed", String::new()),
+                    };
+                    writeln!(
+                        out,
+                        ">\n      <failure message=\"{}\">{}</failure>\n    </testcase>",
+                        xml_escape(message.lines().next().unwrap_or_default()),
+                        xml_escape(&details)
+                    )?;
+                }
+            }
+        }
+
+        writeln!(out, "  </testsuite>")?;
+    }
+
+    writeln!(out, "</testsuites>")
+}
+
+fn xml_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            // Other control characters aren't allowed in XML at all
+            '\n' | '\r' | '\t' => escaped.push(c),
+            c if c.is_control() => (),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(name: &[&str], status: Status, error: Option<&str>) -> TestResult {
+        TestResult {
+            name: name.iter().map(|part| part.to_string()).collect(),
+            status,
+            duration: 1.5,
+            error: error.map(|message| TestError {
+                message: message.to_string(),
+                stack: Some("at foo.test.js:3:7".to_string()),
+            }),
+        }
+    }
+
+    fn tap(number: usize, result: &TestResult) -> String {
+        let mut out = vec![];
+        write_tap_result(&mut out, number, Path::new("foo.test.js"), result).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    fn junit(files: &[FileResults]) -> String {
+        let mut counts = Counts::default();
+        for file in files {
+            for result in &file.results {
+                counts.add(result.status);
+            }
+        }
+        let mut out = vec![];
+        write_junit(&mut out, &counts, files).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn parses_harness_results() {
+        let results: HarnessResults = serde_json::from_str(
+            r#"{
+                "finished": true,
+                "results": [
+                    { "name": ["math", "adds"], "status": "pass", "duration": 0.25 },
+                    {
+                        "name": ["fails"],
+                        "status": "fail",
+                        "duration": 1,
+                        "error": { "message": "expected 1 to be 2" }
+                    }
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        assert!(results.finished);
+        assert_eq!(results.results.len(), 2);
+        assert_eq!(results.results[0].name, ["math", "adds"]);
+        assert!(results.results[0].status == Status::Pass);
+        assert!(results.results[0].error.is_none());
+        let error = results.results[1].error.as_ref().unwrap();
+        assert_eq!(error.message, "expected 1 to be 2");
+        assert!(error.stack.is_none());
+    }
+
+    #[test]
+    fn tap_results() {
+        assert_eq!(
+            tap(1, &result(&["math", "adds"], Status::Pass, None)),
+            "ok 1 - foo.test.js > math > adds\n"
+        );
+        assert_eq!(
+            tap(2, &result(&["later"], Status::Skip, None)),
+            "ok 2 - foo.test.js > later # SKIP\n"
+        );
+        assert_eq!(
+            tap(3, &result(&["someday"], Status::Todo, None)),
+            "not ok 3 - foo.test.js > someday # TODO\n"
+        );
+    }
+
+    #[test]
+    fn tap_failure_has_yaml_block() {
+        assert_eq!(
+            tap(
+                4,
+                &result(&["breaks"], Status::Fail, Some("line one\nline two"))
+            ),
+            "not ok 4 - foo.test.js > breaks\n\
+             \x20 ---\n\
+             \x20 duration_ms: 1.500\n\
+             \x20 message: |-\n\
+             \x20   line one\n\
+             \x20   line two\n\
+             \x20 stack: |-\n\
+             \x20   at foo.test.js:3:7\n\
+             \x20 ...\n"
+        );
+    }
+
+    #[test]
+    fn tap_names_are_escaped() {
+        assert_eq!(
+            tap(1, &result(&["issue #12\nstill works"], Status::Pass, None)),
+            "ok 1 - foo.test.js > issue \\#12 still works\n"
+        );
+    }
+
+    #[test]
+    fn junit_report() {
+        let files = [FileResults {
+            path: PathBuf::from("foo.test.js"),
+            results: vec![
+                result(&["math", "adds"], Status::Pass, None),
+                result(&["<compare>"], Status::Fail, Some("expected \"a\" & 'b'")),
+                result(&["later"], Status::Skip, None),
+                result(&["someday"], Status::Todo, None),
+            ],
+            duration: Duration::from_millis(20),
+        }];
+
+        assert_eq!(
+            junit(&files),
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<testsuites name="winterjs" tests="4" failures="1" skipped="2" time="0.020">
+  <testsuite name="foo.test.js" tests="4" failures="1" skipped="2" time="0.020">
+    <testcase classname="foo.test.js &gt; math" name="adds" time="0.002" />
+    <testcase classname="foo.test.js" name="&lt;compare&gt;" time="0.002">
+      <failure message="expected &quot;a&quot; &amp; &apos;b&apos;">expected &quot;a&quot; &amp; &apos;b&apos;
+at foo.test.js:3:7</failure>
+    </testcase>
+    <testcase classname="foo.test.js" name="later" time="0.002">
+      <skipped />
+    </testcase>
+    <testcase classname="foo.test.js" name="someday" time="0.002">
+      <skipped message="todo" />
+    </testcase>
+  </testsuite>
+</testsuites>
+"#
+        );
+    }
+
+    #[test]
+    fn junit_file_errors_are_named_after_the_file() {
+        let files = [FileResults {
+            path: PathBuf::from("broken.test.js"),
+            results: vec![TestResult::file_error("SyntaxError: unexpected token")],
+            duration: Duration::ZERO,
+        }];
+
+        assert!(junit(&files).contains(
+            r#"<testcase classname="broken.test.js" name="broken.test.js" time="0.000">"#
+        ));
+    }
+
+    #[test]
+    fn xml_escape_drops_control_characters() {
+        assert_eq!(xml_escape("a\u{1}b\tc\nd"), "ab\tc\nd");
+    }
+}
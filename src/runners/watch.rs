@@ -1,109 +1,327 @@
-// TODO: get watch mode working again
-
-// use std::{path::PathBuf, sync::Arc};
-
-// use anyhow::bail;
-// use tokio::sync::Mutex;
-
-// use crate::request_handlers::{RequestHandler, UserCode};
-
-// use super::single::{SharedSingleRunner, SingleRunner};
-
-// /// Wraps an [`SingleRunner`] with auto-reload capabilities.
-// ///
-// /// Will auto-reload the JS code, and re-initialize the runner on changes.
-// #[derive(Clone)]
-// pub struct WatchRunner {
-//     state: Arc<State>,
-// }
-
-// struct State {
-//     js_path: PathBuf,
-//     handler: Box<dyn RequestHandler>,
-//     max_js_threads: usize,
-//     mutable: Mutex<Option<MutableState>>,
-// }
-
-// struct MutableState {
-//     contents: String,
-//     runner: SharedSingleRunner,
-// }
-
-// // TODO: support watching modules. This is complicated by the fact that we must
-// // watch all modules loaded in after the entry module was loaded as well.
-// impl WatchRunner {
-//     pub fn new(
-//         handler: Box<dyn RequestHandler>,
-//         js_path: PathBuf,
-//         script_mode: bool,
-//         max_js_threads: usize,
-//     ) -> anyhow::Result<Self> {
-//         if !script_mode {
-//             bail!("Watch mode is not compatible with module mode yet. Use --script to run in script mode instead.");
-//         }
-//         Ok(Self {
-//             state: Arc::new(State {
-//                 handler,
-//                 js_path,
-//                 max_js_threads,
-//                 mutable: Mutex::new(None),
-//             }),
-//         })
-//     }
-
-//     async fn acquire_runner(&self) -> Result<SharedSingleRunner, anyhow::Error> {
-//         // WASIX does not support inotify file watching APIs, so we naively
-//         // load the full file contents on every request.
-//         //
-//         // This is slow, but this should be sufficient for debugging.
-//         //
-//         // We should investigate just going by the file modification time.
-//         // but loading the full contents is more reliable.
-
-//         let mut mutable = self.state.mutable.lock().await;
-
-//         let (contents, file_name) = match UserCode::from_path(&self.state.js_path, true)
-//             .await
-//             .unwrap()
-//         {
-//             UserCode::Script { code, file_name } => (code, file_name),
-//             _ => unreachable!(),
-//         };
-
-//         if let Some(mutable) = mutable.as_mut() {
-//             if mutable.contents == contents {
-//                 return Ok(mutable.runner.clone());
-//             }
-//         }
-
-//         tracing::info!(path=%self.state.js_path.display(), "reloaded application code");
-
-//         let runner = SingleRunner::new_request_handler(
-//             self.state.handler.clone(),
-//             self.state.max_js_threads,
-//             UserCode::Script {
-//                 code: contents.clone(),
-//                 file_name,
-//             },
-//         );
-//         *mutable = Some(MutableState {
-//             contents,
-//             runner: runner.clone(),
-//         });
-
-//         Ok(runner)
-//     }
-// }
-
-// #[async_trait::async_trait]
-// impl crate::server::Runner for WatchRunner {
-//     async fn handle(
-//         &self,
-//         addr: std::net::SocketAddr,
-//         req: http::request::Parts,
-//         body: hyper::Body,
-//     ) -> Result<hyper::Response<hyper::Body>, anyhow::Error> {
-//         let runner = self.acquire_runner().await?;
-//         runner.handle(addr, req, body).await
-//     }
-// }
+//! Watch mode for local development. We keep track of every file the user
+//! code was loaded from, and reload the code whenever one of them changes.
+//! For modules, that's the entry module plus everything the module loader
+//! resolves from it, which the workers report as they load them. For
+//! directories, it's every file under the directory.
+//!
+//! On Linux, inotify tells us when something in the watched directories
+//! changes. WASIX has no way of getting notified, so there we check on the
+//! files periodically instead. Either way, a reload that fails (say,
+//! because of a syntax error) is logged, and the previous version of the
+//! code keeps serving requests until the next change.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, SystemTime},
+};
+
+use ion::{
+    module::{ModuleLoader, ModuleRequest},
+    Context, Object, ResultExc, Value,
+};
+use mozjs::jsapi::JSObject;
+use parking_lot::Mutex;
+use tokio::sync::Notify;
+
+use crate::{
+    request_handlers::{UserCode, UserCodeSource},
+    server::BoxedDynRunner,
+    stencil_cache::{module_path, CachingLoader},
+};
+
+/// How long to wait for more changes before reloading, since editors often
+/// write a file in several steps.
+const DEBOUNCE_DELAY: Duration = Duration::from_millis(100);
+
+#[cfg(not(target_os = "linux"))]
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+lazy_static::lazy_static! {
+    static ref LOADED_MODULES: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+    static ref MODULE_LOADED: Notify = Notify::new();
+}
+
+/// Whether workers should load modules through [`TrackingLoader`]. Only set
+/// in watch mode, so other servers don't pay for tracking modules.
+static TRACK_MODULES: AtomicBool = AtomicBool::new(false);
+
+/// Makes workers started from now on report the modules they load. Has to
+/// be called before the runner starts its workers.
+pub fn enable_module_tracking() {
+    TRACK_MODULES.store(true, Ordering::Relaxed);
+}
+
+pub(super) fn is_tracking_modules() -> bool {
+    TRACK_MODULES.load(Ordering::Relaxed)
+}
+
+/// Forgets the modules loaded by the previous version of the user code.
+/// Called when reloading; the new workers report the modules they load
+/// again, so files the code no longer imports aren't watched anymore.
+pub(super) fn forget_loaded_modules() {
+    LOADED_MODULES.lock().clear();
+}
+
+fn record_module(path: PathBuf) {
+    let path = std::fs::canonicalize(&path).unwrap_or(path);
+    if LOADED_MODULES.lock().insert(path) {
+        MODULE_LOADED.notify_one();
+    }
+}
+
+/// The module loader used by the workers in watch mode. It records the file
+/// of every module it resolves, so we know which files to watch.
+#[derive(Default)]
+pub(super) struct TrackingLoader(CachingLoader);
+
+impl ModuleLoader for TrackingLoader {
+    fn resolve(
+        &mut self,
+        cx: &Context,
+        private: &Value,
+        request: &ModuleRequest,
+    ) -> ResultExc<*mut JSObject> {
+        // Record the file before loading it, so we watch files that fail
+        // to load as well
+        if let Some(path) = module_path(cx, private, request) {
+            record_module(path);
+        }
+        self.0.resolve(cx, private, request)
+    }
+
+    fn register(
+        &mut self,
+        cx: &Context,
+        module: *mut JSObject,
+        request: &ModuleRequest,
+    ) -> ResultExc<*mut JSObject> {
+        self.0.register(cx, module, request)
+    }
+
+    fn metadata(&self, cx: &Context, private: &Value, meta: &mut Object) -> ResultExc<()> {
+        self.0.metadata(cx, private, meta)
+    }
+}
+
+/// The modification time and size of each watched file, or `None` for
+/// files that don't exist (anymore).
+struct Snapshot {
+    files: HashMap<PathBuf, Option<(SystemTime, u64)>>,
+}
+
+impl Snapshot {
+    fn take(code: &UserCode, source: &UserCodeSource) -> Self {
+        let paths = match code {
+            UserCode::Directory(dir) => {
+                let mut paths = vec![];
+                walk_dir(dir, &mut |path| paths.push(path.to_path_buf()));
+                paths
+            }
+            UserCode::Module(entry) => {
+                let mut paths = vec![entry.clone()];
+                paths.extend(LOADED_MODULES.lock().iter().cloned());
+                paths
+            }
+            UserCode::Script { .. } => vec![source.path.clone()],
+        };
+
+        let files = paths
+            .into_iter()
+            .map(|path| {
+                let metadata = std::fs::metadata(&path)
+                    .ok()
+                    .map(|m| (m.modified().unwrap_or(SystemTime::UNIX_EPOCH), m.len()));
+                (path, metadata)
+            })
+            .collect();
+        Self { files }
+    }
+
+    /// Returns whether any file we knew about has changed. Files we didn't
+    /// know about yet are modules that were just loaded, unless we're
+    /// watching a whole directory, in which case they're new files.
+    fn has_changes(&self, newer: &Self, directory: bool) -> bool {
+        let changed = newer
+            .files
+            .iter()
+            .any(|(path, metadata)| match self.files.get(path) {
+                Some(old) => old != metadata,
+                None => directory,
+            });
+        let removed = directory && self.files.keys().any(|p| !newer.files.contains_key(p));
+        changed || removed
+    }
+
+    /// The directories to watch for changes to the files.
+    #[cfg(target_os = "linux")]
+    fn directories(&self, code: &UserCode) -> BTreeSet<PathBuf> {
+        let mut dirs = BTreeSet::new();
+        if let UserCode::Directory(dir) = code {
+            dirs.insert(dir.clone());
+        }
+        for path in self.files.keys() {
+            if let Some(parent) = path.parent() {
+                dirs.insert(parent.to_path_buf());
+            }
+        }
+        dirs
+    }
+}
+
+fn walk_dir(dir: &Path, f: &mut impl FnMut(&Path)) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        match entry.file_type() {
+            Ok(t) if t.is_dir() => walk_dir(&path, f),
+            Ok(_) => f(&path),
+            Err(_) => (),
+        }
+    }
+}
+
+/// Watches the files the user code was loaded from and reloads the runner
+/// when they change. Runs until the server shuts down.
+pub async fn watch_for_changes(runner: BoxedDynRunner, source: UserCodeSource) {
+    let code = match source.load() {
+        Ok(code) => code,
+        Err(e) => {
+            tracing::error!(error = format!("{e:#}"), "Failed to watch for changes");
+            return;
+        }
+    };
+    let directory = matches!(code, UserCode::Directory(_));
+
+    let mut changes = match Changes::new() {
+        Ok(changes) => changes,
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to watch for changes");
+            return;
+        }
+    };
+
+    tracing::info!(path = %source.path.display(), "Watching for changes");
+    let mut snapshot = Snapshot::take(&code, &source);
+    loop {
+        #[cfg(target_os = "linux")]
+        changes.watch(&snapshot.directories(&code));
+
+        tokio::select! {
+            _ = changes.next() => (),
+            // Newly loaded modules need watching too
+            _ = MODULE_LOADED.notified() => (),
+        }
+        tokio::time::sleep(DEBOUNCE_DELAY).await;
+        changes.clear();
+
+        let newer = Snapshot::take(&code, &source);
+        let changed = snapshot.has_changes(&newer, directory);
+        snapshot = newer;
+        if changed {
+            tracing::info!("Change detected, reloading");
+            // Errors are logged, and the previous version keeps serving
+            _ = crate::server::admin::reload(runner.clone()).await;
+        }
+    }
+}
+
+/// Notifies us of changes in the watched directories through inotify.
+#[cfg(target_os = "linux")]
+struct Changes {
+    fd: tokio::io::unix::AsyncFd<std::os::fd::OwnedFd>,
+    watched: BTreeSet<PathBuf>,
+}
+
+#[cfg(target_os = "linux")]
+impl Changes {
+    fn new() -> std::io::Result<Self> {
+        use std::os::fd::FromRawFd;
+
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let fd = unsafe { std::os::fd::OwnedFd::from_raw_fd(fd) };
+        Ok(Self {
+            fd: tokio::io::unix::AsyncFd::new(fd)?,
+            watched: BTreeSet::new(),
+        })
+    }
+
+    fn watch(&mut self, dirs: &BTreeSet<PathBuf>) {
+        use std::os::{fd::AsRawFd, unix::ffi::OsStrExt};
+
+        let unwatched = dirs.difference(&self.watched).cloned().collect::<Vec<_>>();
+        for dir in unwatched {
+            let Ok(path) = std::ffi::CString::new(dir.as_os_str().as_bytes()) else {
+                continue;
+            };
+            let mask = libc::IN_CLOSE_WRITE
+                | libc::IN_MODIFY
+                | libc::IN_CREATE
+                | libc::IN_DELETE
+                | libc::IN_MOVED_FROM
+                | libc::IN_MOVED_TO;
+            let wd = unsafe { libc::inotify_add_watch(self.fd.as_raw_fd(), path.as_ptr(), mask) };
+            if wd == -1 {
+                // The directory may not exist yet; we'll try again next time
+                tracing::debug!(dir = %dir.display(), error = %std::io::Error::last_os_error(), "Failed to watch directory");
+            } else {
+                self.watched.insert(dir);
+            }
+        }
+    }
+
+    /// Waits until something changes.
+    async fn next(&mut self) {
+        loop {
+            let Ok(mut guard) = self.fd.readable().await else {
+                return std::future::pending().await;
+            };
+            if Self::drain(guard.get_inner()) {
+                return;
+            }
+            guard.clear_ready();
+        }
+    }
+
+    /// Discards the pending events, since we take a fresh snapshot anyway.
+    fn clear(&mut self) {
+        Self::drain(self.fd.get_ref());
+    }
+
+    fn drain(fd: &std::os::fd::OwnedFd) -> bool {
+        use std::os::fd::AsRawFd;
+
+        let mut buf = [0u8; 4096];
+        let mut any = false;
+        loop {
+            let read = unsafe { libc::read(fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) };
+            if read <= 0 {
+                return any;
+            }
+            any = true;
+        }
+    }
+}
+
+/// Checks on the files periodically, where inotify isn't available.
+#[cfg(not(target_os = "linux"))]
+struct Changes;
+
+#[cfg(not(target_os = "linux"))]
+impl Changes {
+    fn new() -> std::io::Result<Self> {
+        Ok(Self)
+    }
+
+    async fn next(&mut self) {
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+
+    fn clear(&mut self) {}
+}
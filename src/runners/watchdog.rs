@@ -0,0 +1,343 @@
+//! Time limits for Javascript code. Request handlers only interleave at
+//! await points, so a handler that never yields (say, `while (true) {}`)
+//! would block its worker thread forever. To prevent that, each worker
+//! thread registers a SpiderMonkey interrupt callback. Workers record when
+//! they start running Javascript, and a watchdog thread asks SpiderMonkey to
+//! run the callback on the ones that may have gone past their limits. The
+//! callback terminates the running script once it has gone on for longer
+//! than the configured limits without yielding.
+//!
+//! Terminating a script leaves the worker usable; the request it was
+//! handling can't complete, and is answered by the runner once its wall
+//! time limit runs out (or as soon as we know which request it was).
+
+use std::{
+    cell::RefCell,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Weak,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use mozjs::jsapi::{JSContext, JS_AddInterruptCallback, JS_RequestInterruptCallback};
+use parking_lot::Mutex;
+
+/// How often the watchdog checks whether any worker has gone past its
+/// limits.
+const CHECK_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ExecutionLimits {
+    /// How long a request may take to produce a response. Requests that
+    /// take longer are answered with 504 Gateway Timeout.
+    pub wall_time: Option<Duration>,
+
+    /// How much CPU time Javascript code may use without yielding to the
+    /// event loop. Only enforced on native builds.
+    pub cpu_time: Option<Duration>,
+}
+
+impl ExecutionLimits {
+    fn is_empty(&self) -> bool {
+        self.wall_time.is_none() && self.cpu_time.is_none()
+    }
+}
+
+/// Parses a duration with an optional `ms`, `s` or `m` suffix. Plain numbers
+/// are taken to be seconds.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    // Only look at the end for the unit, so numbers like `1e3` still parse
+    let (number, millis) = [("ms", 1.0), ("s", 1000.0), ("m", 60_000.0)]
+        .into_iter()
+        .find_map(|(unit, millis)| Some((s.strip_suffix(unit)?, millis)))
+        .unwrap_or((s, 1000.0));
+    number
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .map(|n| n * millis)
+        .filter(|ms| ms.is_finite() && *ms >= 0.0)
+        .map(|ms| Duration::from_micros((ms * 1000.0) as u64))
+        .ok_or_else(|| format!("invalid duration '{s}', expected something like 500ms, 30s or 1m"))
+}
+
+pub(super) fn timeout_response() -> hyper::Response<hyper::Body> {
+    hyper::Response::builder()
+        .status(504)
+        .body(hyper::Body::from("The request took too long to complete"))
+        .expect("Failed to construct 504 response")
+}
+
+struct RawContext(*mut JSContext);
+
+// SAFETY: the pointer is only used for JS_RequestInterruptCallback, which
+// SpiderMonkey allows calling from any thread.
+unsafe impl Send for RawContext {}
+
+/// When a worker started running Javascript, in wall and CPU time.
+#[derive(Clone, Copy)]
+struct Busy {
+    started: Instant,
+    cpu_started: Option<Duration>,
+}
+
+/// The part of a worker's state the watchdog thread can see.
+struct Shared {
+    /// Cleared when the worker's context goes away.
+    cx: Mutex<Option<RawContext>>,
+    limits: ExecutionLimits,
+    enforcing: AtomicBool,
+    /// Set while the worker is running Javascript.
+    busy: Mutex<Option<Busy>>,
+}
+
+impl Shared {
+    /// Whether the worker may have gone past one of its limits. A thread
+    /// can't use more CPU time than wall time, so the CPU limit can only
+    /// have run out once that much wall time has passed; the interrupt
+    /// callback checks the actual CPU time.
+    fn may_have_exceeded_limits(&self) -> bool {
+        if !self.enforcing.load(Ordering::Relaxed) {
+            return false;
+        }
+        let Some(busy) = *self.busy.lock() else {
+            return false;
+        };
+
+        let elapsed = busy.started.elapsed();
+        [self.limits.wall_time, self.limits.cpu_time]
+            .into_iter()
+            .flatten()
+            .any(|limit| elapsed > limit)
+    }
+
+    fn set_busy(&self, busy: bool) {
+        *self.busy.lock() = busy.then(|| Busy {
+            started: Instant::now(),
+            cpu_started: thread_cpu_time(),
+        });
+    }
+}
+
+struct ThreadState {
+    shared: Arc<Shared>,
+    terminated: bool,
+}
+
+thread_local! {
+    static STATE: RefCell<Option<ThreadState>> = const { RefCell::new(None) };
+}
+
+lazy_static::lazy_static! {
+    static ref WORKERS: Mutex<Vec<Weak<Shared>>> = {
+        std::thread::Builder::new()
+            .name("winterjs-watchdog".to_string())
+            .spawn(run_watchdog)
+            .expect("Failed to start watchdog thread");
+        Mutex::new(vec![])
+    };
+}
+
+fn run_watchdog() {
+    loop {
+        std::thread::sleep(CHECK_INTERVAL);
+
+        let mut workers = WORKERS.lock();
+        workers.retain(|shared| match shared.upgrade() {
+            Some(shared) => match &*shared.cx.lock() {
+                Some(cx) => {
+                    // Idle workers and ones that are well within their
+                    // limits are left alone, so they don't get interrupted
+                    // for nothing.
+                    if shared.may_have_exceeded_limits() {
+                        unsafe { JS_RequestInterruptCallback(cx.0) };
+                    }
+                    true
+                }
+                None => false,
+            },
+            None => false,
+        });
+    }
+}
+
+/// Keeps the watchdog installed on the current thread's context.
+pub(super) struct Watchdog {
+    shared: Arc<Shared>,
+}
+
+impl Watchdog {
+    /// Installs the watchdog on the current thread's context. Returns `None`
+    /// if there are no limits to enforce.
+    pub fn install(cx: &ion::Context, limits: ExecutionLimits) -> Option<Self> {
+        if limits.is_empty() {
+            return None;
+        }
+
+        let shared = Arc::new(Shared {
+            cx: Mutex::new(Some(RawContext(cx.as_ptr()))),
+            limits,
+            enforcing: AtomicBool::new(false),
+            busy: Mutex::new(None),
+        });
+        unsafe { JS_AddInterruptCallback(cx.as_ptr(), Some(interrupt_callback)) };
+        STATE.with(|state| {
+            *state.borrow_mut() = Some(ThreadState {
+                shared: shared.clone(),
+                terminated: false,
+            })
+        });
+        WORKERS.lock().push(Arc::downgrade(&shared));
+
+        Some(Self { shared })
+    }
+
+    /// Starts enforcing the limits. Initializing the user code is allowed to
+    /// take as long as it needs.
+    pub fn start_enforcing(&self) {
+        self.shared.enforcing.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        *self.shared.cx.lock() = None;
+        STATE.with(|state| {
+            let mut state = state.borrow_mut();
+            if state
+                .as_ref()
+                .is_some_and(|s| Arc::ptr_eq(&s.shared, &self.shared))
+            {
+                *state = None;
+            }
+        });
+    }
+}
+
+fn with_state<R>(f: impl FnOnce(&mut ThreadState) -> R) -> Option<R> {
+    STATE.with(|state| state.borrow_mut().as_mut().map(f))
+}
+
+/// Returns whether the watchdog has terminated a script since the worker
+/// last started running.
+pub(super) fn script_terminated() -> bool {
+    with_state(|state| state.terminated).unwrap_or(false)
+}
+
+/// Called by the worker each time it goes around its loop. A busy worker
+/// can go a long time without actually yielding, so we count each pass
+/// separately.
+pub(super) fn restart_clock() {
+    with_state(|state| {
+        if state.shared.busy.lock().is_some() {
+            state.shared.set_busy(true);
+        }
+    });
+}
+
+/// Wraps the future that runs a worker's Javascript code, so we know how
+/// long it has been running without yielding.
+pub(super) fn watch<F: Future>(future: F) -> Watched<F> {
+    Watched {
+        inner: Box::pin(future),
+    }
+}
+
+pub(super) struct Watched<F> {
+    inner: Pin<Box<F>>,
+}
+
+impl<F: Future> Future for Watched<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        with_state(|state| {
+            state.shared.set_busy(true);
+            state.terminated = false;
+        });
+        let result = self.inner.as_mut().poll(cx);
+        with_state(|state| state.shared.set_busy(false));
+        result
+    }
+}
+
+unsafe extern "C" fn interrupt_callback(_cx: *mut JSContext) -> bool {
+    let exceeded = with_state(|state| {
+        let shared = &state.shared;
+        if !shared.enforcing.load(Ordering::Relaxed) {
+            return None;
+        }
+        let busy = (*shared.busy.lock())?;
+
+        let wall = busy.started.elapsed();
+        if shared.limits.wall_time.is_some_and(|limit| wall > limit) {
+            return Some(format!("{} ms", wall.as_millis()));
+        }
+
+        let cpu = thread_cpu_time()
+            .zip(busy.cpu_started)
+            .map(|(now, started)| now.saturating_sub(started))?;
+        if shared.limits.cpu_time.is_some_and(|limit| cpu > limit) {
+            return Some(format!("{} ms of CPU time", cpu.as_millis()));
+        }
+
+        None
+    })
+    .flatten();
+
+    match exceeded {
+        Some(duration) => {
+            tracing::warn!("Terminating Javascript code that ran for {duration} without yielding");
+            with_state(|state| state.terminated = true);
+            // Returning false terminates the script with an uncatchable error
+            false
+        }
+        None => true,
+    }
+}
+
+#[cfg(not(target_os = "wasi"))]
+fn thread_cpu_time() -> Option<Duration> {
+    let mut time = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    if unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut time) } != 0 {
+        return None;
+    }
+    Some(Duration::new(time.tv_sec as u64, time.tv_nsec as u32))
+}
+
+#[cfg(target_os = "wasi")]
+fn thread_cpu_time() -> Option<Duration> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_duration("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("1m"), Ok(Duration::from_secs(60)));
+        assert_eq!(parse_duration(" 10 "), Ok(Duration::from_secs(10)));
+        assert_eq!(parse_duration("1.5"), Ok(Duration::from_millis(1500)));
+        assert_eq!(parse_duration("0"), Ok(Duration::ZERO));
+        assert_eq!(parse_duration("1e3"), Ok(Duration::from_secs(1000)));
+        assert_eq!(parse_duration("2.5e2ms"), Ok(Duration::from_millis(250)));
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("ms").is_err());
+        assert!(parse_duration("-1s").is_err());
+        assert!(parse_duration("5h").is_err());
+        assert!(parse_duration("5sec").is_err());
+        assert!(parse_duration("inf").is_err());
+        assert!(parse_duration("NaN").is_err());
+    }
+}
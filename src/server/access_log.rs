@@ -0,0 +1,412 @@
+//! Access logging. One line is written per request once the response body
+//! has been sent (or the client went away), so the size and latency cover
+//! the whole response and not just the headers. See
+//! [`super::response_body::ResponseBody`].
+
+use std::{
+    io::Write,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    thread::JoinHandle,
+    time::{Instant, SystemTime},
+};
+
+use anyhow::Context as _;
+use chrono::{DateTime, SecondsFormat, Utc};
+use hyper::Body;
+use tokio::sync::mpsc;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum AccessLogFormat {
+    /// The Apache/nginx combined log format, followed by the latency in
+    /// milliseconds and the worker thread.
+    Combined,
+    /// One JSON object per line.
+    Json,
+}
+
+#[derive(Clone, Debug)]
+pub enum AccessLogOutput {
+    Stdout,
+    File(PathBuf),
+}
+
+impl FromStr for AccessLogOutput {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "" => Err("expected 'stdout' or a file path".to_string()),
+            "-" | "stdout" => Ok(Self::Stdout),
+            path => Ok(Self::File(path.into())),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AccessLogConfig {
+    pub output: AccessLogOutput,
+    pub format: AccessLogFormat,
+}
+
+pub struct AccessLog {
+    format: AccessLogFormat,
+    /// Lines for the writer thread, or `None` to make it stop.
+    lines: mpsc::UnboundedSender<Option<String>>,
+    writer: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl AccessLog {
+    /// Opens the log output and starts the thread that writes to it. Lines
+    /// are handed to that thread so request tasks never block on disk I/O.
+    pub fn open(config: &AccessLogConfig) -> anyhow::Result<Self> {
+        let writer: Box<dyn Write + Send> = match &config.output {
+            AccessLogOutput::Stdout => Box::new(std::io::stdout()),
+            AccessLogOutput::File(path) => Box::new(
+                std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .with_context(|| format!("Failed to open access log '{}'", path.display()))?,
+            ),
+        };
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        let writer = std::thread::Builder::new()
+            .name("access-log".to_string())
+            .spawn(move || write_lines(writer, rx))
+            .context("Failed to start access log writer thread")?;
+
+        Ok(Self {
+            format: config.format,
+            lines: tx,
+            writer: Mutex::new(Some(writer)),
+        })
+    }
+
+    /// Waits for the lines that are still queued to be written, and stops
+    /// the writer thread. Called once the server has stopped handling
+    /// requests; anything logged after this is dropped.
+    pub fn close(&self) {
+        _ = self.lines.send(None);
+        if let Some(writer) = self.writer.lock().unwrap().take() {
+            _ = writer.join();
+        }
+    }
+
+    fn write(&self, entry: &Entry) {
+        let mut line = match self.format {
+            AccessLogFormat::Combined => entry.to_combined(),
+            AccessLogFormat::Json => entry.to_json(),
+        };
+        line.push('\n');
+
+        // Sending only fails if the writer thread is gone, in which case
+        // there's nowhere left to write the line to.
+        _ = self.lines.send(Some(line));
+    }
+}
+
+fn write_lines(mut writer: Box<dyn Write + Send>, mut rx: mpsc::UnboundedReceiver<Option<String>>) {
+    while let Some(Some(line)) = rx.blocking_recv() {
+        // Each line goes out in a single write, so lines from several
+        // processes sharing the file don't get interleaved.
+        if let Err(e) = writer.write_all(line.as_bytes()) {
+            tracing::error!(error = %e, "Failed to write access log");
+        }
+    }
+    if let Err(e) = writer.flush() {
+        tracing::error!(error = %e, "Failed to write access log");
+    }
+}
+
+/// Everything we know about a request before the runner handles it.
+pub struct RequestInfo {
+    time: SystemTime,
+    started: Instant,
+    remote_addr: SocketAddr,
+    client_addr: Option<IpAddr>,
+    method: String,
+    uri: String,
+    version: http::Version,
+    referer: Option<String>,
+    user_agent: Option<String>,
+}
+
+impl RequestInfo {
+    pub fn new(remote_addr: SocketAddr, req: &hyper::Request<Body>) -> Self {
+        let header = |name: http::header::HeaderName| {
+            req.headers()
+                .get(name)
+                .map(|v| String::from_utf8_lossy(v.as_bytes()).into_owned())
+        };
+
+        Self {
+            time: SystemTime::now(),
+            started: Instant::now(),
+            remote_addr,
+            client_addr: req
+                .extensions()
+                .get::<super::ClientAddr>()
+                .map(|addr| addr.0),
+            method: req.method().to_string(),
+            uri: req.uri().to_string(),
+            version: req.version(),
+            referer: header(http::header::REFERER),
+            user_agent: header(http::header::USER_AGENT),
+        }
+    }
+}
+
+struct Entry<'a> {
+    request: &'a RequestInfo,
+    status: u16,
+    size: u64,
+    latency_ms: f64,
+    worker: Option<usize>,
+}
+
+impl Entry<'_> {
+    fn to_combined(&self) -> String {
+        let quoted = |s: &Option<String>| match s {
+            Some(s) => escape(s),
+            None => "-".to_string(),
+        };
+
+        format!(
+            "{} - - [{}] \"{} {} {:?}\" {} {} \"{}\" \"{}\" {:.3} {}",
+            self.request
+                .client_addr
+                .unwrap_or_else(|| self.request.remote_addr.ip()),
+            format_clf_time(self.request.time),
+            escape(&self.request.method),
+            escape(&self.request.uri),
+            self.request.version,
+            self.status,
+            self.size,
+            quoted(&self.request.referer),
+            quoted(&self.request.user_agent),
+            self.latency_ms,
+            self.worker
+                .map(|w| w.to_string())
+                .unwrap_or_else(|| "-".to_string()),
+        )
+    }
+
+    fn to_json(&self) -> String {
+        serde_json::json!({
+            "time": format_rfc3339_time(self.request.time),
+            "remote_addr": self.request.remote_addr.to_string(),
+            "client_addr": self.request.client_addr.map(|addr| addr.to_string()),
+            "method": self.request.method,
+            "uri": self.request.uri,
+            "version": format!("{:?}", self.request.version),
+            "status": self.status,
+            "size": self.size,
+            "latency_ms": self.latency_ms,
+            "worker": self.worker,
+            "referer": self.request.referer,
+            "user_agent": self.request.user_agent,
+        })
+        .to_string()
+    }
+}
+
+/// A log entry waiting for the response body to be sent. The line is
+/// written when this is dropped.
+pub struct PendingEntry {
+    log: Arc<AccessLog>,
+    request: RequestInfo,
+    status: u16,
+    worker: Option<usize>,
+    size: u64,
+}
+
+impl PendingEntry {
+    pub fn new(
+        log: Arc<AccessLog>,
+        request: RequestInfo,
+        response: &hyper::Response<Body>,
+    ) -> Self {
+        Self {
+            log,
+            request,
+            status: response.status().as_u16(),
+            worker: response
+                .extensions()
+                .get::<super::WorkerThreadId>()
+                .map(|w| w.0),
+            size: 0,
+        }
+    }
+
+    pub fn record_data(&mut self, len: usize) {
+        self.size += len as u64;
+    }
+}
+
+impl Drop for PendingEntry {
+    fn drop(&mut self) {
+        let latency_ms = self.request.started.elapsed().as_micros() as f64 / 1000.0;
+        self.log.write(&Entry {
+            request: &self.request,
+            status: self.status,
+            size: self.size,
+            latency_ms,
+            worker: self.worker,
+        });
+    }
+}
+
+/// Escapes quotes, backslashes and control characters, like Apache does.
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn format_clf_time(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time)
+        .format("%d/%b/%Y:%H:%M:%S +0000")
+        .to_string()
+}
+
+fn format_rfc3339_time(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time).to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, time::Duration};
+
+    use super::*;
+
+    fn request() -> RequestInfo {
+        RequestInfo {
+            time: SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            started: Instant::now(),
+            remote_addr: "10.0.0.2:54321".parse().unwrap(),
+            client_addr: Some("203.0.113.7".parse().unwrap()),
+            method: "GET".to_string(),
+            uri: "/search?q=\"a\"".to_string(),
+            version: http::Version::HTTP_11,
+            referer: None,
+            user_agent: Some("curl/8.0".to_string()),
+        }
+    }
+
+    #[test]
+    fn combined_lines() {
+        let request = request();
+        let entry = Entry {
+            request: &request,
+            status: 200,
+            size: 1234,
+            latency_ms: 12.5,
+            worker: Some(2),
+        };
+        assert_eq!(
+            entry.to_combined(),
+            "203.0.113.7 - - [14/Nov/2023:22:13:20 +0000] \"GET /search?q=\\\"a\\\" HTTP/1.1\" \
+             200 1234 \"-\" \"curl/8.0\" 12.500 2"
+        );
+    }
+
+    #[test]
+    fn combined_lines_without_client_addr_or_worker() {
+        let request = RequestInfo {
+            client_addr: None,
+            user_agent: Some("evil\n\"agent\"".to_string()),
+            ..request()
+        };
+        let entry = Entry {
+            request: &request,
+            status: 404,
+            size: 0,
+            latency_ms: 0.25,
+            worker: None,
+        };
+        assert_eq!(
+            entry.to_combined(),
+            "10.0.0.2 - - [14/Nov/2023:22:13:20 +0000] \"GET /search?q=\\\"a\\\" HTTP/1.1\" \
+             404 0 \"-\" \"evil\\x0a\\\"agent\\\"\" 0.250 -"
+        );
+    }
+
+    #[test]
+    fn json_lines() {
+        let request = request();
+        let entry = Entry {
+            request: &request,
+            status: 200,
+            size: 1234,
+            latency_ms: 12.5,
+            worker: Some(2),
+        };
+        let line: serde_json::Value = serde_json::from_str(&entry.to_json()).unwrap();
+        assert_eq!(
+            line,
+            serde_json::json!({
+                "time": "2023-11-14T22:13:20.000Z",
+                "remote_addr": "10.0.0.2:54321",
+                "client_addr": "203.0.113.7",
+                "method": "GET",
+                "uri": "/search?q=\"a\"",
+                "version": "HTTP/1.1",
+                "status": 200,
+                "size": 1234,
+                "latency_ms": 12.5,
+                "worker": 2,
+                "referer": null,
+                "user_agent": "curl/8.0",
+            })
+        );
+    }
+
+    #[test]
+    fn parses_outputs() {
+        assert!(matches!("stdout".parse(), Ok(AccessLogOutput::Stdout)));
+        assert!(matches!("-".parse(), Ok(AccessLogOutput::Stdout)));
+        assert!(matches!(
+            "/var/log/access.log".parse(),
+            Ok(AccessLogOutput::File(path)) if path == Path::new("/var/log/access.log")
+        ));
+        assert!("".parse::<AccessLogOutput>().is_err());
+    }
+
+    #[test]
+    fn close_writes_queued_lines() {
+        let path = std::env::temp_dir().join(format!("winterjs-access-{}.log", std::process::id()));
+        let log = AccessLog::open(&AccessLogConfig {
+            output: AccessLogOutput::File(path.clone()),
+            format: AccessLogFormat::Combined,
+        })
+        .unwrap();
+        let request = request();
+        for status in [200, 404] {
+            log.write(&Entry {
+                request: &request,
+                status,
+                size: 0,
+                latency_ms: 1.0,
+                worker: None,
+            });
+        }
+        log.close();
+
+        let contents = std::fs::read_to_string(&path);
+        _ = std::fs::remove_file(&path);
+        let contents = contents.unwrap();
+        assert_eq!(contents.lines().count(), 2);
+        assert!(contents.lines().next().unwrap().contains("\" 200 0 "));
+    }
+}
@@ -0,0 +1,94 @@
+//! Administrative actions on a running server. For now, that's reloading
+//! the user code, which can be triggered with SIGHUP or through `POST
+//! /reload` on the admin address. Either way, requests keep being served
+//! by the current code until the new code has initialized, and if it fails
+//! to, the current code stays in place.
+
+use std::{convert::Infallible, net::SocketAddr};
+
+use anyhow::Context as _;
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+
+use super::BoxedDynRunner;
+
+pub const RELOAD_PATH: &str = "/reload";
+
+/// Reloads the user code, logging the outcome.
+pub async fn reload(runner: BoxedDynRunner) -> anyhow::Result<()> {
+    // Once started, a reload runs to completion even if whoever asked for
+    // it goes away
+    let result = tokio::spawn(async move { runner.reload().await })
+        .await
+        .unwrap_or_else(|e| Err(anyhow::anyhow!("Reload task failed: {e}")));
+
+    if let Err(e) = &result {
+        tracing::error!(
+            error = format!("{e:#}"),
+            "Failed to reload user code, still serving the previous version"
+        );
+    }
+    result
+}
+
+/// Reloads the user code each time the process receives SIGHUP.
+#[cfg(unix)]
+pub async fn reload_on_sighup(runner: BoxedDynRunner) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut signal = match signal(SignalKind::hangup()) {
+        Ok(s) => s,
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to listen for SIGHUP, reloading on SIGHUP is disabled");
+            return;
+        }
+    };
+
+    while signal.recv().await.is_some() {
+        tracing::info!("Received SIGHUP, reloading user code");
+        _ = reload(runner.clone()).await;
+    }
+}
+
+async fn handle(runner: BoxedDynRunner, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let (status, body) = match (req.method(), req.uri().path()) {
+        (&Method::POST, RELOAD_PATH) => match reload(runner).await {
+            Ok(()) => (StatusCode::OK, "reloaded".to_string()),
+            Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("{e:#}")),
+        },
+        (_, RELOAD_PATH) => (
+            StatusCode::METHOD_NOT_ALLOWED,
+            "Method not allowed".to_string(),
+        ),
+        _ => (StatusCode::NOT_FOUND, "Not found".to_string()),
+    };
+
+    Ok(Response::builder()
+        .status(status)
+        .header(hyper::header::CACHE_CONTROL, "no-store")
+        .body(Body::from(body))
+        .expect("Failed to construct admin response"))
+}
+
+/// Starts serving the admin endpoints on the given address. They are not
+/// authenticated, so the address should not be reachable from outside.
+pub fn start(addr: SocketAddr, runner: BoxedDynRunner) -> anyhow::Result<()> {
+    let make_service = make_service_fn(move |_| {
+        let runner = runner.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |req| handle(runner.clone(), req))) }
+    });
+
+    let server = Server::try_bind(&addr)
+        .with_context(|| format!("Failed to bind admin endpoints to '{addr}'"))?
+        .serve(make_service);
+    tracing::info!("serving admin endpoints on 'http://{addr}'");
+
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            tracing::error!(error = %e, "Admin server failed");
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,69 @@
+//! Helpers for starting WinterJS child processes that share our listening
+//! sockets. The sockets are passed as file descriptors starting at
+//! [`FIRST_FD`], in the order they're given.
+
+use std::{
+    os::unix::{
+        io::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        process::CommandExt,
+    },
+    process::Command,
+};
+
+use anyhow::Context as _;
+
+pub const FIRST_FD: RawFd = 3;
+
+/// Builds a command that runs the current executable with the same
+/// arguments we were started with.
+pub fn winterjs_command() -> anyhow::Result<Command> {
+    let mut command = Command::new(std::env::current_exe().context("Failed to find executable")?);
+    command.args(std::env::args_os().skip(1));
+    Ok(command)
+}
+
+/// Arranges for `fds` to be available in the child process as `FIRST_FD`,
+/// `FIRST_FD + 1` and so on. The returned fds must be kept open until the
+/// child process is spawned.
+pub fn pass_fds(command: &mut Command, fds: &[RawFd]) -> anyhow::Result<Vec<OwnedFd>> {
+    // Duplicate the fds above the target range first, so moving them into
+    // place can't overwrite one we haven't moved yet. The duplicates are
+    // closed on exec.
+    let min_fd = FIRST_FD + fds.len() as RawFd;
+    let sources = fds
+        .iter()
+        .map(|fd| {
+            let dup = unsafe { libc::fcntl(*fd, libc::F_DUPFD_CLOEXEC, min_fd) };
+            if dup == -1 {
+                Err(std::io::Error::last_os_error())
+            } else {
+                // Safety: we just created this
+                Ok(unsafe { OwnedFd::from_raw_fd(dup) })
+            }
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .context("Failed to duplicate file descriptors")?;
+    let source_fds = sources.iter().map(|fd| fd.as_raw_fd()).collect::<Vec<_>>();
+
+    unsafe {
+        command.pre_exec(move || {
+            // Only async-signal-safe calls are allowed here. dup2 also clears
+            // the close-on-exec flag on the new fd.
+            for (i, fd) in source_fds.iter().enumerate() {
+                if libc::dup2(*fd, FIRST_FD + i as RawFd) == -1 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+
+    Ok(sources)
+}
+
+pub fn set_cloexec(fd: RawFd) -> anyhow::Result<()> {
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+        return Err(std::io::Error::last_os_error()).context("Failed to set close-on-exec flag");
+    }
+    Ok(())
+}
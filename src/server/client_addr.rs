@@ -0,0 +1,280 @@
+//! Working out which client a request came from. Behind a reverse proxy,
+//! the peer address is the proxy's own, so for requests from trusted
+//! proxies we take the client address from `X-Forwarded-For` instead.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+use http::HeaderMap;
+
+/// A proxy whose `X-Forwarded-For` header we believe.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TrustedProxy {
+    /// A single address or a network in CIDR notation.
+    Network { addr: IpAddr, prefix_len: u8 },
+
+    /// Any peer connecting through a Unix domain socket.
+    Unix,
+}
+
+impl TrustedProxy {
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self, ip) {
+            (Self::Unix, ip) => ip.is_unspecified(),
+            (Self::Network { addr, prefix_len }, ip) => match (addr, ip) {
+                (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                    let mask = u32::MAX.checked_shl(32 - *prefix_len as u32).unwrap_or(0);
+                    u32::from(*net) & mask == u32::from(ip) & mask
+                }
+                (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                    let mask = u128::MAX.checked_shl(128 - *prefix_len as u32).unwrap_or(0);
+                    u128::from(*net) & mask == u128::from(ip) & mask
+                }
+                _ => false,
+            },
+        }
+    }
+}
+
+impl FromStr for TrustedProxy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "unix" {
+            return Ok(Self::Unix);
+        }
+
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|_| {
+                format!(
+                    "invalid address '{s}', expected an IP address, \
+                    a network like 10.0.0.0/8 or 'unix'"
+                )
+            })?
+            .to_canonical();
+        let max_prefix_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse::<u8>()
+                .ok()
+                .filter(|len| *len <= max_prefix_len)
+                .ok_or_else(|| format!("invalid prefix length in '{s}'"))?,
+            None => max_prefix_len,
+        };
+
+        Ok(Self::Network { addr, prefix_len })
+    }
+}
+
+impl std::fmt::Display for TrustedProxy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Network { addr, prefix_len } => write!(f, "{addr}/{prefix_len}"),
+            Self::Unix => write!(f, "unix"),
+        }
+    }
+}
+
+/// Returns the address of the client, or `None` if we don't know it, as is
+/// the case for requests coming in on Unix domain sockets without a
+/// trusted proxy in front.
+///
+/// `X-Forwarded-For` is read from right to left, since each proxy appends
+/// the address it got the request from; the first address that isn't a
+/// trusted proxy is the client. Anything to the left of that could have
+/// been made up by the client.
+pub fn resolve(
+    peer: SocketAddr,
+    headers: &HeaderMap,
+    trusted_proxies: &[TrustedProxy],
+) -> Option<IpAddr> {
+    let is_trusted = |ip: IpAddr| trusted_proxies.iter().any(|proxy| proxy.contains(ip));
+
+    let mut client = peer.ip().to_canonical();
+    if is_trusted(client) {
+        let forwarded = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .rev()
+            .flat_map(|value| value.to_str().unwrap_or_default().rsplit(','));
+        for entry in forwarded {
+            let Some(ip) = parse_forwarded_addr(entry.trim()) else {
+                break;
+            };
+            client = ip;
+            if !is_trusted(ip) {
+                break;
+            }
+        }
+    }
+
+    (!client.is_unspecified()).then_some(client)
+}
+
+fn parse_forwarded_addr(s: &str) -> Option<IpAddr> {
+    // Some proxies include the port
+    s.parse::<IpAddr>()
+        .ok()
+        .or_else(|| s.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .map(|ip| ip.to_canonical())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(forwarded: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in forwarded {
+            headers.append("x-forwarded-for", value.parse().unwrap());
+        }
+        headers
+    }
+
+    fn proxies(proxies: &[&str]) -> Vec<TrustedProxy> {
+        proxies.iter().map(|proxy| proxy.parse().unwrap()).collect()
+    }
+
+    fn resolve_str(peer: &str, forwarded: &[&str], trusted: &[&str]) -> Option<String> {
+        resolve(
+            peer.parse().unwrap(),
+            &headers(forwarded),
+            &proxies(trusted),
+        )
+        .map(|ip| ip.to_string())
+    }
+
+    const UNIX_PEER: &str = "0.0.0.0:0";
+
+    #[test]
+    fn parses_trusted_proxies() {
+        assert_eq!(
+            "10.0.0.0/8".parse(),
+            Ok(TrustedProxy::Network {
+                addr: "10.0.0.0".parse().unwrap(),
+                prefix_len: 8
+            })
+        );
+        assert_eq!(
+            "::1".parse(),
+            Ok(TrustedProxy::Network {
+                addr: "::1".parse().unwrap(),
+                prefix_len: 128
+            })
+        );
+        assert_eq!(
+            "::ffff:10.1.2.3".parse(),
+            Ok(TrustedProxy::Network {
+                addr: "10.1.2.3".parse().unwrap(),
+                prefix_len: 32
+            })
+        );
+        assert_eq!("unix".parse(), Ok(TrustedProxy::Unix));
+        assert!("10.0.0.0/33".parse::<TrustedProxy>().is_err());
+        assert!("10.0.0.0/".parse::<TrustedProxy>().is_err());
+        assert!("localhost".parse::<TrustedProxy>().is_err());
+    }
+
+    #[test]
+    fn uses_peer_address_without_trusted_proxies() {
+        assert_eq!(
+            resolve_str("192.0.2.1:4000", &["203.0.113.7"], &[]),
+            Some("192.0.2.1".to_string())
+        );
+    }
+
+    #[test]
+    fn ignores_forwarded_for_from_untrusted_peers() {
+        assert_eq!(
+            resolve_str("192.0.2.1:4000", &["203.0.113.7"], &["10.0.0.0/8"]),
+            Some("192.0.2.1".to_string())
+        );
+    }
+
+    #[test]
+    fn takes_client_from_trusted_proxy() {
+        assert_eq!(
+            resolve_str("10.0.0.1:4000", &["203.0.113.7"], &["10.0.0.0/8"]),
+            Some("203.0.113.7".to_string())
+        );
+    }
+
+    #[test]
+    fn skips_trusted_proxies_in_chain() {
+        assert_eq!(
+            resolve_str(
+                "10.0.0.1:4000",
+                &["203.0.113.7, 10.0.0.3", "10.0.0.2"],
+                &["10.0.0.0/8"]
+            ),
+            Some("203.0.113.7".to_string())
+        );
+    }
+
+    #[test]
+    fn ignores_addresses_left_of_the_client() {
+        // The client can put whatever it wants in the header before the
+        // first proxy appends to it
+        assert_eq!(
+            resolve_str(
+                "10.0.0.1:4000",
+                &["10.0.0.5, 198.51.100.1, 203.0.113.7"],
+                &["10.0.0.0/8"]
+            ),
+            Some("203.0.113.7".to_string())
+        );
+    }
+
+    #[test]
+    fn stops_at_invalid_entries() {
+        assert_eq!(
+            resolve_str(
+                "10.0.0.1:4000",
+                &["203.0.113.7, unknown, 10.0.0.2"],
+                &["10.0.0.0/8"]
+            ),
+            Some("10.0.0.2".to_string())
+        );
+    }
+
+    #[test]
+    fn accepts_forwarded_addresses_with_ports() {
+        assert_eq!(
+            resolve_str("10.0.0.1:4000", &["203.0.113.7:1234"], &["10.0.0.1"]),
+            Some("203.0.113.7".to_string())
+        );
+        assert_eq!(
+            resolve_str("10.0.0.1:4000", &["[2001:db8::1]:443"], &["10.0.0.1"]),
+            Some("2001:db8::1".to_string())
+        );
+    }
+
+    #[test]
+    fn canonicalizes_mapped_ipv4_addresses() {
+        assert_eq!(
+            resolve_str(
+                "[::ffff:10.0.0.1]:4000",
+                &["::ffff:203.0.113.7"],
+                &["10.0.0.0/8"]
+            ),
+            Some("203.0.113.7".to_string())
+        );
+    }
+
+    #[test]
+    fn unix_socket_peers() {
+        assert_eq!(resolve_str(UNIX_PEER, &["203.0.113.7"], &[]), None);
+        assert_eq!(
+            resolve_str(UNIX_PEER, &["203.0.113.7"], &["unix"]),
+            Some("203.0.113.7".to_string())
+        );
+        assert_eq!(resolve_str(UNIX_PEER, &[], &["unix"]), None);
+    }
+}
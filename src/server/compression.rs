@@ -0,0 +1,563 @@
+//! Compression of responses produced by the Javascript code. The encoding
+//! is picked based on the request's `Accept-Encoding` header, and bodies
+//! are compressed as they stream out, so streamed responses keep flowing:
+//! whatever has been compressed so far is flushed whenever the body has no
+//! more data ready.
+
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use futures::Stream;
+use hyper::{
+    body::HttpBody,
+    header::{self, HeaderMap, HeaderValue},
+    Body, Response, StatusCode,
+};
+
+pub const DEFAULT_MIN_SIZE: u64 = 1024;
+
+/// Content types that are almost always compressed already, which are
+/// skipped unless other types are configured.
+pub const DEFAULT_SKIP_CONTENT_TYPES: &[&str] = &[
+    "image/png",
+    "image/jpeg",
+    "image/gif",
+    "image/webp",
+    "image/avif",
+    "audio/*",
+    "video/*",
+    "font/woff",
+    "font/woff2",
+    "application/gzip",
+    "application/zip",
+    "application/zstd",
+    "application/x-7z-compressed",
+    "application/x-bzip2",
+    "application/x-rar-compressed",
+];
+
+/// Compressed output is sent on once this much has built up, even if the
+/// body still has more data ready.
+const MAX_BUFFERED: usize = 64 * 1024;
+
+// Responses are compressed on the fly, so these favor speed over size
+const GZIP_LEVEL: u32 = 6;
+const BROTLI_QUALITY: u32 = 4;
+const BROTLI_WINDOW_BITS: u32 = 22;
+const ZSTD_LEVEL: i32 = 3;
+
+#[derive(Clone, Debug)]
+pub struct CompressionConfig {
+    /// Responses known to be smaller than this aren't worth compressing.
+    pub min_size: u64,
+
+    /// Content types not to compress, either exact (`application/zip`) or
+    /// whole families (`image/*`).
+    pub skip_content_types: Vec<String>,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            min_size: DEFAULT_MIN_SIZE,
+            skip_content_types: DEFAULT_SKIP_CONTENT_TYPES
+                .iter()
+                .map(|t| t.to_string())
+                .collect(),
+        }
+    }
+}
+
+impl CompressionConfig {
+    fn should_compress(&self, res: &Response<Body>) -> bool {
+        let status = res.status();
+        if status.is_informational()
+            || matches!(
+                status,
+                StatusCode::NO_CONTENT | StatusCode::PARTIAL_CONTENT | StatusCode::NOT_MODIFIED
+            )
+        {
+            return false;
+        }
+
+        let headers = res.headers();
+        if headers.contains_key(header::CONTENT_ENCODING)
+            || headers.contains_key(header::CONTENT_RANGE)
+        {
+            return false;
+        }
+        if header_contains(headers, header::CACHE_CONTROL, "no-transform") {
+            return false;
+        }
+
+        let size = HttpBody::size_hint(res.body()).exact().or_else(|| {
+            headers
+                .get(header::CONTENT_LENGTH)
+                .and_then(|len| len.to_str().ok()?.parse().ok())
+        });
+        if size.is_some_and(|size| size < self.min_size) {
+            return false;
+        }
+
+        let content_type = headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|t| t.to_str().ok())
+            .and_then(|t| t.split(';').next())
+            .map(|t| t.trim().to_ascii_lowercase())
+            .unwrap_or_default();
+        !self
+            .skip_content_types
+            .iter()
+            .any(|skip| match skip.strip_suffix('*') {
+                Some(prefix) => content_type.starts_with(&prefix.to_ascii_lowercase()),
+                None => content_type.eq_ignore_ascii_case(skip),
+            })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    Brotli,
+    Zstd,
+    Gzip,
+}
+
+impl Encoding {
+    /// In order of preference, when the client accepts several equally.
+    const ALL: [Self; 3] = [Self::Brotli, Self::Zstd, Self::Gzip];
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Brotli => "br",
+            Self::Zstd => "zstd",
+            Self::Gzip => "gzip",
+        }
+    }
+}
+
+/// Picks the encoding to use based on the request's `Accept-Encoding`
+/// header, or `None` if the client doesn't accept any we support.
+pub fn negotiate(headers: &HeaderMap) -> Option<Encoding> {
+    let mut accepted = HashMap::new();
+    let mut wildcard = None;
+    let values = headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','));
+    for value in values {
+        let mut params = value.split(';');
+        let name = params
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        let quality = params
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+
+        match name.as_str() {
+            "br" => _ = accepted.insert(Encoding::Brotli, quality),
+            "zstd" => _ = accepted.insert(Encoding::Zstd, quality),
+            "gzip" | "x-gzip" => _ = accepted.insert(Encoding::Gzip, quality),
+            "*" => wildcard = Some(quality),
+            _ => (),
+        }
+    }
+
+    let mut best = None;
+    for encoding in Encoding::ALL {
+        let quality = accepted.get(&encoding).copied().or(wildcard).unwrap_or(0.0);
+        if quality > 0.0 && !best.is_some_and(|(_, best)| quality <= best) {
+            best = Some((encoding, quality));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+/// Compresses the response with the given encoding, if it's worth
+/// compressing at all. `encoding` is `None` when the client didn't accept
+/// any encoding we support.
+pub fn compress(
+    config: &CompressionConfig,
+    encoding: Option<Encoding>,
+    mut res: Response<Body>,
+) -> Response<Body> {
+    if !config.should_compress(&res) {
+        return res;
+    }
+
+    // Whether or not we compress, the response depends on Accept-Encoding
+    let headers = res.headers_mut();
+    if !header_contains(headers, header::VARY, "accept-encoding")
+        && !header_contains(headers, header::VARY, "*")
+    {
+        headers.append(header::VARY, HeaderValue::from_static("accept-encoding"));
+    }
+
+    let Some(encoding) = encoding else {
+        return res;
+    };
+    let encoder = match Encoder::new(encoding) {
+        Ok(encoder) => encoder,
+        Err(e) => {
+            tracing::warn!(error = %e, "Failed to set up response compression");
+            return res;
+        }
+    };
+
+    let (mut parts, body) = res.into_parts();
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.insert(
+        header::CONTENT_ENCODING,
+        HeaderValue::from_static(encoding.name()),
+    );
+    // A strong ETag promises byte-for-byte identical bodies, which the
+    // compressed body isn't
+    if let Some(etag) = parts.headers.get(header::ETAG) {
+        if !etag.as_bytes().starts_with(b"W/") {
+            let mut weak = b"W/".to_vec();
+            weak.extend_from_slice(etag.as_bytes());
+            if let Ok(weak) = HeaderValue::from_bytes(&weak) {
+                parts.headers.insert(header::ETAG, weak);
+            }
+        }
+    }
+
+    let body = CompressedBody {
+        body,
+        encoder: Some(encoder),
+        unflushed: false,
+    };
+    Response::from_parts(parts, Body::wrap_stream(body))
+}
+
+fn header_contains(headers: &HeaderMap, name: header::HeaderName, token: &str) -> bool {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|value| {
+            value
+                .split('=')
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .eq_ignore_ascii_case(token)
+        })
+}
+
+enum Encoder {
+    Gzip(flate2::write::GzEncoder<Vec<u8>>),
+    Brotli(Box<brotli::CompressorWriter<Vec<u8>>>),
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+}
+
+impl Encoder {
+    fn new(encoding: Encoding) -> io::Result<Self> {
+        Ok(match encoding {
+            Encoding::Gzip => Self::Gzip(flate2::write::GzEncoder::new(
+                vec![],
+                flate2::Compression::new(GZIP_LEVEL),
+            )),
+            Encoding::Brotli => Self::Brotli(Box::new(brotli::CompressorWriter::new(
+                vec![],
+                4096,
+                BROTLI_QUALITY,
+                BROTLI_WINDOW_BITS,
+            ))),
+            Encoding::Zstd => Self::Zstd(zstd::stream::write::Encoder::new(vec![], ZSTD_LEVEL)?),
+        })
+    }
+
+    fn writer(&mut self) -> &mut dyn Write {
+        match self {
+            Self::Gzip(e) => e,
+            Self::Brotli(e) => e.as_mut(),
+            Self::Zstd(e) => e,
+        }
+    }
+
+    fn output(&mut self) -> &mut Vec<u8> {
+        match self {
+            Self::Gzip(e) => e.get_mut(),
+            Self::Brotli(e) => e.get_mut(),
+            Self::Zstd(e) => e.get_mut(),
+        }
+    }
+
+    fn take_output(&mut self) -> Bytes {
+        std::mem::take(self.output()).into()
+    }
+
+    /// Compresses the data, keeping the output for later.
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.writer().write_all(data)
+    }
+
+    /// Returns everything compressed so far, so the client can decompress
+    /// all the data written until now.
+    fn flush(&mut self) -> io::Result<Bytes> {
+        self.writer().flush()?;
+        Ok(self.take_output())
+    }
+
+    fn finish(self) -> io::Result<Bytes> {
+        let output = match self {
+            Self::Gzip(e) => e.finish()?,
+            Self::Brotli(e) => e.into_inner(),
+            Self::Zstd(e) => e.finish()?,
+        };
+        Ok(output.into())
+    }
+}
+
+struct CompressedBody {
+    body: Body,
+    encoder: Option<Encoder>,
+
+    /// Whether data has been written since the last flush.
+    unflushed: bool,
+}
+
+impl Stream for CompressedBody {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            let Some(encoder) = this.encoder.as_mut() else {
+                return Poll::Ready(None);
+            };
+
+            match Pin::new(&mut this.body).poll_data(cx) {
+                Poll::Ready(Some(Ok(data))) => {
+                    if let Err(e) = encoder.write(&data) {
+                        this.encoder = None;
+                        return Poll::Ready(Some(Err(e)));
+                    }
+                    this.unflushed = true;
+                    if encoder.output().len() >= MAX_BUFFERED {
+                        return Poll::Ready(Some(Ok(encoder.take_output())));
+                    }
+                }
+                Poll::Ready(Some(Err(e))) => {
+                    this.encoder = None;
+                    return Poll::Ready(Some(Err(io::Error::other(e))));
+                }
+                Poll::Ready(None) => {
+                    let encoder = this.encoder.take().expect("encoder is present");
+                    return Poll::Ready(Some(encoder.finish()));
+                }
+                Poll::Pending => {
+                    // Don't hold back what we have while waiting for more
+                    if !this.unflushed {
+                        return Poll::Pending;
+                    }
+                    this.unflushed = false;
+                    return match encoder.flush() {
+                        Ok(output) if output.is_empty() => Poll::Pending,
+                        Ok(output) => Poll::Ready(Some(Ok(output))),
+                        Err(e) => {
+                            this.encoder = None;
+                            Poll::Ready(Some(Err(e)))
+                        }
+                    };
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    fn accept(values: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(header::ACCEPT_ENCODING, value.parse().unwrap());
+        }
+        headers
+    }
+
+    fn response(content_type: &str, body: impl Into<Body>) -> Response<Body> {
+        Response::builder()
+            .header(header::CONTENT_TYPE, content_type)
+            .body(body.into())
+            .unwrap()
+    }
+
+    fn decode(encoding: &str, data: &[u8]) -> Vec<u8> {
+        let mut decoded = vec![];
+        match encoding {
+            "gzip" => flate2::read::GzDecoder::new(data).read_to_end(&mut decoded),
+            "br" => brotli::Decompressor::new(data, 4096).read_to_end(&mut decoded),
+            "zstd" => zstd::stream::read::Decoder::new(data)
+                .and_then(|mut decoder| decoder.read_to_end(&mut decoded)),
+            _ => panic!("unexpected encoding {encoding}"),
+        }
+        .unwrap();
+        decoded
+    }
+
+    #[test]
+    fn negotiates_nothing_without_supported_encodings() {
+        assert_eq!(negotiate(&accept(&[])), None);
+        assert_eq!(negotiate(&accept(&["identity"])), None);
+        assert_eq!(negotiate(&accept(&["deflate, compress"])), None);
+    }
+
+    #[test]
+    fn prefers_brotli_then_zstd_then_gzip() {
+        assert_eq!(negotiate(&accept(&["gzip"])), Some(Encoding::Gzip));
+        assert_eq!(negotiate(&accept(&["x-gzip"])), Some(Encoding::Gzip));
+        assert_eq!(negotiate(&accept(&["gzip, zstd"])), Some(Encoding::Zstd));
+        assert_eq!(
+            negotiate(&accept(&["gzip, deflate, br, zstd"])),
+            Some(Encoding::Brotli)
+        );
+    }
+
+    #[test]
+    fn honors_quality_values() {
+        assert_eq!(
+            negotiate(&accept(&["br;q=0.5, gzip;q=1.0"])),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(
+            negotiate(&accept(&["br; q=0.2", "zstd ;q=0.8"])),
+            Some(Encoding::Zstd)
+        );
+        assert_eq!(negotiate(&accept(&["br;q=0, gzip"])), Some(Encoding::Gzip));
+        assert_eq!(negotiate(&accept(&["gzip;q=0"])), None);
+        // Invalid quality values count as 1
+        assert_eq!(
+            negotiate(&accept(&["gzip;q=abc, br;q=0.9"])),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(negotiate(&accept(&[" GZip ; Q=0.5"])), Some(Encoding::Gzip));
+    }
+
+    #[test]
+    fn applies_wildcard_to_unlisted_encodings() {
+        assert_eq!(negotiate(&accept(&["*"])), Some(Encoding::Brotli));
+        assert_eq!(
+            negotiate(&accept(&["br;q=0, *;q=0.5"])),
+            Some(Encoding::Zstd)
+        );
+        assert_eq!(negotiate(&accept(&["gzip, *;q=0"])), Some(Encoding::Gzip));
+        assert_eq!(negotiate(&accept(&["*;q=0"])), None);
+    }
+
+    #[tokio::test]
+    async fn compresses_with_each_encoding() {
+        let text = "hello, compressed world! ".repeat(200);
+        for encoding in Encoding::ALL {
+            let res = compress(
+                &CompressionConfig::default(),
+                Some(encoding),
+                response("text/plain; charset=utf-8", text.clone()),
+            );
+
+            let headers = res.headers();
+            assert_eq!(headers[header::CONTENT_ENCODING], encoding.name());
+            assert_eq!(headers[header::VARY], "accept-encoding");
+            assert!(!headers.contains_key(header::CONTENT_LENGTH));
+
+            let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+            assert!(body.len() < text.len());
+            assert_eq!(decode(encoding.name(), &body), text.as_bytes());
+        }
+    }
+
+    #[tokio::test]
+    async fn compresses_streamed_bodies() {
+        let chunks = ["first chunk ", "second chunk ", "third chunk"];
+        let body = Body::wrap_stream(futures::stream::iter(
+            chunks.map(|chunk| Ok::<_, io::Error>(Bytes::from_static(chunk.as_bytes()))),
+        ));
+        let res = compress(
+            &CompressionConfig::default(),
+            Some(Encoding::Gzip),
+            response("text/plain", body),
+        );
+        assert_eq!(res.headers()[header::CONTENT_ENCODING], "gzip");
+
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(decode("gzip", &body), chunks.concat().as_bytes());
+    }
+
+    #[test]
+    fn skips_small_responses() {
+        let res = compress(
+            &CompressionConfig::default(),
+            Some(Encoding::Gzip),
+            response("text/plain", "tiny"),
+        );
+        assert!(!res.headers().contains_key(header::CONTENT_ENCODING));
+    }
+
+    #[test]
+    fn skips_configured_content_types() {
+        let body = vec![0u8; 4096];
+        for content_type in ["image/png", "video/mp4", "Application/Zip"] {
+            let res = compress(
+                &CompressionConfig::default(),
+                Some(Encoding::Gzip),
+                response(content_type, body.clone()),
+            );
+            assert!(
+                !res.headers().contains_key(header::CONTENT_ENCODING),
+                "{content_type}"
+            );
+        }
+    }
+
+    #[test]
+    fn skips_already_encoded_and_no_transform_responses() {
+        let body = "x".repeat(4096);
+        let mut res = response("text/plain", body.clone());
+        res.headers_mut()
+            .insert(header::CONTENT_ENCODING, HeaderValue::from_static("br"));
+        let res = compress(&CompressionConfig::default(), Some(Encoding::Gzip), res);
+        assert_eq!(res.headers()[header::CONTENT_ENCODING], "br");
+
+        let mut res = response("text/plain", body);
+        res.headers_mut().insert(
+            header::CACHE_CONTROL,
+            HeaderValue::from_static("public, no-transform"),
+        );
+        let res = compress(&CompressionConfig::default(), Some(Encoding::Gzip), res);
+        assert!(!res.headers().contains_key(header::CONTENT_ENCODING));
+    }
+
+    #[test]
+    fn adds_vary_without_accepted_encoding() {
+        let res = compress(
+            &CompressionConfig::default(),
+            None,
+            response("text/plain", "x".repeat(4096)),
+        );
+        assert!(!res.headers().contains_key(header::CONTENT_ENCODING));
+        assert_eq!(res.headers()[header::VARY], "accept-encoding");
+    }
+
+    #[test]
+    fn weakens_strong_etags() {
+        let mut res = response("text/plain", "x".repeat(4096));
+        res.headers_mut()
+            .insert(header::ETAG, HeaderValue::from_static("\"abc\""));
+        let res = compress(&CompressionConfig::default(), Some(Encoding::Zstd), res);
+        assert_eq!(res.headers()[header::ETAG], "W/\"abc\"");
+    }
+}
@@ -0,0 +1,251 @@
+//! Zero-downtime restarts. On SIGUSR2, the running server spawns a new
+//! WinterJS process with the same arguments and passes its listening
+//! sockets to it. Once the new process reports that it's ready, i.e. the
+//! user code has initialized in at least one of its workers, the old
+//! one stops accepting connections and drains. If the new process fails
+//! to start, the old one keeps serving as if nothing happened.
+//!
+//! The sockets are passed to the new process followed by the write end of
+//! a pipe it uses to report readiness.
+
+use std::{
+    fs::File,
+    io::Write,
+    os::unix::io::{FromRawFd, RawFd},
+    process::Child,
+    sync::Mutex,
+    time::Duration,
+};
+
+use anyhow::{bail, Context as _};
+use tokio::{
+    io::AsyncReadExt,
+    net::unix::pipe,
+    signal::unix::{signal, SignalKind},
+};
+
+use super::{
+    child_process::{pass_fds, set_cloexec, winterjs_command, FIRST_FD},
+    ListenAddr,
+};
+
+const HANDOFF_FDS_VAR: &str = "WINTERJS_HANDOFF_FDS";
+const HANDOFF_READY_FD_VAR: &str = "WINTERJS_HANDOFF_READY_FD";
+
+/// How long to wait for the new process to start serving before giving up
+/// on the restart.
+const READY_TIMEOUT: Duration = Duration::from_secs(120);
+
+static READY_PIPE: Mutex<Option<File>> = Mutex::new(None);
+
+/// Returns the sockets handed over by the previous process, if we were
+/// started as part of a restart. These replace any configured addresses.
+pub fn take_inherited_listeners() -> anyhow::Result<Option<Vec<ListenAddr>>> {
+    let fds = std::env::var(HANDOFF_FDS_VAR).ok();
+    let ready_fd = std::env::var(HANDOFF_READY_FD_VAR).ok();
+    std::env::remove_var(HANDOFF_FDS_VAR);
+    std::env::remove_var(HANDOFF_READY_FD_VAR);
+
+    let Some(handoff) = parse_handoff_env(fds.as_deref(), ready_fd.as_deref())? else {
+        return Ok(None);
+    };
+
+    // Safety: the previous process passed this fd to us for this purpose only
+    let ready_pipe = unsafe { File::from_raw_fd(handoff.ready_fd) };
+    set_cloexec(handoff.ready_fd)?;
+    *READY_PIPE.lock().unwrap() = Some(ready_pipe);
+
+    tracing::info!(
+        "Received {} socket(s) from the previous process",
+        handoff.listeners.len()
+    );
+    Ok(Some(handoff.listeners))
+}
+
+#[derive(Debug, PartialEq)]
+struct Handoff {
+    listeners: Vec<ListenAddr>,
+    ready_fd: RawFd,
+}
+
+/// Parses the variables [`spawn_successor`] sets, given as `fds` and
+/// `ready_fd`. Returns `None` if we weren't started by a restart.
+fn parse_handoff_env(fds: Option<&str>, ready_fd: Option<&str>) -> anyhow::Result<Option<Handoff>> {
+    let Some(fds) = fds else {
+        return Ok(None);
+    };
+    let count: RawFd = fds
+        .parse()
+        .ok()
+        .filter(|count| *count >= 0)
+        .with_context(|| format!("Invalid socket count in {HANDOFF_FDS_VAR}: '{fds}'"))?;
+
+    let ready_fd = ready_fd.with_context(|| format!("{HANDOFF_READY_FD_VAR} is not set"))?;
+    let ready_fd: RawFd = ready_fd
+        .parse()
+        .with_context(|| format!("Invalid fd in {HANDOFF_READY_FD_VAR}: '{ready_fd}'"))?;
+    if (FIRST_FD..FIRST_FD + count).contains(&ready_fd) {
+        bail!("The fd in {HANDOFF_READY_FD_VAR} is one of the sockets");
+    }
+
+    Ok(Some(Handoff {
+        listeners: (FIRST_FD..FIRST_FD + count).map(ListenAddr::Fd).collect(),
+        ready_fd,
+    }))
+}
+
+/// The variables that tell the new process about the sockets we pass to
+/// it as `FIRST_FD` onwards, followed by the ready pipe.
+fn handoff_env(listener_count: usize) -> [(&'static str, String); 2] {
+    [
+        (HANDOFF_FDS_VAR, listener_count.to_string()),
+        (
+            HANDOFF_READY_FD_VAR,
+            (FIRST_FD + listener_count as RawFd).to_string(),
+        ),
+    ]
+}
+
+/// Lets the previous process know we're accepting connections and the user
+/// code has initialized, so it can start draining. Does nothing if we weren't started by a restart.
+pub fn notify_ready() {
+    if let Some(mut pipe) = READY_PIPE.lock().unwrap().take() {
+        if let Err(e) = pipe.write_all(b"1") {
+            tracing::error!(error = %e, "Failed to notify the previous process of readiness");
+        }
+    }
+}
+
+/// Waits for SIGUSR2 and hands the sockets over to a new process. Returns
+/// once a new process has taken over, at which point this one should stop
+/// accepting connections.
+pub async fn wait_for_restart(listener_fds: Vec<RawFd>) {
+    let mut signal = match signal(SignalKind::user_defined2()) {
+        Ok(s) => s,
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to listen for SIGUSR2, zero-downtime restarts are disabled");
+            std::future::pending().await
+        }
+    };
+
+    loop {
+        signal.recv().await;
+        tracing::info!("Received SIGUSR2, starting a new process");
+
+        match hand_off(&listener_fds).await {
+            Ok(()) => {
+                tracing::info!("New process is ready, draining this one");
+                return;
+            }
+            Err(e) => {
+                tracing::error!(
+                    error = format!("{e:#}"),
+                    "Restart failed, continuing to serve requests"
+                );
+            }
+        }
+    }
+}
+
+async fn hand_off(listener_fds: &[RawFd]) -> anyhow::Result<()> {
+    let (mut child, mut ready) = spawn_successor(listener_fds)?;
+
+    let mut buf = [0u8; 1];
+    let result = tokio::time::timeout(READY_TIMEOUT, ready.read(&mut buf)).await;
+
+    match result {
+        Ok(Ok(1)) => Ok(()),
+        failure => {
+            // Don't leave the new process running (or as a zombie) if it
+            // didn't make it
+            _ = child.kill();
+            _ = tokio::task::spawn_blocking(move || child.wait()).await;
+
+            match failure {
+                Err(_) => bail!("New process did not become ready in time"),
+                Ok(Err(e)) => Err(e).context("Failed to wait for the new process"),
+                Ok(Ok(_)) => bail!("New process exited before becoming ready"),
+            }
+        }
+    }
+}
+
+fn spawn_successor(listener_fds: &[RawFd]) -> anyhow::Result<(Child, pipe::Receiver)> {
+    let mut pipe_fds = [0 as RawFd; 2];
+    if unsafe { libc::pipe(pipe_fds.as_mut_ptr()) } == -1 {
+        return Err(std::io::Error::last_os_error()).context("Failed to create pipe");
+    }
+    // Safety: we just created these
+    let (read_end, write_end) = unsafe {
+        (
+            File::from_raw_fd(pipe_fds[0]),
+            File::from_raw_fd(pipe_fds[1]),
+        )
+    };
+    set_cloexec(pipe_fds[0])?;
+    set_cloexec(pipe_fds[1])?;
+
+    let mut fds = listener_fds.to_vec();
+    fds.push(pipe_fds[1]);
+
+    let mut command = winterjs_command()?;
+    command.envs(handoff_env(listener_fds.len()));
+    let passed_fds = pass_fds(&mut command, &fds)?;
+
+    let child = command.spawn().context("Failed to spawn new process")?;
+
+    // Close our copies of the write end, so reading from the pipe fails
+    // if the new process exits before reporting readiness.
+    drop(passed_fds);
+    drop(write_end);
+
+    let ready = pipe::Receiver::from_file(read_end).context("Failed to read from pipe")?;
+    Ok((child, ready))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(env: &[(&'static str, String)]) -> anyhow::Result<Option<Handoff>> {
+        let var = |name| {
+            env.iter()
+                .find(|(var, _)| *var == name)
+                .map(|(_, value)| value.as_str())
+        };
+        parse_handoff_env(var(HANDOFF_FDS_VAR), var(HANDOFF_READY_FD_VAR))
+    }
+
+    #[test]
+    fn reads_what_the_previous_process_passed() {
+        assert_eq!(
+            parse(&handoff_env(2)).unwrap(),
+            Some(Handoff {
+                listeners: vec![ListenAddr::Fd(FIRST_FD), ListenAddr::Fd(FIRST_FD + 1)],
+                ready_fd: FIRST_FD + 2,
+            })
+        );
+        assert_eq!(
+            parse(&handoff_env(0)).unwrap(),
+            Some(Handoff {
+                listeners: vec![],
+                ready_fd: FIRST_FD,
+            })
+        );
+    }
+
+    #[test]
+    fn ignores_missing_handoff() {
+        assert_eq!(parse(&[]).unwrap(), None);
+        assert_eq!(parse_handoff_env(None, Some("5")).unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_invalid_variables() {
+        assert!(parse_handoff_env(Some("2"), None).is_err());
+        assert!(parse_handoff_env(Some("two"), Some("5")).is_err());
+        assert!(parse_handoff_env(Some("-1"), Some("5")).is_err());
+        assert!(parse_handoff_env(Some("2"), Some("five")).is_err());
+        assert!(parse_handoff_env(Some("2"), Some(&FIRST_FD.to_string())).is_err());
+    }
+}
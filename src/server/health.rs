@@ -0,0 +1,106 @@
+//! Health and readiness probes. `/healthz` reports that the process is up
+//! and serving, while `/readyz` only succeeds once at least one worker has
+//! initialized the user code, and stops succeeding once shutdown starts.
+//! The probes can be served on a separate address, on reserved paths of
+//! the main listeners, or both.
+
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use anyhow::Context as _;
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server, StatusCode,
+};
+use tokio::sync::Notify;
+
+pub const HEALTH_PATH: &str = "/healthz";
+pub const READY_PATH: &str = "/readyz";
+
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+lazy_static::lazy_static! {
+    static ref INITIALIZED_NOTIFY: Notify = Notify::new();
+}
+
+/// Called by the workers once the user code has been evaluated and its
+/// initial event loop has run to completion.
+pub fn set_initialized() {
+    if !INITIALIZED.swap(true, Ordering::SeqCst) {
+        tracing::debug!("User code initialized, ready to serve requests");
+        INITIALIZED_NOTIFY.notify_waiters();
+    }
+}
+
+pub fn set_shutting_down() {
+    SHUTTING_DOWN.store(true, Ordering::SeqCst);
+}
+
+pub fn is_ready() -> bool {
+    INITIALIZED.load(Ordering::SeqCst) && !SHUTTING_DOWN.load(Ordering::SeqCst)
+}
+
+/// Waits until at least one worker has initialized the user code.
+pub async fn wait_until_initialized() {
+    loop {
+        let notified = INITIALIZED_NOTIFY.notified();
+        if INITIALIZED.load(Ordering::SeqCst) {
+            return;
+        }
+        notified.await;
+    }
+}
+
+/// Returns the response for a probe path, or `None` for any other path.
+pub fn probe_response(path: &str) -> Option<Response<Body>> {
+    let ok = match path {
+        HEALTH_PATH => true,
+        READY_PATH => is_ready(),
+        _ => return None,
+    };
+
+    let (status, body) = if ok {
+        (StatusCode::OK, "ok")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "not ready")
+    };
+    Some(
+        Response::builder()
+            .status(status)
+            .header(hyper::header::CACHE_CONTROL, "no-store")
+            .body(Body::from(body))
+            .expect("Failed to construct probe response"),
+    )
+}
+
+async fn handle(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    Ok(probe_response(req.uri().path()).unwrap_or_else(|| {
+        Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::from("Not found"))
+            .expect("Failed to construct 404 response")
+    }))
+}
+
+/// Starts serving the probes on the given address.
+pub fn start(addr: SocketAddr) -> anyhow::Result<()> {
+    let make_service = make_service_fn(|_| async { Ok::<_, Infallible>(service_fn(handle)) });
+
+    let server = Server::try_bind(&addr)
+        .with_context(|| format!("Failed to bind health checks to '{addr}'"))?
+        .serve(make_service);
+    tracing::info!(
+        "serving health checks on 'http://{addr}{HEALTH_PATH}' and 'http://{addr}{READY_PATH}'"
+    );
+
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            tracing::error!(error = %e, "Health check server failed");
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,182 @@
+//! Limits on what clients can send us and how long they can take.
+
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use hyper::{body::HttpBody, Body};
+
+/// hyper doesn't accept a smaller read buffer than this.
+pub const MIN_MAX_HEADER_SIZE: usize = 8192;
+
+#[derive(Clone, Debug, Default)]
+pub struct RequestLimits {
+    /// Requests with a larger body are rejected with 413 Payload Too Large.
+    pub max_body_size: Option<u64>,
+
+    /// Connections sending larger request headers are closed.
+    pub max_header_size: Option<usize>,
+
+    /// Connections that don't send the full request headers within this
+    /// time, counted from the first byte, are closed.
+    pub header_read_timeout: Option<Duration>,
+
+    /// Connections that are idle for this long between requests are closed.
+    pub idle_timeout: Option<Duration>,
+}
+
+#[derive(Debug)]
+struct BodyTooLarge;
+
+impl std::fmt::Display for BodyTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "request body is too large")
+    }
+}
+
+impl std::error::Error for BodyTooLarge {}
+
+/// Wraps a request body so reading it fails once more than `max` bytes
+/// have been received, which also sets `exceeded`.
+struct LimitedBody {
+    inner: Body,
+    remaining: u64,
+    exceeded: Arc<AtomicBool>,
+}
+
+impl futures::Stream for LimitedBody {
+    type Item = Result<Bytes, Box<dyn std::error::Error + Send + Sync>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.exceeded.load(Ordering::SeqCst) {
+            return Poll::Ready(None);
+        }
+
+        match Pin::new(&mut self.inner).poll_data(cx) {
+            Poll::Ready(Some(Ok(data))) => match self.remaining.checked_sub(data.len() as u64) {
+                Some(remaining) => {
+                    self.remaining = remaining;
+                    Poll::Ready(Some(Ok(data)))
+                }
+                None => {
+                    self.exceeded.store(true, Ordering::SeqCst);
+                    Poll::Ready(Some(Err(Box::new(BodyTooLarge))))
+                }
+            },
+            Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(Box::new(e)))),
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let hint = HttpBody::size_hint(&self.inner);
+        (
+            hint.lower() as usize,
+            hint.upper().map(|upper| upper as usize),
+        )
+    }
+}
+
+/// Enforces the maximum body size on a request. Returns `None` if the
+/// request declares a body that's too large up front; otherwise, the
+/// returned flag is set if the body turns out to be too large while it's
+/// being read.
+pub fn limit_body(
+    req: hyper::Request<Body>,
+    max: u64,
+) -> Option<(hyper::Request<Body>, Arc<AtomicBool>)> {
+    if req.body().size_hint().lower() > max {
+        return None;
+    }
+
+    let exceeded = Arc::new(AtomicBool::new(false));
+    let exceeded_clone = exceeded.clone();
+    let req = req.map(|inner| {
+        Body::wrap_stream(LimitedBody {
+            inner,
+            remaining: max,
+            exceeded: exceeded_clone,
+        })
+    });
+    Some((req, exceeded))
+}
+
+pub fn payload_too_large() -> hyper::Response<Body> {
+    hyper::Response::builder()
+        .status(hyper::StatusCode::PAYLOAD_TOO_LARGE)
+        .header(hyper::header::CONNECTION, "close")
+        .body(Body::from("Request body is too large"))
+        .expect("Failed to construct 413 response")
+}
+
+/// Parses a size in bytes, with an optional binary `K`, `M` or `G` suffix.
+pub fn parse_byte_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (digits, multiplier) = match s.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&s[..i], 1 << 10),
+        Some((i, 'm' | 'M')) => (&s[..i], 1 << 20),
+        Some((i, 'g' | 'G')) => (&s[..i], 1 << 30),
+        _ => (s, 1),
+    };
+    digits
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format!("invalid size '{s}', expected a number of bytes like 1048576 or 1M"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(body: Body) -> hyper::Request<Body> {
+        hyper::Request::post("/").body(body).unwrap()
+    }
+
+    fn chunked(chunks: &'static [&'static str]) -> Body {
+        Body::wrap_stream(futures::stream::iter(chunks.iter().map(|chunk| {
+            Ok::<_, std::io::Error>(Bytes::from_static(chunk.as_bytes()))
+        })))
+    }
+
+    #[test]
+    fn rejects_declared_size_up_front() {
+        assert!(limit_body(request(Body::from("hello world")), 5).is_none());
+    }
+
+    #[tokio::test]
+    async fn passes_bodies_within_the_limit() {
+        let (req, exceeded) = limit_body(request(chunked(&["hello", " world"])), 11).unwrap();
+        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+        assert_eq!(body, "hello world");
+        assert!(!exceeded.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn fails_streamed_bodies_over_the_limit() {
+        let (req, exceeded) = limit_body(request(chunked(&["hello", " world"])), 8).unwrap();
+        assert!(hyper::body::to_bytes(req.into_body()).await.is_err());
+        assert!(exceeded.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn parses_byte_sizes() {
+        assert_eq!(parse_byte_size("1024"), Ok(1024));
+        assert_eq!(parse_byte_size("8K"), Ok(8192));
+        assert_eq!(parse_byte_size(" 10m "), Ok(10 << 20));
+        assert_eq!(parse_byte_size("1G"), Ok(1 << 30));
+        assert!(parse_byte_size("").is_err());
+        assert!(parse_byte_size("K").is_err());
+        assert!(parse_byte_size("1.5M").is_err());
+        assert!(parse_byte_size("99999999999999999G").is_err());
+    }
+}
@@ -0,0 +1,621 @@
+//! Accepting connections for the server. Each listener runs its own accept
+//! loop and performs any per-connection handshakes (such as TLS) on a
+//! separate task, so a slow client can't hold up the others. Connections
+//! that are ready to serve HTTP are handed to hyper through [`Incoming`].
+
+use std::{
+    fmt::Display,
+    net::SocketAddr,
+    pin::Pin,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+#[cfg(unix)]
+use std::path::PathBuf;
+
+use anyhow::Context as _;
+use futures::{task::AtomicWaker, Future};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpListener,
+    sync::mpsc,
+};
+
+#[cfg(unix)]
+use tokio::net::TcpSocket;
+
+use super::{proxy_protocol, tls::TlsTerminator, ConnectionInfo, ServerConfig};
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_secs(1);
+
+pub trait ConnectionIo: AsyncRead + AsyncWrite + Send + Unpin + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin + 'static> ConnectionIo for T {}
+
+pub struct Connection {
+    io: Box<dyn ConnectionIo>,
+    remote_addr: SocketAddr,
+    info: ConnectionInfo,
+    idle_timer: Option<IdleTimer>,
+}
+
+impl Connection {
+    fn new(
+        io: Box<dyn ConnectionIo>,
+        remote_addr: SocketAddr,
+        info: ConnectionInfo,
+        idle_timeout: Option<Duration>,
+    ) -> Self {
+        Self {
+            io,
+            remote_addr,
+            info,
+            idle_timer: idle_timeout.map(IdleTimer::new),
+        }
+    }
+
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+
+    pub fn info(&self) -> &ConnectionInfo {
+        &self.info
+    }
+
+    /// Returns the activity tracker for the idle timeout, if there is one.
+    pub fn activity(&self) -> Option<Arc<ConnectionActivity>> {
+        self.idle_timer.as_ref().map(|t| t.activity.clone())
+    }
+}
+
+/// Keeps track of when a connection was last used, and how many requests
+/// are in progress on it.
+pub struct ConnectionActivity {
+    started: Instant,
+    last_active_ms: AtomicU64,
+    active_requests: AtomicUsize,
+    /// Woken when the last active request is done, so the idle timer can
+    /// start again.
+    idle_waker: AtomicWaker,
+}
+
+impl ConnectionActivity {
+    fn touch(&self) {
+        self.last_active_ms
+            .store(self.started.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Marks a request as in progress until the returned guard is dropped.
+    /// The connection is never considered idle while a request is in
+    /// progress, even if it takes a while to produce a response.
+    pub fn start_request(self: &Arc<Self>) -> ActiveRequest {
+        self.active_requests.fetch_add(1, Ordering::SeqCst);
+        ActiveRequest(self.clone())
+    }
+}
+
+pub struct ActiveRequest(Arc<ConnectionActivity>);
+
+impl ActiveRequest {
+    /// Keeps the connection active beyond the end of this request, e.g.
+    /// for as long as an upgraded connection is open.
+    pub fn keep_active(&self) -> ActiveRequest {
+        self.0.start_request()
+    }
+}
+
+impl Drop for ActiveRequest {
+    fn drop(&mut self) {
+        // The idle time starts counting when the response is done
+        self.0.touch();
+        if self.0.active_requests.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.idle_waker.wake();
+        }
+    }
+}
+
+struct IdleTimer {
+    timeout: Duration,
+    activity: Arc<ConnectionActivity>,
+    sleep: Pin<Box<tokio::time::Sleep>>,
+}
+
+impl IdleTimer {
+    fn new(timeout: Duration) -> Self {
+        let started = Instant::now();
+        Self {
+            timeout,
+            activity: Arc::new(ConnectionActivity {
+                started,
+                last_active_ms: AtomicU64::new(0),
+                active_requests: AtomicUsize::new(0),
+                idle_waker: AtomicWaker::new(),
+            }),
+            sleep: Box::pin(tokio::time::sleep_until((started + timeout).into())),
+        }
+    }
+
+    /// Returns an error if the connection has been idle for too long, and
+    /// otherwise makes sure we're woken up when it would be.
+    fn poll_expired(&mut self, cx: &mut Context<'_>) -> Option<std::io::Error> {
+        self.activity.idle_waker.register(cx.waker());
+        if self.activity.active_requests.load(Ordering::SeqCst) > 0 {
+            return None;
+        }
+
+        let last_active =
+            Duration::from_millis(self.activity.last_active_ms.load(Ordering::Relaxed));
+        let deadline = self.activity.started + last_active + self.timeout;
+        if Instant::now() >= deadline {
+            return Some(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "connection was idle for too long",
+            ));
+        }
+
+        self.sleep.as_mut().reset(deadline.into());
+        match self.sleep.as_mut().poll(cx) {
+            Poll::Ready(()) => Some(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "connection was idle for too long",
+            )),
+            Poll::Pending => None,
+        }
+    }
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let result = Pin::new(&mut self.io).poll_read(cx, buf);
+        match self.idle_timer.as_mut() {
+            None => result,
+            Some(timer) => match result {
+                Poll::Ready(_) => {
+                    timer.activity.touch();
+                    result
+                }
+                Poll::Pending => match timer.poll_expired(cx) {
+                    Some(e) => Poll::Ready(Err(e)),
+                    None => Poll::Pending,
+                },
+            },
+        }
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let result = Pin::new(&mut self.io).poll_write(cx, buf);
+        if let (Poll::Ready(Ok(_)), Some(timer)) = (&result, &self.idle_timer) {
+            timer.activity.touch();
+        }
+        result
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[std::io::IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        let result = Pin::new(&mut self.io).poll_write_vectored(cx, bufs);
+        if let (Poll::Ready(Ok(_)), Some(timer)) = (&result, &self.idle_timer) {
+            timer.activity.touch();
+        }
+        result
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.io.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
+
+/// The receiving end of the accept loops, polled by hyper.
+pub struct Incoming {
+    rx: mpsc::Receiver<Connection>,
+}
+
+impl Incoming {
+    pub fn new() -> (Self, mpsc::Sender<Connection>) {
+        let (tx, rx) = mpsc::channel(1024);
+        (Self { rx }, tx)
+    }
+}
+
+impl hyper::server::accept::Accept for Incoming {
+    type Conn = Connection;
+    type Error = std::io::Error;
+
+    fn poll_accept(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        self.rx.poll_recv(cx).map(|c| c.map(Ok))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+    /// An already-listening socket inherited from the parent process.
+    #[cfg(unix)]
+    Fd(std::os::unix::io::RawFd),
+}
+
+impl FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(fd) = s.strip_prefix("fd:") {
+            #[cfg(unix)]
+            return fd
+                .parse()
+                .map(Self::Fd)
+                .map_err(|e| format!("invalid file descriptor: {e}"));
+            #[cfg(not(unix))]
+            return Err(format!(
+                "Inheriting sockets (fd:{fd}) is not supported on this platform"
+            ));
+        }
+
+        match s.strip_prefix("unix:") {
+            #[cfg(unix)]
+            Some(path) if !path.is_empty() => Ok(Self::Unix(path.into())),
+            #[cfg(unix)]
+            Some(_) => Err("expected a path after 'unix:'".to_string()),
+            #[cfg(not(unix))]
+            Some(_) => Err("Unix domain sockets are not supported on this platform".to_string()),
+            None => s
+                .parse()
+                .map(Self::Tcp)
+                .map_err(|e| format!("invalid socket address: {e}")),
+        }
+    }
+}
+
+impl Display for ListenAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{addr}"),
+            #[cfg(unix)]
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+            #[cfg(unix)]
+            Self::Fd(fd) => write!(f, "fd:{fd}"),
+        }
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+impl Listener {
+    pub async fn bind(addr: &ListenAddr, config: &ServerConfig) -> anyhow::Result<Self> {
+        match addr {
+            ListenAddr::Tcp(addr) => Ok(Self::Tcp(
+                bind_tcp(*addr, config.reuse_port)
+                    .await
+                    .with_context(|| format!("Failed to bind to '{addr}'"))?,
+            )),
+
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => {
+                let listener = bind_unix(path, config.unix_socket_mode)?;
+                listener.set_nonblocking(true)?;
+                Ok(Self::Unix(tokio::net::UnixListener::from_std(listener)?))
+            }
+
+            #[cfg(unix)]
+            ListenAddr::Fd(fd) => Self::from_fd(*fd)
+                .with_context(|| format!("Failed to use inherited socket fd:{fd}")),
+        }
+    }
+
+    #[cfg(unix)]
+    pub fn as_raw_fd(&self) -> std::os::unix::io::RawFd {
+        use std::os::unix::io::AsRawFd;
+
+        match self {
+            Self::Tcp(listener) => listener.as_raw_fd(),
+            Self::Unix(listener) => listener.as_raw_fd(),
+        }
+    }
+
+    #[cfg(unix)]
+    fn from_fd(fd: std::os::unix::io::RawFd) -> std::io::Result<Self> {
+        use std::os::unix::io::FromRawFd;
+
+        let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+        let mut len = std::mem::size_of_val(&addr) as libc::socklen_t;
+        if unsafe { libc::getsockname(fd, &mut addr as *mut _ as *mut libc::sockaddr, &mut len) }
+            == -1
+        {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        // Inherited sockets don't have close-on-exec set, but we don't want
+        // them leaking into processes we spawn later on.
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        match addr.ss_family as libc::c_int {
+            libc::AF_INET | libc::AF_INET6 => {
+                // Safety: we checked that this is an IP socket, and we take
+                // ownership of it for the rest of the process' lifetime.
+                let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+                listener.set_nonblocking(true)?;
+                Ok(Self::Tcp(TcpListener::from_std(listener)?))
+            }
+            libc::AF_UNIX => {
+                // Safety: same as above
+                let listener = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+                listener.set_nonblocking(true)?;
+                Ok(Self::Unix(tokio::net::UnixListener::from_std(listener)?))
+            }
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "not a TCP or Unix domain socket",
+            )),
+        }
+    }
+
+    async fn accept(&self) -> std::io::Result<(Box<dyn ConnectionIo>, SocketAddr)> {
+        match self {
+            Self::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Box::new(stream), addr))
+            }
+
+            // Unix sockets don't have a meaningful remote address
+            #[cfg(unix)]
+            Self::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((
+                    Box::new(stream),
+                    (std::net::Ipv4Addr::UNSPECIFIED, 0).into(),
+                ))
+            }
+        }
+    }
+}
+
+async fn bind_tcp(addr: SocketAddr, reuse_port: bool) -> std::io::Result<TcpListener> {
+    if !reuse_port {
+        return TcpListener::bind(addr).await;
+    }
+
+    #[cfg(unix)]
+    {
+        let socket = match addr {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        socket.set_reuseaddr(true)?;
+        socket.set_reuseport(true)?;
+        socket.bind(addr)?;
+        socket.listen(1024)
+    }
+
+    #[cfg(not(unix))]
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "SO_REUSEPORT is not supported on this platform",
+    ))
+}
+
+/// Binds a Unix domain socket, replacing a stale socket file left behind by
+/// a previous run.
+#[cfg(unix)]
+pub fn bind_unix(
+    path: &std::path::Path,
+    mode: Option<u32>,
+) -> anyhow::Result<std::os::unix::net::UnixListener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    // A socket file left behind by a previous run would make binding fail,
+    // but we don't want to remove anything else.
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            std::fs::remove_file(path)
+                .with_context(|| format!("Failed to remove stale socket '{}'", path.display()))?;
+        }
+    }
+
+    // The socket is created with the permissions the umask allows, and
+    // clients could connect before we get to change them, so the umask is
+    // set to match the mode while binding. This only happens at startup,
+    // before the server creates any other files.
+    let previous_umask = mode.map(|mode| unsafe { libc::umask((!mode & 0o777) as libc::mode_t) });
+    let listener = std::os::unix::net::UnixListener::bind(path);
+    if let Some(umask) = previous_umask {
+        unsafe { libc::umask(umask) };
+    }
+    let listener = listener.with_context(|| format!("Failed to bind to '{}'", path.display()))?;
+
+    // Sets the bits the umask can't, such as the sticky bit
+    if let Some(mode) = mode {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+            .with_context(|| format!("Failed to set permissions on '{}'", path.display()))?;
+    }
+
+    Ok(listener)
+}
+
+/// How to set up accepted connections before handing them to hyper.
+#[derive(Clone)]
+pub struct AcceptOptions {
+    pub tls: Option<TlsTerminator>,
+    pub idle_timeout: Option<Duration>,
+
+    /// Whether connections start with a PROXY protocol header, which
+    /// replaces the remote address.
+    pub proxy_protocol: bool,
+}
+
+/// Accepts connections on the listener until the server stops polling for
+/// new connections.
+pub async fn accept(listener: Listener, options: AcceptOptions, tx: mpsc::Sender<Connection>) {
+    loop {
+        let (mut stream, mut remote_addr) = tokio::select! {
+            _ = tx.closed() => break,
+            res = listener.accept() => match res {
+                Ok(s) => s,
+                Err(e) => {
+                    // Most likely out of file descriptors, give existing
+                    // connections a chance to finish.
+                    tracing::error!(error = %e, "Failed to accept connection");
+                    tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                    continue;
+                }
+            }
+        };
+
+        let AcceptOptions {
+            tls,
+            idle_timeout,
+            proxy_protocol,
+        } = options.clone();
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            if proxy_protocol {
+                match tokio::time::timeout(
+                    HANDSHAKE_TIMEOUT,
+                    proxy_protocol::read_header(&mut stream),
+                )
+                .await
+                {
+                    Ok(Ok(Some(source))) => remote_addr = source,
+                    Ok(Ok(None)) => (),
+                    Ok(Err(e)) => {
+                        tracing::debug!(%remote_addr, error = %e, "Invalid PROXY protocol header");
+                        return;
+                    }
+                    Err(_) => {
+                        tracing::debug!(%remote_addr, "Timed out reading PROXY protocol header");
+                        return;
+                    }
+                }
+            }
+
+            let connection = match tls {
+                None => Connection::new(
+                    stream,
+                    remote_addr,
+                    ConnectionInfo { tls: false },
+                    idle_timeout,
+                ),
+                Some(tls) => {
+                    let stream = match tokio::time::timeout(
+                        HANDSHAKE_TIMEOUT,
+                        tls.acceptor().accept(stream),
+                    )
+                    .await
+                    {
+                        Ok(Ok(s)) => s,
+                        Ok(Err(e)) => {
+                            tracing::debug!(%remote_addr, error = %e, "TLS handshake failed");
+                            return;
+                        }
+                        Err(_) => {
+                            tracing::debug!(%remote_addr, "TLS handshake timed out");
+                            return;
+                        }
+                    };
+                    Connection::new(
+                        Box::new(stream),
+                        remote_addr,
+                        ConnectionInfo { tls: true },
+                        idle_timeout,
+                    )
+                }
+            };
+
+            _ = tx.send(connection).await;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_tcp_addresses() {
+        assert_eq!(
+            "0.0.0.0:8080".parse(),
+            Ok(ListenAddr::Tcp("0.0.0.0:8080".parse().unwrap()))
+        );
+        assert_eq!(
+            "[::]:8080".parse(),
+            Ok(ListenAddr::Tcp("[::]:8080".parse().unwrap()))
+        );
+        assert!("0.0.0.0".parse::<ListenAddr>().is_err());
+        assert!("localhost:8080".parse::<ListenAddr>().is_err());
+        assert!("".parse::<ListenAddr>().is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn binds_unix_sockets_with_the_given_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("winterjs-{}.sock", std::process::id()));
+        let listener = bind_unix(&path, Some(0o600));
+        let mode = std::fs::metadata(&path).map(|m| m.permissions().mode());
+        _ = std::fs::remove_file(&path);
+
+        assert!(listener.is_ok());
+        assert_eq!(mode.unwrap() & 0o777, 0o600);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn parses_unix_sockets_and_fds() {
+        assert_eq!(
+            "unix:/run/winterjs.sock".parse(),
+            Ok(ListenAddr::Unix("/run/winterjs.sock".into()))
+        );
+        assert!("unix:".parse::<ListenAddr>().is_err());
+        assert_eq!("fd:3".parse(), Ok(ListenAddr::Fd(3)));
+        assert!("fd:".parse::<ListenAddr>().is_err());
+        assert!("fd:three".parse::<ListenAddr>().is_err());
+    }
+
+    #[test]
+    fn round_trips_through_display() {
+        let mut addrs = vec!["127.0.0.1:8080", "[::1]:443"];
+        if cfg!(unix) {
+            addrs.extend(["unix:relative/path.sock", "fd:4"]);
+        }
+        for addr in addrs {
+            assert_eq!(addr.parse::<ListenAddr>().unwrap().to_string(), addr);
+        }
+    }
+}
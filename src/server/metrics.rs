@@ -0,0 +1,358 @@
+//! Prometheus metrics. Counters are kept in a global registry that the
+//! server and the runners update, and are rendered in the Prometheus text
+//! format on a separate listener when `--metrics-addr` is given.
+
+use std::{
+    collections::BTreeMap,
+    convert::Infallible,
+    fmt::Write,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicI32, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::Context as _;
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server, StatusCode,
+};
+use parking_lot::Mutex;
+
+/// Upper bounds of the latency histogram buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+lazy_static::lazy_static! {
+    static ref METRICS: Metrics = Metrics::default();
+}
+
+#[derive(Default)]
+struct Metrics {
+    requests: Mutex<BTreeMap<u16, LatencyHistogram>>,
+    worker_in_flight_requests: Mutex<BTreeMap<usize, Arc<AtomicI32>>>,
+    threads_spawned: AtomicU64,
+    script_init_failures: AtomicU64,
+    requests_unresolvable: AtomicU64,
+    requests_cancelled_by_shutdown: AtomicU64,
+}
+
+#[derive(Default)]
+struct LatencyHistogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Metrics {
+    fn record_request(&self, status: StatusCode, latency: Duration) {
+        let latency = latency.as_secs_f64();
+        let mut requests = self.requests.lock();
+        let histogram = requests.entry(status.as_u16()).or_default();
+        for (bucket, le) in histogram.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if latency <= le {
+                *bucket += 1;
+            }
+        }
+        histogram.count += 1;
+        histogram.sum += latency;
+    }
+
+    fn register_worker(&self, index: usize, in_flight_requests: Arc<AtomicI32>) {
+        self.worker_in_flight_requests
+            .lock()
+            .insert(index, in_flight_requests);
+    }
+
+    fn unregister_worker(&self, index: usize, in_flight_requests: &Arc<AtomicI32>) {
+        let mut workers = self.worker_in_flight_requests.lock();
+        // After a reload, a new worker takes over the index while the old
+        // one is still draining
+        if workers
+            .get(&index)
+            .is_some_and(|registered| Arc::ptr_eq(registered, in_flight_requests))
+        {
+            workers.remove(&index);
+        }
+    }
+}
+
+pub fn record_request(status: StatusCode, latency: Duration) {
+    METRICS.record_request(status, latency);
+}
+
+/// Registers the in-flight request counter of a worker thread, which is
+/// reported as a gauge.
+pub fn register_worker(index: usize, in_flight_requests: Arc<AtomicI32>) {
+    METRICS.register_worker(index, in_flight_requests);
+}
+
+/// Stops reporting the gauge of a worker thread that exited.
+pub fn unregister_worker(index: usize, in_flight_requests: &Arc<AtomicI32>) {
+    METRICS.unregister_worker(index, in_flight_requests);
+}
+
+pub fn record_thread_spawned() {
+    METRICS.threads_spawned.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn record_script_init_failure() {
+    METRICS.script_init_failures.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn record_request_unresolvable() {
+    METRICS
+        .requests_unresolvable
+        .fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn record_request_cancelled_by_shutdown() {
+    METRICS
+        .requests_cancelled_by_shutdown
+        .fetch_add(1, Ordering::Relaxed);
+}
+
+impl Metrics {
+    fn render(&self) -> String {
+        fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+            _ = writeln!(out, "# HELP {name} {help}");
+            _ = writeln!(out, "# TYPE {name} {kind}");
+        }
+
+        let mut out = String::new();
+
+        {
+            let requests = self.requests.lock();
+
+            header(
+                &mut out,
+                "winterjs_http_requests_total",
+                "counter",
+                "Number of HTTP requests handled, by response status.",
+            );
+            for (status, histogram) in requests.iter() {
+                _ = writeln!(
+                    out,
+                    "winterjs_http_requests_total{{status=\"{status}\"}} {}",
+                    histogram.count
+                );
+            }
+
+            header(
+                &mut out,
+                "winterjs_http_request_duration_seconds",
+                "histogram",
+                "Time until the response headers were ready, by response status.",
+            );
+            for (status, histogram) in requests.iter() {
+                for (bucket, le) in histogram.buckets.iter().zip(LATENCY_BUCKETS) {
+                    _ = writeln!(
+                    out,
+                    "winterjs_http_request_duration_seconds_bucket{{status=\"{status}\",le=\"{le}\"}} {bucket}"
+                );
+                }
+                _ = writeln!(
+                out,
+                "winterjs_http_request_duration_seconds_bucket{{status=\"{status}\",le=\"+Inf\"}} {}",
+                histogram.count
+            );
+                _ = writeln!(
+                    out,
+                    "winterjs_http_request_duration_seconds_sum{{status=\"{status}\"}} {}",
+                    histogram.sum
+                );
+                _ = writeln!(
+                    out,
+                    "winterjs_http_request_duration_seconds_count{{status=\"{status}\"}} {}",
+                    histogram.count
+                );
+            }
+        }
+
+        header(
+            &mut out,
+            "winterjs_worker_in_flight_requests",
+            "gauge",
+            "Number of requests currently being handled by each Javascript worker thread.",
+        );
+        for (worker, in_flight) in self.worker_in_flight_requests.lock().iter() {
+            _ = writeln!(
+                out,
+                "winterjs_worker_in_flight_requests{{worker=\"{worker}\"}} {}",
+                in_flight.load(Ordering::SeqCst)
+            );
+        }
+
+        header(
+            &mut out,
+            "winterjs_worker_threads_spawned_total",
+            "counter",
+            "Number of Javascript worker threads started.",
+        );
+        _ = writeln!(
+            out,
+            "winterjs_worker_threads_spawned_total {}",
+            self.threads_spawned.load(Ordering::Relaxed)
+        );
+
+        header(
+            &mut out,
+            "winterjs_script_init_failures_total",
+            "counter",
+            "Number of times the user code failed to initialize in a worker thread.",
+        );
+        _ = writeln!(
+            out,
+            "winterjs_script_init_failures_total {}",
+            self.script_init_failures.load(Ordering::Relaxed)
+        );
+
+        header(
+            &mut out,
+            "winterjs_requests_cancelled_total",
+            "counter",
+            "Number of requests cancelled before the user code produced a response, by reason.",
+        );
+        _ = writeln!(
+            out,
+            "winterjs_requests_cancelled_total{{reason=\"unresolvable\"}} {}",
+            self.requests_unresolvable.load(Ordering::Relaxed)
+        );
+        _ = writeln!(
+            out,
+            "winterjs_requests_cancelled_total{{reason=\"shutting_down\"}} {}",
+            self.requests_cancelled_by_shutdown.load(Ordering::Relaxed)
+        );
+
+        out
+    }
+}
+
+async fn handle(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let response = if req.uri().path() == "/metrics" {
+        Response::builder()
+            .header(
+                hyper::header::CONTENT_TYPE,
+                "text/plain; version=0.0.4; charset=utf-8",
+            )
+            .body(Body::from(METRICS.render()))
+    } else {
+        Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::from("Not found"))
+    };
+
+    Ok(response.expect("Failed to construct metrics response"))
+}
+
+/// Starts serving the metrics at `/metrics` on the given address.
+pub fn start(addr: SocketAddr) -> anyhow::Result<()> {
+    let make_service = make_service_fn(|_| async { Ok::<_, Infallible>(service_fn(handle)) });
+
+    let server = Server::try_bind(&addr)
+        .with_context(|| format!("Failed to bind metrics endpoint to '{addr}'"))?
+        .serve(make_service);
+    tracing::info!("serving metrics on 'http://{addr}/metrics'");
+
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            tracing::error!(error = %e, "Metrics server failed");
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_empty_metrics() {
+        assert_eq!(
+            Metrics::default().render(),
+            "\
+# HELP winterjs_http_requests_total Number of HTTP requests handled, by response status.
+# TYPE winterjs_http_requests_total counter
+# HELP winterjs_http_request_duration_seconds Time until the response headers were ready, by response status.
+# TYPE winterjs_http_request_duration_seconds histogram
+# HELP winterjs_worker_in_flight_requests Number of requests currently being handled by each Javascript worker thread.
+# TYPE winterjs_worker_in_flight_requests gauge
+# HELP winterjs_worker_threads_spawned_total Number of Javascript worker threads started.
+# TYPE winterjs_worker_threads_spawned_total counter
+winterjs_worker_threads_spawned_total 0
+# HELP winterjs_script_init_failures_total Number of times the user code failed to initialize in a worker thread.
+# TYPE winterjs_script_init_failures_total counter
+winterjs_script_init_failures_total 0
+# HELP winterjs_requests_cancelled_total Number of requests cancelled before the user code produced a response, by reason.
+# TYPE winterjs_requests_cancelled_total counter
+winterjs_requests_cancelled_total{reason=\"unresolvable\"} 0
+winterjs_requests_cancelled_total{reason=\"shutting_down\"} 0
+"
+        );
+    }
+
+    #[test]
+    fn renders_request_histograms() {
+        let metrics = Metrics::default();
+        metrics.record_request(StatusCode::OK, Duration::from_millis(3));
+        metrics.record_request(StatusCode::OK, Duration::from_millis(200));
+        metrics.record_request(StatusCode::INTERNAL_SERVER_ERROR, Duration::from_secs(20));
+        let rendered = metrics.render();
+
+        for line in [
+            "winterjs_http_requests_total{status=\"200\"} 2",
+            "winterjs_http_requests_total{status=\"500\"} 1",
+            "winterjs_http_request_duration_seconds_bucket{status=\"200\",le=\"0.005\"} 1",
+            "winterjs_http_request_duration_seconds_bucket{status=\"200\",le=\"0.1\"} 1",
+            "winterjs_http_request_duration_seconds_bucket{status=\"200\",le=\"0.25\"} 2",
+            "winterjs_http_request_duration_seconds_bucket{status=\"200\",le=\"+Inf\"} 2",
+            "winterjs_http_request_duration_seconds_sum{status=\"200\"} 0.203",
+            "winterjs_http_request_duration_seconds_count{status=\"200\"} 2",
+            "winterjs_http_request_duration_seconds_bucket{status=\"500\",le=\"10\"} 0",
+            "winterjs_http_request_duration_seconds_bucket{status=\"500\",le=\"+Inf\"} 1",
+            "winterjs_http_request_duration_seconds_sum{status=\"500\"} 20",
+        ] {
+            assert!(
+                rendered.lines().any(|l| l == line),
+                "{line} not found in:\n{rendered}"
+            );
+        }
+
+        // Statuses are listed in order, and every bucket is rendered
+        let buckets = rendered
+            .lines()
+            .filter(|l| l.starts_with("winterjs_http_request_duration_seconds_bucket"))
+            .collect::<Vec<_>>();
+        assert_eq!(buckets.len(), 2 * (LATENCY_BUCKETS.len() + 1));
+        assert!(buckets[0].contains("status=\"200\""));
+        assert!(buckets[buckets.len() - 1].contains("status=\"500\""));
+    }
+
+    #[test]
+    fn reports_registered_workers() {
+        let metrics = Metrics::default();
+        let first = Arc::new(AtomicI32::new(3));
+        let second = Arc::new(AtomicI32::new(0));
+        metrics.register_worker(0, first.clone());
+        metrics.register_worker(1, second.clone());
+        assert!(metrics
+            .render()
+            .contains("winterjs_worker_in_flight_requests{worker=\"0\"} 3\n"));
+        assert!(metrics
+            .render()
+            .contains("winterjs_worker_in_flight_requests{worker=\"1\"} 0\n"));
+
+        // A worker that replaced the first one keeps its index when the
+        // first one exits
+        let replacement = Arc::new(AtomicI32::new(1));
+        metrics.register_worker(0, replacement.clone());
+        metrics.unregister_worker(0, &first);
+        metrics.unregister_worker(1, &second);
+        let rendered = metrics.render();
+        assert!(rendered.contains("winterjs_worker_in_flight_requests{worker=\"0\"} 1\n"));
+        assert!(!rendered.contains("worker=\"1\""));
+    }
+}
@@ -0,0 +1,402 @@
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context as _;
+use async_trait::async_trait;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server};
+
+use self::listener::{ActiveRequest, Connection, Incoming, Listener};
+use self::response_body::ResponseBody;
+
+pub use self::listener::ListenAddr;
+
+pub mod access_log;
+pub mod admin;
+#[cfg(unix)]
+mod child_process;
+pub mod client_addr;
+pub mod compression;
+#[cfg(unix)]
+pub mod handoff;
+pub mod health;
+pub mod limits;
+mod listener;
+pub mod metrics;
+#[cfg(unix)]
+pub mod prefork;
+mod proxy_protocol;
+mod response_body;
+#[cfg(unix)]
+pub mod socket_activation;
+pub mod tls;
+mod websocket;
+
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    /// All listeners feed into the same runner.
+    pub listen: Vec<ListenAddr>,
+
+    /// File permissions to apply to Unix domain sockets after binding.
+    pub unix_socket_mode: Option<u32>,
+
+    pub tls: Option<tls::TlsConfig>,
+
+    /// Whether to accept HTTP/2 connections, negotiated through ALPN
+    /// for TLS connections and with prior knowledge (h2c) otherwise.
+    pub http2: bool,
+
+    pub limits: limits::RequestLimits,
+
+    /// Proxies whose `X-Forwarded-For` header is used to find out the
+    /// client address.
+    pub trusted_proxies: Vec<client_addr::TrustedProxy>,
+
+    /// Whether connections start with a PROXY protocol header from a load
+    /// balancer, giving the client address.
+    pub proxy_protocol: bool,
+
+    pub access_log: Option<access_log::AccessLogConfig>,
+
+    /// How to compress responses, if at all.
+    pub compression: Option<compression::CompressionConfig>,
+
+    /// Where to serve the health and readiness probes, if anywhere.
+    pub health_addr: Option<SocketAddr>,
+
+    /// Whether to answer the health and readiness probes on the main
+    /// listeners, instead of passing those paths on to the user code.
+    pub health_paths: bool,
+
+    /// Where to serve Prometheus metrics, if anywhere.
+    pub metrics_addr: Option<SocketAddr>,
+
+    /// Where to serve the admin endpoints, if anywhere. See [`admin`].
+    pub admin_addr: Option<SocketAddr>,
+
+    /// Whether to set `SO_REUSEPORT` on TCP listeners, so several worker
+    /// processes can listen on the same port. See [`prefork`].
+    pub reuse_port: bool,
+
+    /// Whether to hand the listening sockets over to a new process on
+    /// SIGUSR2. See [`handoff`].
+    pub zero_downtime_restart: bool,
+
+    /// How long to wait for requests to finish when draining after the
+    /// sockets have been handed over to a new process.
+    pub shutdown_timeout: Option<Duration>,
+}
+
+/// Information about the connection a request was received on. This is
+/// attached to incoming requests as an extension.
+#[derive(Clone, Debug)]
+pub struct ConnectionInfo {
+    pub tls: bool,
+}
+
+/// The address of the client a request came from, taking trusted proxies
+/// into account. This is attached to incoming requests as an extension when
+/// the address is known.
+#[derive(Clone, Copy, Debug)]
+pub struct ClientAddr(pub IpAddr);
+
+/// Identifies the Javascript worker thread that handled a request. Runners
+/// attach this to their responses as an extension.
+#[derive(Clone, Copy, Debug)]
+pub struct WorkerThreadId(pub usize);
+
+pub async fn run_server(
+    config: ServerConfig,
+    handler: BoxedDynRunner,
+    shutdown_signal: tokio::sync::oneshot::Receiver<()>,
+) -> Result<(), anyhow::Error> {
+    let access_log = config
+        .access_log
+        .as_ref()
+        .map(access_log::AccessLog::open)
+        .transpose()?
+        .map(Arc::new);
+
+    let context = AppContext {
+        runner: handler.clone(),
+        access_log: access_log.clone(),
+        health_paths: config.health_paths,
+        trusted_proxies: config.trusted_proxies.clone().into(),
+        max_body_size: config.limits.max_body_size,
+        compression: config.compression.clone().map(Arc::new),
+    };
+
+    let make_service = make_service_fn(move |conn: &Connection| {
+        let context = context.clone();
+
+        let addr = conn.remote_addr();
+        let info = conn.info().clone();
+        let activity = conn.activity();
+
+        // Create a `Service` for responding to the request.
+        let service = service_fn(move |mut req: Request<Body>| {
+            req.extensions_mut().insert(info.clone());
+            let active_request = activity.as_ref().map(|a| a.start_request());
+            handle(context.clone(), addr, req, active_request)
+        });
+
+        // Return the service to hyper.
+        async move { Ok::<_, Infallible>(service) }
+    });
+
+    let tls = config
+        .tls
+        .as_ref()
+        .map(|tls| tls::TlsTerminator::new(tls, config.http2))
+        .transpose()
+        .context("Failed to set up TLS")?;
+    if let Some(ref tls) = tls {
+        tokio::spawn(tls.clone().watch_for_changes());
+    }
+
+    if let Some(addr) = config.health_addr {
+        health::start(addr)?;
+    }
+
+    if let Some(addr) = config.metrics_addr {
+        metrics::start(addr)?;
+    }
+
+    if let Some(addr) = config.admin_addr {
+        admin::start(addr, handler.clone())?;
+    }
+
+    #[cfg(unix)]
+    if handler.supports_reload() {
+        tokio::spawn(admin::reload_on_sighup(handler.clone()));
+    }
+
+    if config.listen.is_empty() {
+        anyhow::bail!("No addresses to listen on");
+    }
+
+    let (incoming, tx) = Incoming::new();
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    let accept_options = listener::AcceptOptions {
+        tls,
+        idle_timeout: config.limits.idle_timeout,
+        proxy_protocol: config.proxy_protocol,
+    };
+    #[cfg(unix)]
+    let mut listener_fds = vec![];
+    for addr in &config.listen {
+        let listener = Listener::bind(addr, &config).await?;
+        tracing::info!(listen=%addr, "starting server on '{scheme}://{addr}'");
+        #[cfg(unix)]
+        listener_fds.push(listener.as_raw_fd());
+        tokio::spawn(listener::accept(
+            listener,
+            accept_options.clone(),
+            tx.clone(),
+        ));
+    }
+    drop(tx);
+
+    // Only let the previous process go once the user code has initialized,
+    // so a broken deployment doesn't replace a working one.
+    #[cfg(unix)]
+    tokio::spawn(async {
+        health::wait_until_initialized().await;
+        handoff::notify_ready();
+    });
+
+    let (restart_tx, restart_rx) = tokio::sync::oneshot::channel();
+    #[cfg(unix)]
+    if config.zero_downtime_restart {
+        tokio::spawn(async move {
+            handoff::wait_for_restart(listener_fds).await;
+            _ = restart_tx.send(());
+        });
+    }
+    #[cfg(not(unix))]
+    drop(restart_tx);
+
+    let handed_off = Arc::new(AtomicBool::new(false));
+    let handed_off_clone = handed_off.clone();
+
+    // Without http1_only, hyper starts out speaking HTTP/1.1 and switches
+    // to HTTP/2 if the connection begins with the HTTP/2 preface, which
+    // covers both h2c with prior knowledge and h2 negotiated through ALPN.
+    let mut builder = Server::builder(incoming).http1_only(!config.http2);
+    if let Some(size) = config.limits.max_header_size {
+        // hyper keeps the request head in its read buffer, so capping the
+        // buffer caps the header size on HTTP/1
+        builder = builder
+            .http1_max_buf_size(size)
+            .http2_max_header_list_size(size.try_into().unwrap_or(u32::MAX));
+    }
+    if let Some(timeout) = config.limits.header_read_timeout {
+        builder = builder.http1_header_read_timeout(timeout);
+    }
+
+    builder
+        .serve(make_service)
+        .with_graceful_shutdown(async move {
+            tokio::select! {
+                _ = shutdown_signal => (),
+                Ok(()) = restart_rx => handed_off_clone.store(true, Ordering::SeqCst),
+            }
+        })
+        .await
+        .context("hyper server failed")?;
+
+    // Every response has been sent by now, so the last lines are queued
+    if let Some(access_log) = access_log {
+        _ = tokio::task::spawn_blocking(move || access_log.close()).await;
+    }
+
+    if handed_off.load(Ordering::SeqCst) {
+        // The new process is serving from the same sockets now, so we must
+        // leave Unix socket files alone.
+        handler.shutdown(config.shutdown_timeout).await;
+        return Ok(());
+    }
+
+    #[cfg(unix)]
+    for addr in &config.listen {
+        if let ListenAddr::Unix(path) = addr {
+            _ = std::fs::remove_file(path);
+        }
+    }
+
+    Ok(())
+}
+
+#[async_trait]
+#[dyn_clonable::clonable]
+pub trait Runner: Send + Sync + Clone + 'static {
+    async fn handle(
+        &self,
+        addr: SocketAddr,
+        req: http::request::Parts,
+        body: hyper::Body,
+    ) -> anyhow::Result<hyper::Response<hyper::Body>>;
+
+    /// Loads the user code again and switches new requests over to it once
+    /// it has initialized. Requests that are already being handled finish on
+    /// the previous version.
+    async fn reload(&self) -> anyhow::Result<()> {
+        anyhow::bail!("Reloading is not supported by this runner")
+    }
+
+    /// Whether [`Runner::reload`] can succeed at all. SIGHUP is only taken
+    /// over for reloading when it can; otherwise it keeps terminating the
+    /// process as usual.
+    fn supports_reload(&self) -> bool {
+        false
+    }
+
+    async fn shutdown(&self, timeout: Option<Duration>);
+}
+
+pub type BoxedDynRunner = Box<dyn Runner>;
+
+#[derive(Clone)]
+struct AppContext {
+    runner: BoxedDynRunner,
+    access_log: Option<Arc<access_log::AccessLog>>,
+    health_paths: bool,
+    trusted_proxies: Arc<[client_addr::TrustedProxy]>,
+    max_body_size: Option<u64>,
+    compression: Option<Arc<compression::CompressionConfig>>,
+}
+
+async fn handle(
+    context: AppContext,
+    addr: SocketAddr,
+    mut req: Request<Body>,
+    active_request: Option<ActiveRequest>,
+) -> Result<Response<ResponseBody>, Infallible> {
+    if let Some(ip) = client_addr::resolve(addr, req.headers(), &context.trusted_proxies) {
+        req.extensions_mut().insert(ClientAddr(ip));
+    }
+
+    let upgrade = websocket::UpgradeRequest::take(&mut req, active_request.as_ref());
+
+    let log = context
+        .access_log
+        .clone()
+        .map(|log| (log, access_log::RequestInfo::new(addr, &req)));
+    let respond = move |res: Response<Body>| {
+        let log = log.map(|(log, info)| access_log::PendingEntry::new(log, info, &res));
+        Ok(ResponseBody::wrap(res, log, active_request))
+    };
+
+    if context.health_paths {
+        if let Some(res) = health::probe_response(req.uri().path()) {
+            return respond(res);
+        }
+    }
+
+    let started = std::time::Instant::now();
+
+    // HEAD responses have no body to compress
+    let encoding = match context.compression {
+        Some(_) if req.method() != hyper::Method::HEAD => compression::negotiate(req.headers()),
+        _ => None,
+    };
+    let compression = context.compression.clone();
+
+    let (req, body_too_large) = match context.max_body_size {
+        Some(max) => match limits::limit_body(req, max) {
+            Some((req, exceeded)) => (req, Some(exceeded)),
+            None => {
+                let res = limits::payload_too_large();
+                metrics::record_request(res.status(), started.elapsed());
+                return respond(res);
+            }
+        },
+        None => (req, None),
+    };
+
+    let res = match handle_inner(context, addr, req).await {
+        Ok(r) => r,
+        Err(err) => {
+            tracing::error!(error = format!("{err:#?}"), "could not process request");
+
+            hyper::Response::builder()
+                .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
+                .body(hyper::Body::from(err.to_string()))
+                .unwrap()
+        }
+    };
+
+    // Whatever the user code made of a truncated body, the client should
+    // learn that it sent too much
+    let res = match body_too_large {
+        Some(exceeded) if exceeded.load(Ordering::SeqCst) => limits::payload_too_large(),
+        _ => res,
+    };
+
+    let res = match compression {
+        Some(config) => compression::compress(&config, encoding, res),
+        None => res,
+    };
+
+    let res = websocket::accept(upgrade, res);
+
+    metrics::record_request(res.status(), started.elapsed());
+
+    respond(res)
+}
+
+async fn handle_inner(
+    context: AppContext,
+    addr: SocketAddr,
+    req: Request<Body>,
+) -> Result<Response<Body>, anyhow::Error> {
+    let (parts, body) = req.into_parts();
+    context
+        .runner
+        .handle(addr, parts, body)
+        .await
+        .context("JavaScript failed")
+}
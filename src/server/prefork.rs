@@ -0,0 +1,318 @@
+//! Prefork mode. A supervisor process starts several WinterJS worker
+//! processes with the same arguments, all serving on the same addresses.
+//! Each worker binds its own TCP listeners with `SO_REUSEPORT`, so the
+//! kernel spreads connections across them. Unix domain sockets and
+//! inherited sockets can't be shared that way, so the supervisor binds
+//! them once and passes them to every worker.
+//!
+//! Workers that exit are restarted, so a crash only takes down the
+//! requests that worker was handling. On Ctrl+C, the supervisor forwards
+//! the signal to the workers and waits for them to shut down cleanly.
+//! SIGHUP is forwarded as well, so each worker reloads the user code.
+
+use std::{
+    os::unix::io::{AsRawFd, OwnedFd, RawFd},
+    process::{Child, ExitStatus},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Context as _;
+
+use super::{
+    child_process::{pass_fds, set_cloexec, winterjs_command, FIRST_FD},
+    listener::bind_unix,
+    ListenAddr,
+};
+
+const WORKER_VAR: &str = "WINTERJS_PREFORK_WORKER";
+const LISTEN_VAR: &str = "WINTERJS_PREFORK_LISTEN";
+
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Workers that exit sooner than this after starting are most likely
+/// failing to start at all, so we wait for [`RESTART_DELAY`] before trying
+/// again instead of restarting them in a tight loop.
+const MIN_UPTIME: Duration = Duration::from_secs(5);
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+static RELOAD_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_reload(_signal: libc::c_int) {
+    RELOAD_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+#[derive(Debug, PartialEq)]
+pub struct WorkerConfig {
+    pub index: usize,
+
+    /// The addresses to serve on, which replace any configured addresses.
+    pub listen: Vec<ListenAddr>,
+}
+
+/// Returns the worker's configuration if we were started by a prefork
+/// supervisor.
+pub fn take_worker_config() -> anyhow::Result<Option<WorkerConfig>> {
+    let index = std::env::var(WORKER_VAR).ok();
+    let listen = std::env::var(LISTEN_VAR).ok();
+    std::env::remove_var(WORKER_VAR);
+    std::env::remove_var(LISTEN_VAR);
+
+    parse_worker_env(index.as_deref(), listen.as_deref())
+}
+
+/// Parses the variables [`spawn_worker`] sets, given as `index` and
+/// `listen`. Returns `None` if we weren't started by a supervisor.
+fn parse_worker_env(
+    index: Option<&str>,
+    listen: Option<&str>,
+) -> anyhow::Result<Option<WorkerConfig>> {
+    let Some(index) = index else {
+        return Ok(None);
+    };
+    let index = index
+        .parse()
+        .with_context(|| format!("Invalid worker index in {WORKER_VAR}: '{index}'"))?;
+
+    let listen = listen.with_context(|| format!("{LISTEN_VAR} is not set"))?;
+    let listen = listen
+        .split(',')
+        .map(|addr| {
+            addr.parse()
+                .map_err(|e| anyhow::anyhow!("Invalid address in {LISTEN_VAR}: '{addr}': {e}"))
+        })
+        .collect::<Result<_, _>>()?;
+
+    Ok(Some(WorkerConfig { index, listen }))
+}
+
+struct Worker {
+    index: usize,
+    child: Option<(Child, Instant)>,
+    restart_at: Instant,
+}
+
+/// Runs the supervisor until Ctrl+C is pressed and all workers have shut
+/// down.
+pub fn supervise(
+    processes: usize,
+    listen: Vec<ListenAddr>,
+    unix_socket_mode: Option<u32>,
+) -> anyhow::Result<()> {
+    let mut shared_sockets: Vec<OwnedFd> = vec![];
+    let mut passed_fds: Vec<RawFd> = vec![];
+    let mut worker_listen = vec![];
+    for addr in &listen {
+        let fd = match addr {
+            ListenAddr::Tcp(_) => {
+                worker_listen.push(addr.to_string());
+                continue;
+            }
+            ListenAddr::Unix(path) => {
+                let socket = OwnedFd::from(bind_unix(path, unix_socket_mode)?);
+                let fd = socket.as_raw_fd();
+                shared_sockets.push(socket);
+                fd
+            }
+            ListenAddr::Fd(fd) => {
+                // We won't be using these ourselves, but they shouldn't
+                // leak into anything other than the workers either.
+                set_cloexec(*fd)?;
+                *fd
+            }
+        };
+        let worker_fd = FIRST_FD + passed_fds.len() as RawFd;
+        worker_listen.push(ListenAddr::Fd(worker_fd).to_string());
+        passed_fds.push(fd);
+    }
+    let worker_listen = worker_listen.join(",");
+
+    let shutting_down = Arc::new(AtomicBool::new(false));
+    let shutting_down_clone = shutting_down.clone();
+    ctrlc::set_handler(move || shutting_down_clone.store(true, Ordering::SeqCst))
+        .context("Failed to set Ctrl-C handler")?;
+    if unsafe {
+        libc::signal(
+            libc::SIGHUP,
+            request_reload as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        )
+    } == libc::SIG_ERR
+    {
+        return Err(std::io::Error::last_os_error()).context("Failed to set SIGHUP handler");
+    }
+
+    tracing::info!("Starting {processes} worker processes");
+    let now = Instant::now();
+    let mut workers = (0..processes)
+        .map(|index| Worker {
+            index,
+            child: None,
+            restart_at: now,
+        })
+        .collect::<Vec<_>>();
+
+    while !shutting_down.load(Ordering::SeqCst) {
+        if RELOAD_REQUESTED.swap(false, Ordering::SeqCst) {
+            tracing::info!("Received SIGHUP, reloading worker processes");
+            for (child, _) in workers.iter().filter_map(|w| w.child.as_ref()) {
+                unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGHUP) };
+            }
+        }
+
+        for worker in &mut workers {
+            if let Some((child, started)) = &mut worker.child {
+                match child.try_wait() {
+                    Ok(None) => continue,
+                    Ok(Some(status)) => {
+                        tracing::error!(
+                            worker = worker.index,
+                            pid = child.id(),
+                            "Worker process {}, restarting it",
+                            describe_exit(status)
+                        );
+                        worker.restart_at = if started.elapsed() < MIN_UPTIME {
+                            Instant::now() + RESTART_DELAY
+                        } else {
+                            Instant::now()
+                        };
+                        worker.child = None;
+                    }
+                    Err(e) => {
+                        tracing::error!(worker = worker.index, error = %e, "Failed to check on worker process");
+                        continue;
+                    }
+                }
+            }
+
+            if Instant::now() < worker.restart_at {
+                continue;
+            }
+
+            match spawn_worker(worker.index, &worker_listen, &passed_fds) {
+                Ok(child) => {
+                    tracing::info!(
+                        worker = worker.index,
+                        pid = child.id(),
+                        "Started worker process"
+                    );
+                    worker.child = Some((child, Instant::now()));
+                }
+                Err(e) => {
+                    tracing::error!(
+                        worker = worker.index,
+                        error = format!("{e:#}"),
+                        "Failed to start worker process"
+                    );
+                    worker.restart_at = Instant::now() + RESTART_DELAY;
+                }
+            }
+        }
+
+        std::thread::sleep(POLL_INTERVAL);
+    }
+
+    tracing::info!("Shutting down worker processes");
+    let children = workers
+        .into_iter()
+        .filter_map(|w| w.child.map(|(child, _)| (w.index, child)))
+        .collect::<Vec<_>>();
+
+    // Workers in the same process group have received the signal from the
+    // terminal already, but not if it was sent to us alone. Each worker only
+    // acts on the first one.
+    for (_, child) in &children {
+        unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGINT) };
+    }
+
+    for (index, mut child) in children {
+        match child.wait() {
+            Ok(status) if status.success() => (),
+            Ok(status) => {
+                tracing::warn!(worker = index, "Worker process {}", describe_exit(status))
+            }
+            Err(e) => {
+                tracing::error!(worker = index, error = %e, "Failed to wait for worker process")
+            }
+        }
+    }
+
+    for addr in &listen {
+        if let ListenAddr::Unix(path) = addr {
+            _ = std::fs::remove_file(path);
+        }
+    }
+
+    Ok(())
+}
+
+fn spawn_worker(index: usize, listen: &str, fds: &[RawFd]) -> anyhow::Result<Child> {
+    let mut command = winterjs_command()?;
+    command
+        .env(WORKER_VAR, index.to_string())
+        .env(LISTEN_VAR, listen);
+    let passed_fds = pass_fds(&mut command, fds)?;
+
+    // Don't leave workers holding on to the port if the supervisor is killed
+    #[cfg(target_os = "linux")]
+    unsafe {
+        use std::os::unix::process::CommandExt;
+
+        command.pre_exec(|| {
+            if libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGINT) == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+
+    let child = command.spawn().context("Failed to spawn worker process")?;
+    drop(passed_fds);
+    Ok(child)
+}
+
+fn describe_exit(status: ExitStatus) -> String {
+    use std::os::unix::process::ExitStatusExt;
+
+    match (status.code(), status.signal()) {
+        (Some(code), _) => format!("exited with code {code}"),
+        (None, Some(signal)) => format!("was killed by signal {signal}"),
+        _ => "exited".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_worker_config() {
+        assert_eq!(
+            parse_worker_env(Some("2"), Some("0.0.0.0:8080,fd:3,fd:4")).unwrap(),
+            Some(WorkerConfig {
+                index: 2,
+                listen: vec![
+                    ListenAddr::Tcp("0.0.0.0:8080".parse().unwrap()),
+                    ListenAddr::Fd(3),
+                    ListenAddr::Fd(4),
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn ignores_missing_worker_config() {
+        assert_eq!(parse_worker_env(None, None).unwrap(), None);
+        assert_eq!(parse_worker_env(None, Some("fd:3")).unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_invalid_worker_config() {
+        assert!(parse_worker_env(Some("2"), None).is_err());
+        assert!(parse_worker_env(Some("two"), Some("fd:3")).is_err());
+        assert!(parse_worker_env(Some("2"), Some("")).is_err());
+        assert!(parse_worker_env(Some("2"), Some("fd:3,localhost")).is_err());
+    }
+}
@@ -0,0 +1,285 @@
+//! The PROXY protocol, which load balancers use to pass on the address of
+//! the client they accepted a connection from by sending a header before
+//! any other data. Both the text (v1) and binary (v2) versions are
+//! supported. See https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Including the CRLF, as per the spec.
+const V1_MAX_LENGTH: usize = 107;
+
+/// Reads the PROXY protocol header from the start of a connection, and
+/// returns the source address it contains. Returns `None` for connections
+/// the proxy made on its own behalf (such as health checks) and for
+/// addresses that aren't TCP over IP, in which case the peer address
+/// should be used as usual.
+///
+/// Exactly the header is consumed, so the stream can be used as normal
+/// afterwards.
+pub async fn read_header<S: AsyncRead + Unpin + ?Sized>(
+    stream: &mut S,
+) -> io::Result<Option<SocketAddr>> {
+    let mut start = [0u8; 12];
+    stream.read_exact(&mut start).await?;
+
+    if start == V2_SIGNATURE {
+        read_v2(stream).await
+    } else if start.starts_with(V1_PREFIX) {
+        read_v1(stream, &start).await
+    } else {
+        Err(invalid(
+            "connection did not start with a PROXY protocol header",
+        ))
+    }
+}
+
+async fn read_v1<S: AsyncRead + Unpin + ?Sized>(
+    stream: &mut S,
+    start: &[u8],
+) -> io::Result<Option<SocketAddr>> {
+    // There's no length up front, and we mustn't read past the header, so
+    // it has to be read one byte at a time. It's short enough.
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LENGTH {
+            return Err(invalid("PROXY protocol v1 header is too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+
+    let line = std::str::from_utf8(&line[V1_PREFIX.len()..line.len() - 2])
+        .map_err(|_| invalid("PROXY protocol v1 header is not valid text"))?;
+    let mut fields = line.split(' ');
+    match fields.next() {
+        Some("UNKNOWN") => Ok(None),
+        Some(protocol @ ("TCP4" | "TCP6")) => {
+            let (Some(src), Some(_dst), Some(src_port), Some(_dst_port), None) = (
+                fields.next(),
+                fields.next(),
+                fields.next(),
+                fields.next(),
+                fields.next(),
+            ) else {
+                return Err(invalid("malformed PROXY protocol v1 header"));
+            };
+
+            let src = match protocol {
+                "TCP4" => src.parse::<Ipv4Addr>().map(IpAddr::from),
+                _ => src.parse::<Ipv6Addr>().map(IpAddr::from),
+            }
+            .map_err(|_| invalid("invalid source address in PROXY protocol v1 header"))?;
+            let src_port = src_port
+                .parse::<u16>()
+                .map_err(|_| invalid("invalid source port in PROXY protocol v1 header"))?;
+
+            Ok(Some(SocketAddr::new(src, src_port)))
+        }
+        _ => Err(invalid("unsupported protocol in PROXY protocol v1 header")),
+    }
+}
+
+async fn read_v2<S: AsyncRead + Unpin + ?Sized>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header).await?;
+    let [version_command, family_protocol, len_hi, len_lo] = header;
+
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+
+    // The rest of the header includes the addresses followed by optional
+    // TLVs, which we don't use but still need to consume
+    let mut rest = vec![0u8; u16::from_be_bytes([len_hi, len_lo]) as usize];
+    stream.read_exact(&mut rest).await?;
+
+    match version_command & 0x0f {
+        // LOCAL: the proxy connected on its own behalf
+        0x0 => return Ok(None),
+        // PROXY
+        0x1 => (),
+        _ => return Err(invalid("unsupported PROXY protocol v2 command")),
+    }
+
+    // Only TCP (STREAM) over IPv4 or IPv6 has an address we can use
+    match family_protocol {
+        0x11 if rest.len() >= 12 => {
+            let src = Ipv4Addr::from(<[u8; 4]>::try_from(&rest[0..4]).unwrap());
+            let src_port = u16::from_be_bytes([rest[8], rest[9]]);
+            Ok(Some(SocketAddr::new(src.into(), src_port)))
+        }
+        0x21 if rest.len() >= 36 => {
+            let src = Ipv6Addr::from(<[u8; 16]>::try_from(&rest[0..16]).unwrap());
+            let src_port = u16::from_be_bytes([rest[32], rest[33]]);
+            Ok(Some(SocketAddr::new(src.into(), src_port)))
+        }
+        0x11 | 0x21 => Err(invalid("PROXY protocol v2 address block is too short")),
+        _ => Ok(None),
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads the header from `data`, and returns what's left of the stream
+    /// after it.
+    async fn read(data: &[u8]) -> io::Result<(Option<SocketAddr>, Vec<u8>)> {
+        let mut stream = data;
+        let addr = read_header(&mut stream).await?;
+        Ok((addr, stream.to_vec()))
+    }
+
+    fn v2(command: u8, family_protocol: u8, rest: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(0x20 | command);
+        header.push(family_protocol);
+        header.extend_from_slice(&(rest.len() as u16).to_be_bytes());
+        header.extend_from_slice(rest);
+        header
+    }
+
+    #[tokio::test]
+    async fn v1_tcp4() {
+        let (addr, rest) = read(b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 443\r\nGET / HTTP/1.1\r\n")
+            .await
+            .unwrap();
+        assert_eq!(addr, Some("203.0.113.7:51234".parse().unwrap()));
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n");
+    }
+
+    #[tokio::test]
+    async fn v1_tcp6() {
+        let (addr, rest) = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 51234 443\r\n")
+            .await
+            .unwrap();
+        assert_eq!(addr, Some("[2001:db8::1]:51234".parse().unwrap()));
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn v1_unknown() {
+        let (addr, rest) = read(b"PROXY UNKNOWN\r\nGET").await.unwrap();
+        assert_eq!(addr, None);
+        assert_eq!(rest, b"GET");
+    }
+
+    #[tokio::test]
+    async fn v1_malformed() {
+        for header in [
+            &b"PROXY TCP4 203.0.113.7 10.0.0.1 51234\r\n"[..],
+            b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 443 extra\r\n",
+            b"PROXY TCP4 2001:db8::1 10.0.0.1 51234 443\r\n",
+            b"PROXY TCP6 203.0.113.7 10.0.0.1 51234 443\r\n",
+            b"PROXY TCP4 203.0.113.7 10.0.0.1 70000 443\r\n",
+            b"PROXY UDP4 203.0.113.7 10.0.0.1 51234 443\r\n",
+        ] {
+            let err = read(header).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{header:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn v1_too_long() {
+        let mut header = b"PROXY TCP4 ".to_vec();
+        header.resize(200, b'1');
+        let err = read(&header).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn v1_truncated() {
+        let err = read(b"PROXY TCP4 203.0.113.7").await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn v2_tcp4() {
+        let mut header = v2(
+            0x1,
+            0x11,
+            &[203, 0, 113, 7, 10, 0, 0, 1, 0xc8, 0x22, 0x01, 0xbb],
+        );
+        header.extend_from_slice(b"GET");
+        let (addr, rest) = read(&header).await.unwrap();
+        assert_eq!(addr, Some("203.0.113.7:51234".parse().unwrap()));
+        assert_eq!(rest, b"GET");
+    }
+
+    #[tokio::test]
+    async fn v2_tcp6_with_tlvs() {
+        let mut block = vec![];
+        block.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        block.extend_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        block.extend_from_slice(&[0xc8, 0x22, 0x01, 0xbb]);
+        // A PP2_TYPE_NOOP TLV, which has to be skipped
+        block.extend_from_slice(&[0x04, 0x00, 0x02, 0x00, 0x00]);
+
+        let mut header = v2(0x1, 0x21, &block);
+        header.extend_from_slice(b"GET");
+        let (addr, rest) = read(&header).await.unwrap();
+        assert_eq!(addr, Some("[2001:db8::1]:51234".parse().unwrap()));
+        assert_eq!(rest, b"GET");
+    }
+
+    #[tokio::test]
+    async fn v2_local() {
+        let (addr, rest) = read(&v2(0x0, 0x00, &[])).await.unwrap();
+        assert_eq!(addr, None);
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn v2_unsupported_family() {
+        // AF_UNIX addresses are two 108 byte paths
+        let (addr, _) = read(&v2(0x1, 0x31, &[0; 216])).await.unwrap();
+        assert_eq!(addr, None);
+    }
+
+    #[tokio::test]
+    async fn v2_bad_version_and_command() {
+        let mut header = v2(0x1, 0x11, &[0; 12]);
+        header[12] = 0x11;
+        let err = read(&header).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let err = read(&v2(0x2, 0x11, &[0; 12])).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn v2_short_address_block() {
+        let err = read(&v2(0x1, 0x11, &[0; 8])).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn v2_truncated() {
+        let header = v2(0x1, 0x11, &[0; 12]);
+        let err = read(&header[..20]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        let err = read(&header[..14]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn no_header() {
+        let err = read(b"GET / HTTP/1.1\r\n").await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let err = read(b"GET").await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
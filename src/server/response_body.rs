@@ -0,0 +1,76 @@
+//! The body type the server hands to hyper. It wraps the runner's response
+//! body to keep track of what happens to the response after the runner is
+//! done with it: the access log line and the end of the request, as far as
+//! the connection's idle timeout is concerned, both wait for the body to be
+//! sent in full (or for the client to go away).
+
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use hyper::{body::HttpBody, Body};
+
+use super::{access_log::PendingEntry, listener::ActiveRequest};
+
+pub struct ResponseBody {
+    inner: Body,
+    access_log: Option<PendingEntry>,
+    active_request: Option<ActiveRequest>,
+}
+
+impl ResponseBody {
+    pub fn wrap(
+        response: hyper::Response<Body>,
+        access_log: Option<PendingEntry>,
+        active_request: Option<ActiveRequest>,
+    ) -> hyper::Response<Self> {
+        response.map(|inner| Self {
+            inner,
+            access_log,
+            active_request,
+        })
+    }
+
+    fn finish(&mut self) {
+        self.access_log = None;
+        self.active_request = None;
+    }
+}
+
+impl HttpBody for ResponseBody {
+    type Data = <Body as HttpBody>::Data;
+    type Error = <Body as HttpBody>::Error;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let result = Pin::new(&mut self.inner).poll_data(cx);
+        match &result {
+            Poll::Ready(Some(Ok(data))) => {
+                if let Some(entry) = &mut self.access_log {
+                    entry.record_data(data.len());
+                }
+            }
+            Poll::Ready(None) => self.finish(),
+            _ => (),
+        }
+        result
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<http::HeaderMap>, Self::Error>> {
+        Pin::new(&mut self.inner).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> hyper::body::SizeHint {
+        self.inner.size_hint()
+    }
+}
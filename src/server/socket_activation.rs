@@ -0,0 +1,160 @@
+//! systemd-style socket activation. The supervisor opens the listening
+//! sockets and passes them to us as file descriptors starting at 3. The
+//! `LISTEN_PID` and `LISTEN_FDS` environment variables identify the process
+//! the sockets are meant for and how many there are, and `LISTEN_FDNAMES`
+//! optionally names them.
+
+use std::os::unix::io::RawFd;
+
+use anyhow::{bail, Context as _};
+
+use super::ListenAddr;
+
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Returns the sockets passed in by the supervisor, if any. The environment
+/// variables are removed so they don't leak into child processes or JS code.
+pub fn take_listen_fds() -> anyhow::Result<Vec<ListenAddr>> {
+    let env = ListenEnv {
+        pid: std::env::var("LISTEN_PID").ok(),
+        fds: std::env::var("LISTEN_FDS").ok(),
+        fd_names: std::env::var("LISTEN_FDNAMES").ok(),
+    };
+    for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(var);
+    }
+
+    let sockets = env.sockets_for(std::process::id())?;
+    if !sockets.is_empty() {
+        tracing::info!(
+            "Received {} socket(s) through socket activation",
+            sockets.len()
+        );
+    }
+    Ok(sockets
+        .into_iter()
+        .map(|(fd, name)| {
+            if let Some(name) = name {
+                tracing::debug!(fd, name, "Received named socket");
+            }
+            ListenAddr::Fd(fd)
+        })
+        .collect())
+}
+
+/// The socket activation environment variables.
+struct ListenEnv {
+    pid: Option<String>,
+    fds: Option<String>,
+    fd_names: Option<String>,
+}
+
+impl ListenEnv {
+    /// The sockets meant for the process `pid`, along with their names.
+    fn sockets_for(&self, pid: u32) -> anyhow::Result<Vec<(RawFd, Option<String>)>> {
+        let Some(listen_pid) = &self.pid else {
+            return Ok(vec![]);
+        };
+        let listen_pid: u32 = listen_pid
+            .parse()
+            .with_context(|| format!("Invalid process ID in LISTEN_PID: '{listen_pid}'"))?;
+        if listen_pid != pid {
+            tracing::warn!(
+                "Ignoring sockets in LISTEN_FDS, since they were meant for another process"
+            );
+            return Ok(vec![]);
+        }
+
+        let fds = self
+            .fds
+            .as_deref()
+            .context("LISTEN_PID was set without LISTEN_FDS")?;
+        let count: RawFd = fds
+            .parse()
+            .with_context(|| format!("Invalid socket count in LISTEN_FDS: '{fds}'"))?;
+        if count < 0 {
+            bail!("Invalid socket count in LISTEN_FDS: '{fds}'");
+        }
+
+        // Names that don't match up with the sockets are left out rather
+        // than attached to the wrong ones
+        let mut names = match self.fd_names.as_deref().map(|names| names.split(':')) {
+            Some(names) if names.clone().count() == count as usize => names
+                .map(|name| (!name.is_empty()).then(|| name.to_string()))
+                .collect(),
+            Some(_) => {
+                tracing::warn!("Ignoring LISTEN_FDNAMES, since it doesn't name every socket");
+                vec![]
+            }
+            None => vec![],
+        }
+        .into_iter();
+
+        Ok((LISTEN_FDS_START..LISTEN_FDS_START + count)
+            .map(|fd| (fd, names.next().flatten()))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(pid: Option<&str>, fds: Option<&str>, fd_names: Option<&str>) -> ListenEnv {
+        ListenEnv {
+            pid: pid.map(String::from),
+            fds: fds.map(String::from),
+            fd_names: fd_names.map(String::from),
+        }
+    }
+
+    #[test]
+    fn takes_sockets_meant_for_this_process() {
+        assert_eq!(
+            env(Some("42"), Some("2"), None).sockets_for(42).unwrap(),
+            vec![(3, None), (4, None)]
+        );
+        assert_eq!(
+            env(Some("42"), Some("0"), None).sockets_for(42).unwrap(),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn ignores_sockets_meant_for_other_processes() {
+        assert_eq!(env(None, None, None).sockets_for(42).unwrap(), vec![]);
+        assert_eq!(env(None, Some("2"), None).sockets_for(42).unwrap(), vec![]);
+        assert_eq!(
+            env(Some("41"), Some("2"), None).sockets_for(42).unwrap(),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_variables() {
+        assert!(env(Some("pid"), Some("2"), None).sockets_for(42).is_err());
+        assert!(env(Some("42"), None, None).sockets_for(42).is_err());
+        assert!(env(Some("42"), Some("two"), None).sockets_for(42).is_err());
+        assert!(env(Some("42"), Some("-1"), None).sockets_for(42).is_err());
+    }
+
+    #[test]
+    fn names_sockets() {
+        assert_eq!(
+            env(Some("42"), Some("3"), Some("http::admin"))
+                .sockets_for(42)
+                .unwrap(),
+            vec![
+                (3, Some("http".to_string())),
+                (4, None),
+                (5, Some("admin".to_string()))
+            ]
+        );
+        assert_eq!(
+            env(Some("42"), Some("2"), Some("http"))
+                .sockets_for(42)
+                .unwrap(),
+            vec![(3, None), (4, None)]
+        );
+    }
+}
@@ -0,0 +1,307 @@
+//! HTTPS termination for the built-in server.
+//!
+//! Certificates are loaded from PEM files and selected based on the SNI
+//! server name sent by the client, falling back to the default certificate.
+//! The files are polled for changes, so renewed certificates are picked up
+//! without restarting the server.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, bail, Context as _};
+use parking_lot::RwLock;
+use rustls::{
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    pub default_certificate: CertificatePaths,
+    pub sni_certificates: Vec<SniCertificate>,
+}
+
+#[derive(Clone, Debug)]
+pub struct CertificatePaths {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// A certificate that's only served to clients requesting a specific
+/// server name. Parsed from `<server name>=<cert path>,<key path>`.
+#[derive(Clone, Debug)]
+pub struct SniCertificate {
+    pub server_name: String,
+    pub paths: CertificatePaths,
+}
+
+impl FromStr for SniCertificate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (server_name, paths) = s
+            .split_once('=')
+            .ok_or_else(|| "expected <server name>=<cert path>,<key path>".to_string())?;
+        let (cert, key) = paths.split_once(',').ok_or_else(|| {
+            "expected a certificate and a key path separated by a comma".to_string()
+        })?;
+
+        if server_name.is_empty() || cert.is_empty() || key.is_empty() {
+            return Err("server name, certificate and key paths must not be empty".to_string());
+        }
+
+        Ok(Self {
+            server_name: server_name.to_ascii_lowercase(),
+            paths: CertificatePaths {
+                cert: cert.into(),
+                key: key.into(),
+            },
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct TlsTerminator {
+    acceptor: tokio_rustls::TlsAcceptor,
+    resolver: Arc<CertificateResolver>,
+}
+
+impl TlsTerminator {
+    pub fn new(config: &TlsConfig, http2: bool) -> anyhow::Result<Self> {
+        let resolver = Arc::new(CertificateResolver::load(config)?);
+
+        let mut server_config = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_cert_resolver(resolver.clone());
+        server_config.alpn_protocols = if http2 {
+            vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+        } else {
+            vec![b"http/1.1".to_vec()]
+        };
+
+        Ok(Self {
+            acceptor: tokio_rustls::TlsAcceptor::from(Arc::new(server_config)),
+            resolver,
+        })
+    }
+
+    pub fn acceptor(&self) -> &tokio_rustls::TlsAcceptor {
+        &self.acceptor
+    }
+
+    /// Periodically checks the certificate files and reloads them when
+    /// they change. Failing to load the new files keeps the old
+    /// certificates in place.
+    pub async fn watch_for_changes(self) {
+        loop {
+            tokio::time::sleep(RELOAD_CHECK_INTERVAL).await;
+            self.resolver.reload_changed();
+        }
+    }
+}
+
+struct LoadedCertificate {
+    paths: CertificatePaths,
+    modified: (Option<SystemTime>, Option<SystemTime>),
+    key: Arc<CertifiedKey>,
+}
+
+impl LoadedCertificate {
+    fn load(paths: &CertificatePaths) -> anyhow::Result<Self> {
+        // Read the modification times first, so a file that's replaced while
+        // we're loading it is picked up again on the next check.
+        let modified = modification_times(paths);
+        let key = load_certified_key(paths)?;
+        Ok(Self {
+            paths: paths.clone(),
+            modified,
+            key: Arc::new(key),
+        })
+    }
+
+    fn is_stale(&self) -> bool {
+        modification_times(&self.paths) != self.modified
+    }
+}
+
+struct Certificates {
+    default: LoadedCertificate,
+    by_name: HashMap<String, LoadedCertificate>,
+}
+
+struct CertificateResolver {
+    certificates: RwLock<Certificates>,
+}
+
+impl CertificateResolver {
+    fn load(config: &TlsConfig) -> anyhow::Result<Self> {
+        let default = LoadedCertificate::load(&config.default_certificate)?;
+
+        let mut by_name = HashMap::new();
+        for sni in &config.sni_certificates {
+            let cert = LoadedCertificate::load(&sni.paths)
+                .with_context(|| format!("Failed to load certificate for '{}'", sni.server_name))?;
+            if by_name.insert(sni.server_name.clone(), cert).is_some() {
+                bail!(
+                    "Duplicate certificate for server name '{}'",
+                    sni.server_name
+                );
+            }
+        }
+
+        tracing::info!(
+            "Loaded TLS certificates for {} server name(s) in addition to the default certificate",
+            by_name.len()
+        );
+
+        Ok(Self {
+            certificates: RwLock::new(Certificates { default, by_name }),
+        })
+    }
+
+    fn reload_changed(&self) {
+        let mut certificates = self.certificates.write();
+        let Certificates { default, by_name } = &mut *certificates;
+
+        for cert in std::iter::once(default).chain(by_name.values_mut()) {
+            if !cert.is_stale() {
+                continue;
+            }
+
+            match LoadedCertificate::load(&cert.paths) {
+                Ok(reloaded) => {
+                    tracing::info!(cert = %cert.paths.cert.display(), "Reloaded TLS certificate");
+                    *cert = reloaded;
+                }
+                Err(e) => {
+                    // Remember the new modification times so we don't retry
+                    // (and log) on every check until the files change again.
+                    cert.modified = modification_times(&cert.paths);
+                    tracing::error!(
+                        cert = %cert.paths.cert.display(),
+                        error = format!("{e:#}"),
+                        "Failed to reload TLS certificate, keeping the old one"
+                    );
+                }
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for CertificateResolver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CertificateResolver")
+            .finish_non_exhaustive()
+    }
+}
+
+impl ResolvesServerCert for CertificateResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let certificates = self.certificates.read();
+
+        let by_name = client_hello
+            .server_name()
+            .and_then(|name| find_by_name(&certificates.by_name, name));
+
+        Some(by_name.unwrap_or(&certificates.default).key.clone())
+    }
+}
+
+/// Finds the certificate for a server name, falling back to a wildcard
+/// certificate for the parent domain. Wildcards only cover one level, like
+/// they do in certificates.
+fn find_by_name<'a, T>(by_name: &'a HashMap<String, T>, name: &str) -> Option<&'a T> {
+    let name = name.to_ascii_lowercase();
+    by_name.get(&name).or_else(|| {
+        let (_, parent) = name.split_once('.')?;
+        by_name.get(&format!("*.{parent}"))
+    })
+}
+
+fn modification_times(paths: &CertificatePaths) -> (Option<SystemTime>, Option<SystemTime>) {
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    (modified(&paths.cert), modified(&paths.key))
+}
+
+fn load_certified_key(paths: &CertificatePaths) -> anyhow::Result<CertifiedKey> {
+    let open = |path: &Path| {
+        File::open(path)
+            .map(BufReader::new)
+            .with_context(|| format!("Failed to open '{}'", path.display()))
+    };
+
+    let certs = rustls_pemfile::certs(&mut open(&paths.cert)?)
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Failed to parse certificates in '{}'", paths.cert.display()))?;
+    if certs.is_empty() {
+        bail!("No certificates found in '{}'", paths.cert.display());
+    }
+
+    let key = rustls_pemfile::private_key(&mut open(&paths.key)?)
+        .with_context(|| format!("Failed to parse private key in '{}'", paths.key.display()))?
+        .ok_or_else(|| anyhow!("No private key found in '{}'", paths.key.display()))?;
+    let key = rustls::crypto::ring::sign::any_supported_type(&key)
+        .map_err(|e| anyhow!("Unsupported private key in '{}': {e}", paths.key.display()))?;
+
+    Ok(CertifiedKey::new(certs, key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sni_certificates() {
+        let sni: SniCertificate = "Example.COM=/certs/example.pem,/certs/example.key"
+            .parse()
+            .unwrap();
+        assert_eq!(sni.server_name, "example.com");
+        assert_eq!(sni.paths.cert, Path::new("/certs/example.pem"));
+        assert_eq!(sni.paths.key, Path::new("/certs/example.key"));
+
+        // Only the first comma separates the paths
+        let sni: SniCertificate = "*.example.com=cert.pem,key,1.pem".parse().unwrap();
+        assert_eq!(sni.server_name, "*.example.com");
+        assert_eq!(sni.paths.cert, Path::new("cert.pem"));
+        assert_eq!(sni.paths.key, Path::new("key,1.pem"));
+    }
+
+    #[test]
+    fn rejects_malformed_sni_certificates() {
+        for s in [
+            "",
+            "example.com",
+            "example.com=cert.pem",
+            "=cert.pem,key.pem",
+            "example.com=,key.pem",
+            "example.com=cert.pem,",
+        ] {
+            assert!(s.parse::<SniCertificate>().is_err(), "{s:?} was accepted");
+        }
+    }
+
+    #[test]
+    fn finds_certificates_by_name() {
+        let by_name = HashMap::from([
+            ("example.com".to_string(), "example"),
+            ("api.example.com".to_string(), "api"),
+            ("*.example.com".to_string(), "wildcard"),
+        ]);
+
+        assert_eq!(find_by_name(&by_name, "example.com"), Some(&"example"));
+        assert_eq!(find_by_name(&by_name, "API.Example.com"), Some(&"api"));
+        assert_eq!(find_by_name(&by_name, "www.example.com"), Some(&"wildcard"));
+        assert_eq!(find_by_name(&by_name, "a.b.example.com"), None);
+        assert_eq!(find_by_name(&by_name, "example.org"), None);
+        assert_eq!(find_by_name(&by_name, "localhost"), None);
+    }
+}
@@ -0,0 +1,114 @@
+//! The server side of WebSocket upgrades. The Javascript code accepts an
+//! upgrade by returning a response with a WebSocket, which reaches us as a
+//! 101 response carrying the connection's [`Peer`]. We then finish the
+//! handshake and pass messages between the upgraded connection and the
+//! Javascript code until either side closes it.
+
+use hyper::{
+    header::{self, HeaderMap, HeaderValue},
+    upgrade::OnUpgrade,
+    Body, Method, Request, Response, StatusCode,
+};
+use tokio_tungstenite::{
+    tungstenite::{handshake::derive_accept_key, protocol::Role},
+    WebSocketStream,
+};
+
+use crate::builtins::websocket::{connection, Peer};
+
+use super::listener::ActiveRequest;
+
+/// A request asking for a WebSocket upgrade.
+pub struct UpgradeRequest {
+    key: HeaderValue,
+    on_upgrade: OnUpgrade,
+
+    /// Keeps the connection from being closed as idle while the WebSocket
+    /// is open.
+    active_request: Option<ActiveRequest>,
+}
+
+impl UpgradeRequest {
+    /// Takes the upgrade out of the request, if it's a valid WebSocket
+    /// upgrade request.
+    pub fn take(req: &mut Request<Body>, active_request: Option<&ActiveRequest>) -> Option<Self> {
+        let headers = req.headers();
+        if req.method() != Method::GET
+            || !header_contains(headers, header::UPGRADE, "websocket")
+            || !header_contains(headers, header::CONNECTION, "upgrade")
+            || headers
+                .get(header::SEC_WEBSOCKET_VERSION)
+                .is_some_and(|v| v != "13")
+        {
+            return None;
+        }
+        let key = headers.get(header::SEC_WEBSOCKET_KEY)?.clone();
+
+        Some(Self {
+            key,
+            on_upgrade: hyper::upgrade::on(req),
+            active_request: active_request.map(ActiveRequest::keep_active),
+        })
+    }
+}
+
+fn header_contains(headers: &HeaderMap, name: header::HeaderName, token: &str) -> bool {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|value| value.trim().eq_ignore_ascii_case(token))
+}
+
+/// Completes the upgrade if the response accepts it. Responses with a
+/// WebSocket to requests that weren't upgrade requests are answered with
+/// 426 Upgrade Required instead.
+pub fn accept(upgrade: Option<UpgradeRequest>, mut res: Response<Body>) -> Response<Body> {
+    let Some(peer) = res.extensions_mut().remove::<Peer>() else {
+        return res;
+    };
+
+    let Some(upgrade) = upgrade else {
+        // Dropping the peer closes the WebSocket on the Javascript side
+        drop(peer);
+        return Response::builder()
+            .status(StatusCode::UPGRADE_REQUIRED)
+            .header(header::UPGRADE, "websocket")
+            .header(header::SEC_WEBSOCKET_VERSION, "13")
+            .body(Body::from("This resource requires a WebSocket connection"))
+            .unwrap();
+    };
+
+    let UpgradeRequest {
+        key,
+        on_upgrade,
+        active_request,
+    } = upgrade;
+
+    let headers = res.headers_mut();
+    headers.insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+    headers.insert(header::UPGRADE, HeaderValue::from_static("websocket"));
+    headers.insert(
+        header::SEC_WEBSOCKET_ACCEPT,
+        derive_accept_key(key.as_bytes())
+            .parse()
+            .expect("accept keys are valid header values"),
+    );
+
+    tokio::spawn(async move {
+        let _active_request = active_request;
+        match on_upgrade.await {
+            Ok(upgraded) => {
+                let stream = WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await;
+                connection::run(stream, peer).await;
+            }
+            Err(e) => {
+                tracing::debug!(error = %e, "WebSocket upgrade failed");
+                peer.fail(e);
+            }
+        }
+    });
+
+    res
+}
@@ -1,15 +1,21 @@
 use std::{ffi::OsStr, path::Path};
 
 use anyhow::{anyhow, Context as _};
-use ion::{module::ModuleLoader, Context, ErrorReport};
+use ion::{
+    module::{ModuleErrorKind, ModuleLoader},
+    Context, ErrorReport, Exception,
+};
 use mozjs::{
-    jsapi::WeakRefSpecifier,
+    jsapi::{self, WeakRefSpecifier},
     rust::{JSEngine, JSEngineHandle, RealmOptions},
 };
 use runtime::{module::StandardModules, Runtime, RuntimeBuilder};
 use self_cell::self_cell;
 
+use crate::stencil_cache;
+
 pub static ENGINE: once_cell::sync::Lazy<JSEngineHandle> = once_cell::sync::Lazy::new(|| {
+    stencil_cache::register_build_id();
     let engine = JSEngine::init().expect("could not create engine");
     let handle = engine.handle();
     std::mem::forget(engine);
@@ -97,8 +103,9 @@ pub fn evaluate_script(
     code: impl AsRef<str>,
     file_name: impl AsRef<OsStr>,
 ) -> anyhow::Result<ion::Value> {
-    ion::script::Script::compile_and_evaluate(cx, Path::new(&file_name), code.as_ref())
-        .map_err(|e| error_report_to_anyhow_error(cx, e))
+    stencil_cache::compile_script(cx, &file_name.as_ref().to_string_lossy(), code.as_ref())
+        .and_then(|script| script.evaluate(cx).map_err(Some))
+        .map_err(|e| error_report_option_to_anyhow_error(cx, e))
 }
 
 pub fn evaluate_module(
@@ -114,27 +121,87 @@ pub fn evaluate_module(
 
     let code = std::fs::read_to_string(path).context("Failed to read script file")?;
 
-    Ok(
-        ion::module::Module::compile_and_evaluate(cx, &file_name, Some(path), &code)
-            .map_err(|e| {
-                error_report_option_to_anyhow_error(cx, Some(e.report)).context(format!(
-                    "Error while loading module during {:?} step",
-                    e.kind
-                ))
-            })?
-            .0,
-    )
+    let module_error = |e: Option<ErrorReport>, kind: ModuleErrorKind| {
+        error_report_option_to_anyhow_error(cx, e)
+            .context(format!("Error while loading module during {kind:?} step"))
+    };
+
+    let module = stencil_cache::compile_module(cx, &file_name, Some(path), &code)
+        .map_err(|e| module_error(e, ModuleErrorKind::Compilation))?;
+
+    let handle = module.0.handle();
+    if !unsafe { jsapi::ModuleLink(cx.as_ptr(), handle.into()) } {
+        return Err(module_error(
+            ErrorReport::new_with_exception_stack(cx),
+            ModuleErrorKind::Instantiation,
+        ));
+    }
+
+    // Like `Module::compile_and_evaluate`, the promise returned for modules
+    // with a top-level await is left to the event loop
+    let mut rval = ion::Value::undefined(cx);
+    if !unsafe { jsapi::ModuleEvaluate(cx.as_ptr(), handle.into(), rval.handle_mut().into()) } {
+        return Err(module_error(
+            ErrorReport::new_with_exception_stack(cx),
+            ModuleErrorKind::Evaluation,
+        ));
+    }
+
+    Ok(module)
+}
+
+/// An error thrown by Javascript code, along with where it was thrown from.
+/// Errors made by [`error_report_to_anyhow_error`] can be downcast to this
+/// to find the location.
+#[derive(Debug)]
+pub struct ScriptError {
+    message: String,
+    location: Option<String>,
+}
+
+impl ScriptError {
+    /// The `file:line:column` the error was thrown from, if it's known.
+    pub fn location(&self) -> Option<&str> {
+        self.location.as_deref()
+    }
+}
+
+impl std::fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for ScriptError {}
+
+fn error_report_location(error_report: &ErrorReport) -> Option<String> {
+    let location = match &error_report.exception {
+        Exception::Error(error) => error.location.as_ref(),
+        _ => None,
+    }
+    .or_else(|| {
+        error_report
+            .stack
+            .as_ref()
+            .and_then(|stack| stack.records.first())
+            .map(|record| &record.location)
+    })?;
+
+    (!location.file.is_empty())
+        .then(|| format!("{}:{}:{}", location.file, location.lineno, location.column))
 }
 
 pub fn error_report_to_anyhow_error(cx: &Context, error_report: ErrorReport) -> anyhow::Error {
-    match error_report.stack {
-        Some(stack) => anyhow::anyhow!(
+    let location = error_report_location(&error_report);
+    let message = match error_report.stack {
+        Some(stack) => format!(
             "Script error: {}\nat:\n{}",
             error_report.exception.format(cx),
             stack.format()
         ),
-        None => anyhow::anyhow!("Runtime error: {}", error_report.exception.format(cx)),
-    }
+        None => format!("Runtime error: {}", error_report.exception.format(cx)),
+    };
+    ScriptError { message, location }.into()
 }
 
 pub fn error_report_option_to_anyhow_error(
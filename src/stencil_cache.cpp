@@ -0,0 +1,66 @@
+// The parts of `stencil_cache` that need SpiderMonkey types the Rust
+// bindings only know as opaque blobs. `JS::TranscodeBuffer` is a
+// `mozilla::Vector`, whose layout depends on how SpiderMonkey was built, so
+// it's only ever touched from here.
+
+#include "jsapi.h"
+#include "js/BuildId.h"
+#include "js/CompileOptions.h"
+#include "js/Transcoding.h"
+#include "js/experimental/JSStencil.h"
+
+static const char* gBuildId = nullptr;
+static size_t gBuildIdLength = 0;
+
+static bool GetBuildId(JS::BuildIdCharVector* buildId) {
+  return buildId->append(gBuildId, gBuildIdLength);
+}
+
+extern "C" {
+
+// SpiderMonkey writes this id into encoded stencils, and refuses to decode
+// stencils carrying a different one. `id` must stay valid for the lifetime
+// of the process.
+void winterjs_set_build_id(const char* id, size_t length) {
+  gBuildId = id;
+  gBuildIdLength = length;
+  JS::SetProcessBuildIdOp(GetBuildId);
+}
+
+// Returns null if encoding fails, with the reason in `result`.
+JS::TranscodeBuffer* winterjs_encode_stencil(JSContext* cx,
+                                             JS::Stencil* stencil,
+                                             JS::TranscodeResult* result) {
+  JS::TranscodeBuffer* buffer = new JS::TranscodeBuffer();
+  *result = JS::EncodeStencil(cx, stencil, *buffer);
+  if (*result != JS::TranscodeResult::Ok) {
+    delete buffer;
+    return nullptr;
+  }
+  return buffer;
+}
+
+const uint8_t* winterjs_transcode_buffer_data(
+    const JS::TranscodeBuffer* buffer) {
+  return buffer->begin();
+}
+
+size_t winterjs_transcode_buffer_length(const JS::TranscodeBuffer* buffer) {
+  return buffer->length();
+}
+
+void winterjs_transcode_buffer_free(JS::TranscodeBuffer* buffer) {
+  delete buffer;
+}
+
+// The decoded stencil copies what it needs, so `data` can be freed as soon
+// as this returns.
+JS::TranscodeResult winterjs_decode_stencil(JSContext* cx, const uint8_t* data,
+                                            size_t length,
+                                            JS::Stencil** stencilOut) {
+  JS::DecodeOptions options;
+  JS::TranscodeRange range(data, length);
+  return JS::DecodeStencil(cx, options, range, stencilOut);
+}
+
+}  // extern "C"
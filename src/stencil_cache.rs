@@ -0,0 +1,486 @@
+//! Compiled Javascript, cached as SpiderMonkey stencils. Every runtime we
+//! start evaluates the same code: the `js_globals` scripts, the internal
+//! modules and the user code, once per worker thread and again on every
+//! reload. A stencil is the output of the parser and bytecode compiler
+//! before it's tied to a runtime, so compiling once and instantiating the
+//! stencil in each runtime skips the most expensive part of startup.
+//!
+//! Stencils are kept in memory and shared by all threads. Entries are keyed
+//! by path and checked against a hash of the source, and only the latest
+//! source of each file is kept, so reloaded code doesn't pile up. Modules
+//! imported by the user code go through here as well, by loading them with
+//! [`CachingLoader`].
+//!
+//! With a cache directory set, stencils are also encoded to XDR and written
+//! to a file named after the hash of the source, so the next process
+//! starting up with the same code doesn't compile it at all. XDR only
+//! decodes in the SpiderMonkey build that encoded it. `build.rs` derives a
+//! build id from the SpiderMonkey we link against, which is registered with
+//! SpiderMonkey to be checked when decoding, and is part of the file name
+//! so files from other builds are never even read.
+
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    path::{Path, PathBuf},
+    ptr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use ion::{
+    module::{Module, ModuleData, ModuleLoader, ModuleRequest},
+    script::Script,
+    Context, ErrorReport, Exception, Object, ResultExc, Value,
+};
+use mozjs::{
+    jsapi::{self, InstantiateOptions, JSContext, JSObject, TranscodeResult},
+    jsval::ObjectValue,
+    rust::{transform_str_to_source_text, CompileOptionsWrapper},
+};
+use parking_lot::Mutex;
+use sha2::{Digest, Sha256};
+
+use crate::ion_mk_err;
+
+/// Identifies the SpiderMonkey build, see `build.rs`.
+const BUILD_ID: &str = env!("WINTERJS_BUILD_ID");
+
+/// Where encoded stencils are written, set with `--code-cache-dir`.
+static CACHE_DIR: once_cell::sync::OnceCell<PathBuf> = once_cell::sync::OnceCell::new();
+
+/// Must be called before any Javascript runtime is created to take effect.
+pub fn set_cache_dir(dir: PathBuf) {
+    _ = CACHE_DIR.set(dir);
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Kind {
+    Script,
+    Module,
+}
+
+impl Kind {
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Script => "script.xdr",
+            Self::Module => "module.xdr",
+        }
+    }
+}
+
+/// A reference to a stencil. SpiderMonkey counts references to stencils
+/// atomically, and they can't be changed once compiled, so they can be
+/// instantiated on any thread.
+struct Stencil(*mut jsapi::Stencil);
+
+unsafe impl Send for Stencil {}
+unsafe impl Sync for Stencil {}
+
+impl Drop for Stencil {
+    fn drop(&mut self) {
+        unsafe { jsapi::StencilRelease(self.0) }
+    }
+}
+
+struct Entry {
+    source_hash: [u8; 32],
+    stencil: Arc<Stencil>,
+}
+
+lazy_static::lazy_static! {
+    static ref STENCILS: Mutex<HashMap<(Kind, String), Entry>> = Mutex::new(HashMap::new());
+}
+
+fn get_or_compile(
+    cx: &Context,
+    kind: Kind,
+    key: &str,
+    file_name: &str,
+    code: &str,
+) -> Result<Arc<Stencil>, Option<ErrorReport>> {
+    let mut hasher = Sha256::new();
+    hasher.update(file_name.as_bytes());
+    hasher.update([0]);
+    hasher.update(code.as_bytes());
+    let source_hash: [u8; 32] = hasher.finalize().into();
+    let key = (kind, key.to_string());
+
+    if let Some(entry) = STENCILS.lock().get(&key) {
+        if entry.source_hash == source_hash {
+            return Ok(entry.stencil.clone());
+        }
+    }
+
+    // Compiling happens outside the lock, so threads starting up at the
+    // same time may all compile the same code; the last one wins
+    let cache_file = CACHE_DIR
+        .get()
+        .map(|dir| dir.join(cache_file_name(kind, &source_hash)));
+    let stencil = match cache_file
+        .as_deref()
+        .and_then(|path| read_cache_file(cx, path))
+    {
+        Some(stencil) => stencil,
+        None => {
+            let stencil = compile(cx, kind, file_name, code)?;
+            if let Some(path) = &cache_file {
+                write_cache_file(cx, &stencil, path);
+            }
+            stencil
+        }
+    };
+    let stencil = Arc::new(stencil);
+    STENCILS.lock().insert(
+        key,
+        Entry {
+            source_hash,
+            stencil: stencil.clone(),
+        },
+    );
+    Ok(stencil)
+}
+
+fn compile(
+    cx: &Context,
+    kind: Kind,
+    file_name: &str,
+    code: &str,
+) -> Result<Stencil, Option<ErrorReport>> {
+    tracing::debug!(file_name, "Compiling stencil");
+
+    let options = unsafe { CompileOptionsWrapper::new(cx.as_ptr(), file_name, 1) };
+    let mut source = transform_str_to_source_text(code);
+    let stencil = unsafe {
+        match kind {
+            Kind::Script => {
+                jsapi::CompileGlobalScriptToStencil(cx.as_ptr(), options.ptr, &mut source)
+            }
+            Kind::Module => {
+                jsapi::CompileModuleScriptToStencil(cx.as_ptr(), options.ptr, &mut source)
+            }
+        }
+    }
+    .mRawPtr;
+
+    if stencil.is_null() {
+        Err(ErrorReport::new_with_exception_stack(cx))
+    } else {
+        Ok(Stencil(stencil))
+    }
+}
+
+fn cache_file_name(kind: Kind, source_hash: &[u8; 32]) -> String {
+    let mut name = format!("{BUILD_ID}-");
+    for byte in source_hash {
+        _ = write!(name, "{byte:02x}");
+    }
+    name.push('.');
+    name.push_str(kind.extension());
+    name
+}
+
+/// `JS::TranscodeBuffer`, which is only handled through the functions in
+/// `stencil_cache.cpp`.
+#[repr(C)]
+struct TranscodeBuffer {
+    _private: [u8; 0],
+}
+
+extern "C" {
+    fn winterjs_set_build_id(id: *const u8, length: usize);
+    fn winterjs_encode_stencil(
+        cx: *mut JSContext,
+        stencil: *mut jsapi::Stencil,
+        result: *mut TranscodeResult,
+    ) -> *mut TranscodeBuffer;
+    fn winterjs_transcode_buffer_data(buffer: *const TranscodeBuffer) -> *const u8;
+    fn winterjs_transcode_buffer_length(buffer: *const TranscodeBuffer) -> usize;
+    fn winterjs_transcode_buffer_free(buffer: *mut TranscodeBuffer);
+    fn winterjs_decode_stencil(
+        cx: *mut JSContext,
+        data: *const u8,
+        length: usize,
+        stencil: *mut *mut jsapi::Stencil,
+    ) -> TranscodeResult;
+}
+
+/// Registers [`BUILD_ID`] with SpiderMonkey, which refuses to encode
+/// stencils without one. Called once, when the engine is initialized.
+pub fn register_build_id() {
+    unsafe { winterjs_set_build_id(BUILD_ID.as_ptr(), BUILD_ID.len()) }
+}
+
+/// A stencil encoded to XDR.
+struct Encoded(*mut TranscodeBuffer);
+
+impl Encoded {
+    fn as_slice(&self) -> &[u8] {
+        unsafe {
+            std::slice::from_raw_parts(
+                winterjs_transcode_buffer_data(self.0),
+                winterjs_transcode_buffer_length(self.0),
+            )
+        }
+    }
+}
+
+impl Drop for Encoded {
+    fn drop(&mut self) {
+        unsafe { winterjs_transcode_buffer_free(self.0) }
+    }
+}
+
+fn encode(cx: &Context, stencil: &Stencil) -> Result<Encoded, TranscodeResult> {
+    let mut result = TranscodeResult::Ok;
+    let buffer = unsafe { winterjs_encode_stencil(cx.as_ptr(), stencil.0, &mut result) };
+    if buffer.is_null() {
+        unsafe { jsapi::JS_ClearPendingException(cx.as_ptr()) };
+        return Err(result);
+    }
+    Ok(Encoded(buffer))
+}
+
+fn decode(cx: &Context, bytes: &[u8]) -> Result<Stencil, TranscodeResult> {
+    let mut stencil = ptr::null_mut();
+    let result =
+        unsafe { winterjs_decode_stencil(cx.as_ptr(), bytes.as_ptr(), bytes.len(), &mut stencil) };
+    if result != TranscodeResult::Ok || stencil.is_null() {
+        // Decoding failures may leave an exception behind
+        unsafe { jsapi::JS_ClearPendingException(cx.as_ptr()) };
+        return Err(result);
+    }
+    Ok(Stencil(stencil))
+}
+
+fn read_cache_file(cx: &Context, path: &Path) -> Option<Stencil> {
+    let bytes = std::fs::read(path).ok()?;
+    match decode(cx, &bytes) {
+        Ok(stencil) => {
+            tracing::debug!(path = %path.display(), "Loaded stencil from the cache");
+            Some(stencil)
+        }
+        Err(result) => {
+            tracing::debug!(path = %path.display(), ?result, "Failed to decode cached stencil");
+            None
+        }
+    }
+}
+
+fn write_cache_file(cx: &Context, stencil: &Stencil, path: &Path) {
+    static TEMP_FILES: AtomicUsize = AtomicUsize::new(0);
+
+    let encoded = match encode(cx, stencil) {
+        Ok(encoded) => encoded,
+        Err(result) => {
+            tracing::warn!(path = %path.display(), ?result, "Failed to encode stencil");
+            return;
+        }
+    };
+
+    // Other threads and processes may be reading the file, so it's
+    // written elsewhere and moved into place in one go
+    let temp_path = path.with_extension(format!(
+        "{}-{}.tmp",
+        std::process::id(),
+        TEMP_FILES.fetch_add(1, Ordering::Relaxed)
+    ));
+    let written = path
+        .parent()
+        .map_or(Ok(()), std::fs::create_dir_all)
+        .and_then(|()| std::fs::write(&temp_path, encoded.as_slice()))
+        .and_then(|()| std::fs::rename(&temp_path, path));
+    if let Err(e) = written {
+        _ = std::fs::remove_file(&temp_path);
+        tracing::warn!(path = %path.display(), "Failed to write stencil to the cache: {e}");
+    }
+}
+
+fn instantiate_options() -> InstantiateOptions {
+    InstantiateOptions {
+        skipFilenameValidation: false,
+        hideScriptFromDebugger: false,
+        deferDebugMetadata: false,
+    }
+}
+
+/// Compiles a script to run in the global scope, like [`Script::compile`].
+pub fn compile_script<'cx>(
+    cx: &'cx Context,
+    file_name: &str,
+    code: &str,
+) -> Result<Script<'cx>, Option<ErrorReport>> {
+    let stencil = get_or_compile(cx, Kind::Script, file_name, file_name, code)?;
+    instantiate_script(cx, &stencil)
+}
+
+fn instantiate_script<'cx>(
+    cx: &'cx Context,
+    stencil: &Stencil,
+) -> Result<Script<'cx>, Option<ErrorReport>> {
+    let script = unsafe {
+        jsapi::InstantiateGlobalStencil(
+            cx.as_ptr(),
+            &instantiate_options(),
+            stencil.0,
+            ptr::null_mut(),
+        )
+    };
+    if script.is_null() {
+        return Err(ErrorReport::new_with_exception_stack(cx));
+    }
+
+    Ok(Script::from(cx.root(script)))
+}
+
+/// Compiles a module without linking or evaluating it, like
+/// [`Module::compile`]. `path` is where relative imports are resolved from.
+pub fn compile_module<'cx>(
+    cx: &'cx Context,
+    name: &str,
+    path: Option<&Path>,
+    code: &str,
+) -> Result<Module<'cx>, Option<ErrorReport>> {
+    // Modules loaded from different directories may share a name
+    let key = path.and_then(Path::to_str).unwrap_or(name);
+    let stencil = get_or_compile(cx, Kind::Module, key, name, code)?;
+
+    let module = unsafe {
+        jsapi::InstantiateModuleStencil(
+            cx.as_ptr(),
+            &instantiate_options(),
+            stencil.0,
+            ptr::null_mut(),
+        )
+    };
+    if module.is_null() {
+        return Err(ErrorReport::new_with_exception_stack(cx));
+    }
+
+    // The module loader finds the module's path here, the same way it does
+    // for the modules it compiles itself
+    let data = ModuleData {
+        path: path.and_then(Path::to_str).map(String::from),
+    };
+    unsafe {
+        jsapi::SetModulePrivate(module, &ObjectValue(data.to_object(cx).handle().get()));
+    }
+
+    Ok(Module(Object::from(cx.root(module))))
+}
+
+/// Works out which file a module request refers to, the same way
+/// `runtime::module::Loader` does. Returns `None` for built-in modules.
+pub(crate) fn module_path(
+    cx: &Context,
+    private: &Value,
+    request: &ModuleRequest,
+) -> Option<PathBuf> {
+    let specifier = request.specifier(cx).to_owned(cx).ok()?;
+    if specifier.starts_with("./") || specifier.starts_with("../") {
+        let referrer = ModuleData::from_private(cx, private)?.path?;
+        Some(Path::new(&referrer).parent()?.join(specifier))
+    } else if specifier.starts_with('/') {
+        Some(PathBuf::from(specifier))
+    } else {
+        None
+    }
+}
+
+/// A module loader that compiles the files it loads with
+/// [`compile_module`], so imported modules come from the cache too. Built-in
+/// modules are left to `runtime::module::Loader`.
+#[derive(Default)]
+pub struct CachingLoader {
+    loader: runtime::module::Loader,
+    modules: HashMap<PathBuf, *mut JSObject>,
+}
+
+impl ModuleLoader for CachingLoader {
+    fn resolve(
+        &mut self,
+        cx: &Context,
+        private: &Value,
+        request: &ModuleRequest,
+    ) -> ResultExc<*mut JSObject> {
+        let Some(path) = module_path(cx, private, request) else {
+            return self.loader.resolve(cx, private, request);
+        };
+        if let Some(module) = self.modules.get(&path) {
+            return Ok(*module);
+        }
+
+        let code = std::fs::read_to_string(&path).map_err(|e| {
+            Exception::Error(ion_mk_err!(
+                format!("Failed to read module '{}': {e}", path.display()),
+                Normal
+            ))
+        })?;
+        let module = compile_module(cx, &path.to_string_lossy(), Some(&path), &code)
+            .map_err(|e| match e {
+                Some(report) => report.exception,
+                None => Exception::Error(ion_mk_err!(
+                    format!("Failed to compile module '{}'", path.display()),
+                    Normal
+                )),
+            })?
+            .module_object();
+
+        self.modules.insert(path, module);
+        Ok(module)
+    }
+
+    fn register(
+        &mut self,
+        cx: &Context,
+        module: *mut JSObject,
+        request: &ModuleRequest,
+    ) -> ResultExc<*mut JSObject> {
+        self.loader.register(cx, module, request)
+    }
+
+    fn metadata(&self, cx: &Context, private: &Value, meta: &mut Object) -> ResultExc<()> {
+        self.loader.metadata(cx, private, meta)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use runtime::config::{Config, CONFIG};
+
+    use super::*;
+    use crate::sm_utils::{
+        error_report_option_to_anyhow_error, error_report_to_anyhow_error, JsApp,
+    };
+
+    #[test]
+    fn encodes_and_decodes_stencils() {
+        _ = CONFIG.set(Config::default());
+        let app = JsApp::build(None::<CachingLoader>, None::<crate::builtins::Modules>);
+        let cx = app.cx();
+
+        let stencil = compile(cx, Kind::Script, "test.js", "(1 + 2) * 7")
+            .map_err(|e| error_report_option_to_anyhow_error(cx, e))
+            .unwrap();
+        let encoded = encode(cx, &stencil).unwrap();
+        let bytes = encoded.as_slice().to_vec();
+        drop(encoded);
+        assert!(bytes
+            .windows(BUILD_ID.len())
+            .any(|w| w == BUILD_ID.as_bytes()));
+
+        let decoded = decode(cx, &bytes).unwrap();
+        let value = instantiate_script(cx, &decoded)
+            .map_err(|e| error_report_option_to_anyhow_error(cx, e))
+            .unwrap()
+            .evaluate(cx)
+            .map_err(|e| error_report_to_anyhow_error(cx, e))
+            .unwrap();
+        assert_eq!(value.handle().to_int32(), 21);
+
+        assert!(decode(cx, b"not a stencil").is_err());
+        assert!(decode(cx, &bytes[..bytes.len() / 2]).is_err());
+    }
+}
@@ -24,8 +24,11 @@ import { handleRequest as handleCryptoSha } from "./test-files/16.2-crypto-sha.j
 import { handleRequest as handleCache } from "./test-files/17-cache.js";
 import { handleRequest as handleEvent } from "./test-files/18-event.js";
 import { handleRequest as handleAbort } from "./test-files/19-abort.js";
+import { handleRequest as handleClientAddress } from "./test-files/20-client-address.js";
+import { handleRequest as handleCompression } from "./test-files/21-compression.js";
+import { handleRequest as handleWebSocket } from "./test-files/22-websocket.js";
 
-function router(req) {
+function router(req, event) {
   const url = new URL(req.url);
   const path = url.pathname;
 
@@ -107,9 +110,18 @@ function router(req) {
   if (path.startsWith("/19-abort")) {
     return handleAbort(req);
   }
+  if (path.startsWith("/20-client-address")) {
+    return handleClientAddress(req, event);
+  }
+  if (path.startsWith("/21-compression")) {
+    return handleCompression(req);
+  }
+  if (path.startsWith("/22-websocket")) {
+    return handleWebSocket(req);
+  }
   return new Response(`Route Not Found - ${path}`, { status: 404 });
 }
 
 addEventListener("fetch", (fetchEvent) => {
-  fetchEvent.respondWith(router(fetchEvent.request));
+  fetchEvent.respondWith(router(fetchEvent.request, fetchEvent));
 });
@@ -0,0 +1,43 @@
+import { assert, assert_equals, assert_true } from "../test-utils";
+
+async function handleRequest(request, event) {
+  try {
+    const clientAddress = event.clientAddress;
+    assert_equals(
+      typeof clientAddress,
+      "string",
+      "event.clientAddress should be a string"
+    );
+    assert_true(
+      /^[0-9.]+$/.test(clientAddress) || clientAddress.includes(":"),
+      `event.clientAddress '${clientAddress}' is not an IP address`
+    );
+
+    // The header is always replaced with the address we know about
+    assert_equals(
+      request.headers.get("x-real-ip"),
+      clientAddress,
+      "X-Real-IP should match event.clientAddress"
+    );
+
+    // request.cf only exists in Cloudflare mode
+    if (request.cf !== undefined) {
+      assert_equals(
+        request.cf.clientAddress,
+        clientAddress,
+        "request.cf.clientAddress should match event.clientAddress"
+      );
+      assert(
+        request.cf.httpProtocol.startsWith("HTTP/"),
+        `Unexpected request.cf.httpProtocol '${request.cf.httpProtocol}'`
+      );
+      assert_equals(request.cf.tls, false, "request.cf.tls should be false");
+    }
+
+    return new Response("All tests passed!");
+  } catch (error) {
+    return new Response(error.message, { status: 500 });
+  }
+}
+
+export { handleRequest };
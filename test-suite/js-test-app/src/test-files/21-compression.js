@@ -0,0 +1,21 @@
+import { assert_equals } from "../test-utils";
+
+// The server compresses this response when started with --compress; the
+// test suite checks the encoding and decompresses the body.
+async function handleRequest(request) {
+  try {
+    assert_equals(
+      request.headers.get("accept-encoding"),
+      "gzip",
+      "Accept-Encoding should be passed on to the script"
+    );
+
+    return new Response("All tests passed!", {
+      headers: { "content-type": "text/plain" },
+    });
+  } catch (error) {
+    return new Response(error.message, { status: 500 });
+  }
+}
+
+export { handleRequest };
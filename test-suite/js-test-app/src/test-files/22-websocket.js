@@ -0,0 +1,71 @@
+import { assert, assert_equals, assert_throws_js } from "../test-utils";
+
+// Answers WebSocket upgrades on /22-websocket/echo by sending every message
+// back. Other requests connect to that route through the WebSocket client
+// and check the echo.
+async function handleRequest(request) {
+  const url = new URL(request.url);
+  if (url.pathname.endsWith("/echo")) {
+    const { 0: client, 1: server } = new WebSocketPair();
+    server.accept();
+    server.addEventListener("message", (event) => server.send(event.data));
+    return new Response(null, { status: 101, webSocket: client });
+  }
+
+  try {
+    assert_throws_js(
+      () => new Response(null, { status: 101 }),
+      "Responses without a webSocket can't have status 101"
+    );
+
+    const pair = new WebSocketPair();
+    assert(
+      !("handle" in pair[0]),
+      "WebSockets should not expose their connection"
+    );
+    pair[1].accept();
+    assert_throws_js(
+      () => new Response(null, { status: 101, webSocket: pair[1] }),
+      "The accepted end can't be returned in a response"
+    );
+    assert_throws_js(
+      () => new Response(null, { status: 200, webSocket: pair[0] }),
+      "Responses with a webSocket must have status 101"
+    );
+    const response = new Response(null, { status: 101, webSocket: pair[0] });
+    assert(response instanceof Response, "Response should be a Response");
+    assert_equals(response.status, 101, "Response status should be 101");
+    assert_equals(
+      response.webSocket,
+      pair[0],
+      "Response should have the webSocket"
+    );
+    pair[1].close();
+
+    url.protocol = "ws:";
+    url.pathname = "/22-websocket/echo";
+    const socket = new WebSocket(url.href);
+    const echo = await new Promise((resolve, reject) => {
+      socket.addEventListener("open", () => socket.send("ping"));
+      socket.addEventListener("message", (event) => resolve(event.data));
+      socket.addEventListener("error", (event) =>
+        reject(new Error(event.message))
+      );
+      socket.addEventListener("close", () => reject(new Error("Closed early")));
+    });
+    assert_equals(echo, "ping", "The message should be echoed back");
+
+    const closed = new Promise((resolve) =>
+      socket.addEventListener("close", resolve)
+    );
+    socket.close(1000, "done");
+    const close = await closed;
+    assert_equals(close.code, 1000, "Close code should be passed back");
+
+    return new Response("All tests passed!");
+  } catch (error) {
+    return new Response(error.message, { status: 500 });
+  }
+}
+
+export { handleRequest };
@@ -1,4 +1,4 @@
-use std::time::Duration;
+use std::{collections::BTreeMap, io::Read, time::Duration};
 
 use anyhow::{bail, Result};
 use futures::{stream::FuturesUnordered, StreamExt};
@@ -16,6 +16,14 @@ pub struct TestCase {
     // Timeout in seconds, will be ignored if zero
     pub timeout: Option<f64>,
 
+    // Extra headers to send with the request
+    #[serde(default)]
+    pub request_headers: BTreeMap<String, String>,
+
+    // Headers the response must have, with these values
+    #[serde(default)]
+    pub expected_headers: BTreeMap<String, String>,
+
     // We don't do anything with the string, but it lets us have
     // documentation in the config file as to why we're skipping
     pub skip: Option<String>,
@@ -221,10 +229,37 @@ async fn run_test_once(
     if let Some(timeout) = test_case.timeout {
         request = request.timeout(std::time::Duration::from_secs_f64(timeout));
     }
+    for (name, value) in &test_case.request_headers {
+        request = request.header(name, value);
+    }
 
     let response = request.send().await?;
     let response_status = response.status();
-    let response_body = response.text().await?;
+
+    for (name, expected) in &test_case.expected_headers {
+        let value = response
+            .headers()
+            .get(name)
+            .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned());
+        if value.as_ref() != Some(expected) {
+            bail!("Response header '{name}' is {value:?}, expected '{expected}'");
+        }
+    }
+
+    // We ask for compressed responses ourselves, so we need to decompress
+    // them ourselves too
+    let gzipped = response
+        .headers()
+        .get("content-encoding")
+        .is_some_and(|encoding| encoding == "gzip");
+    let response_body = response.bytes().await?;
+    let response_body = if gzipped {
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(&response_body[..]).read_to_string(&mut decoded)?;
+        decoded
+    } else {
+        String::from_utf8_lossy(&response_body).into_owned()
+    };
 
     if response_body != test_case.expected_output {
         bail!(